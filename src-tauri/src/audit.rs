@@ -0,0 +1,420 @@
+//! 审计日志
+//!
+//! 安全相关事件（认证成功/失败、控制权授予与收回、踢出设备、用户类别变更等）按天追加写入
+//! `audit/audit-YYYY-MM-DD.jsonl`，每行一条 JSON，只追加不修改。
+//! 超过保留天数的整天文件会被删除；可以按时间、事件、设备、结果查询，并导出为 JSON 或 CSV。
+//! 默认开启，可以关闭；无人值守模式下强制开启。
+
+use chrono::{DateTime, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::client_utils::unattended;
+use crate::config::{get_audit_dir, get_audit_settings_path};
+
+const FILE_PREFIX: &str = "audit-";
+const FILE_SUFFIX: &str = ".jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+    /// 被策略、黑名单或审批拒绝
+    Denied,
+    /// 不区分成败的事件，如会话结束
+    Info,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub ts_ms: u64,
+    /// 事件类型，如 session_start、auth_failure、control_granted
+    pub event: String,
+    pub outcome: Outcome,
+    pub device_serial: Option<String>,
+    pub uuid: Option<String>,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditSettings {
+    pub enabled: bool,
+    /// 保留天数，0 表示永久保留
+    pub retention_days: u32,
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: 90,
+        }
+    }
+}
+
+/// 查询条件，未设置的条件不限
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    #[serde(default)]
+    pub from_ms: Option<u64>,
+    #[serde(default)]
+    pub to_ms: Option<u64>,
+    #[serde(default)]
+    pub event: Option<String>,
+    #[serde(default)]
+    pub device_serial: Option<String>,
+    #[serde(default)]
+    pub outcome: Option<Outcome>,
+    /// 最多返回多少条，按时间从新到旧
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.from_ms.is_none_or(|from| entry.ts_ms >= from)
+            && self.to_ms.is_none_or(|to| entry.ts_ms <= to)
+            && self.event.as_ref().is_none_or(|e| *e == entry.event)
+            && self
+                .device_serial
+                .as_ref()
+                .is_none_or(|s| entry.device_serial.as_ref() == Some(s))
+            && self.outcome.is_none_or(|o| o == entry.outcome)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+pub struct AuditLog {
+    dir: PathBuf,
+    settings: AuditSettings,
+    /// 上次清理过期文件的日期，每天最多清理一次
+    last_prune: Option<NaiveDate>,
+}
+
+fn day_of(ts_ms: u64) -> NaiveDate {
+    DateTime::<Utc>::from_timestamp_millis(ts_ms as i64)
+        .unwrap_or_default()
+        .date_naive()
+}
+
+fn now_ms() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+impl AuditLog {
+    pub fn new(dir: PathBuf, settings: AuditSettings) -> Self {
+        Self {
+            dir,
+            settings,
+            last_prune: None,
+        }
+    }
+
+    fn file_for(&self, day: NaiveDate) -> PathBuf {
+        self.dir.join(format!(
+            "{}{}{}",
+            FILE_PREFIX,
+            day.format("%Y-%m-%d"),
+            FILE_SUFFIX
+        ))
+    }
+
+    /// 目录中的日志文件及其日期，按日期排序
+    fn files(&self) -> Vec<(NaiveDate, PathBuf)> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut files: Vec<(NaiveDate, PathBuf)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let date = name.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_SUFFIX)?;
+                let day = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+                Some((day, entry.path()))
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    pub fn append(&mut self, entry: &AuditEntry) -> std::io::Result<()> {
+        let day = day_of(entry.ts_ms);
+        if self.last_prune != Some(day) {
+            self.prune(entry.ts_ms);
+            self.last_prune = Some(day);
+        }
+        fs::create_dir_all(&self.dir)?;
+        let line = serde_json::to_string(entry)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.file_for(day))?;
+        writeln!(file, "{}", line)
+    }
+
+    /// 删除超过保留天数的整天文件，返回删除的文件数
+    pub fn prune(&self, now_ms: u64) -> usize {
+        if self.settings.retention_days == 0 {
+            return 0;
+        }
+        let oldest = day_of(now_ms) - chrono::Days::new(self.settings.retention_days as u64);
+        let mut removed = 0;
+        for (day, path) in self.files() {
+            if day < oldest && fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            println!("[AUDIT]清理{}个过期的审计日志文件", removed);
+        }
+        removed
+    }
+
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditEntry> {
+        let first_day = query.from_ms.map(day_of);
+        let last_day = query.to_ms.map(day_of);
+        let mut entries: Vec<AuditEntry> = self
+            .files()
+            .into_iter()
+            .filter(|(day, _)| first_day.is_none_or(|d| *day >= d))
+            .filter(|(day, _)| last_day.is_none_or(|d| *day <= d))
+            .filter_map(|(_, path)| fs::read_to_string(path).ok())
+            .flat_map(|data| {
+                data.lines()
+                    .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|entry| query.matches(entry))
+            .collect();
+        entries.sort_by(|a, b| b.ts_ms.cmp(&a.ts_ms));
+        if let Some(limit) = query.limit {
+            entries.truncate(limit);
+        }
+        entries
+    }
+}
+
+fn load_settings() -> AuditSettings {
+    fs::read_to_string(get_audit_settings_path())
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+lazy_static! {
+    static ref AUDIT: Mutex<AuditLog> = Mutex::new(AuditLog::new(get_audit_dir(), load_settings()));
+}
+
+pub fn get_settings() -> AuditSettings {
+    AUDIT.lock().unwrap().settings.clone()
+}
+
+pub fn set_settings(settings: AuditSettings) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(&settings).map_err(|e| e.to_string())?;
+    fs::write(get_audit_settings_path(), json).map_err(|e| format!("保存审计设置失败: {}", e))?;
+    if !settings.enabled {
+        // 关闭之前先记下
+        record("audit_disabled", Outcome::Success, None, None, "");
+    }
+    let mut log = AUDIT.lock().unwrap();
+    log.settings = settings;
+    log.last_prune = None;
+    Ok(())
+}
+
+/// 是否记录审计日志，无人值守模式下总是记录
+pub fn is_enabled() -> bool {
+    AUDIT.lock().unwrap().settings.enabled || unattended::is_enabled()
+}
+
+pub fn record(
+    event: &str,
+    outcome: Outcome,
+    device_serial: Option<&str>,
+    uuid: Option<&str>,
+    detail: &str,
+) {
+    if !is_enabled() {
+        return;
+    }
+    let entry = AuditEntry {
+        ts_ms: now_ms(),
+        event: event.to_string(),
+        outcome,
+        device_serial: device_serial.map(str::to_string),
+        uuid: uuid.map(str::to_string),
+        detail: detail.to_string(),
+    };
+    if let Err(e) = AUDIT.lock().unwrap().append(&entry) {
+        println!("[AUDIT]写入审计日志失败：{:?}", e);
+    }
+}
+
+pub fn query(query: &AuditQuery) -> Vec<AuditEntry> {
+    AUDIT.lock().unwrap().query(query)
+}
+
+/// 设备名等字段来自手机端，以 `= + - @`、制表符或回车开头的值加上 `'`，防止表格软件当作公式执行
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+pub fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from("time,ts_ms,event,outcome,device_serial,uuid,detail\n");
+    for entry in entries {
+        let time = DateTime::<Utc>::from_timestamp_millis(entry.ts_ms as i64)
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default();
+        let outcome = serde_json::to_value(entry.outcome)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default();
+        let fields = [
+            time,
+            entry.ts_ms.to_string(),
+            entry.event.clone(),
+            outcome,
+            entry.device_serial.clone().unwrap_or_default(),
+            entry.uuid.clone().unwrap_or_default(),
+            entry.detail.clone(),
+        ];
+        let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&line.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// 按条件导出到文件，返回导出的条数
+pub fn export(query: &AuditQuery, format: ExportFormat, path: &Path) -> Result<usize, String> {
+    let entries = self::query(query);
+    let data = match format {
+        ExportFormat::Json => serde_json::to_string_pretty(&entries).map_err(|e| e.to_string())?,
+        ExportFormat::Csv => to_csv(&entries),
+    };
+    fs::write(path, data).map_err(|e| format!("导出审计日志失败: {}", e))?;
+    record(
+        "audit_exported",
+        Outcome::Success,
+        None,
+        None,
+        &format!("{}条，{:?}", entries.len(), path),
+    );
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY_MS: u64 = 24 * 3600 * 1000;
+
+    fn entry(ts_ms: u64, event: &str, outcome: Outcome, serial: &str) -> AuditEntry {
+        AuditEntry {
+            ts_ms,
+            event: event.to_string(),
+            outcome,
+            device_serial: Some(serial.to_string()),
+            uuid: None,
+            detail: "a,\"b\"".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_append_query_prune() {
+        let dir = std::env::temp_dir().join(format!("lqmy-audit-{}", uuid::Uuid::new_v4()));
+        let mut log = AuditLog::new(
+            dir.clone(),
+            AuditSettings {
+                enabled: true,
+                retention_days: 30,
+            },
+        );
+        let now = now_ms();
+        log.append(&entry(
+            now - 40 * DAY_MS,
+            "auth_failure",
+            Outcome::Failure,
+            "s1",
+        ))
+        .unwrap();
+        log.append(&entry(
+            now - DAY_MS,
+            "session_start",
+            Outcome::Success,
+            "s1",
+        ))
+        .unwrap();
+        log.append(&entry(now, "auth_failure", Outcome::Failure, "s2"))
+            .unwrap();
+
+        // 追加当天的记录时清理了 40 天前的文件
+        let all = log.query(&AuditQuery::default());
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].device_serial.as_deref(), Some("s2"));
+
+        let failures = log.query(&AuditQuery {
+            outcome: Some(Outcome::Failure),
+            ..Default::default()
+        });
+        assert_eq!(failures.len(), 1);
+        let recent = log.query(&AuditQuery {
+            from_ms: Some(now - 1000),
+            device_serial: Some("s2".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(recent, vec![all[0].clone()]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_csv_escaping() {
+        let csv = to_csv(&[entry(0, "kick", Outcome::Success, "s1")]);
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("time,ts_ms,event,outcome,device_serial,uuid,detail")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("1970-01-01T00:00:00+00:00,0,kick,success,s1,,\"a,\"\"b\"\"\"")
+        );
+    }
+
+    #[test]
+    fn test_csv_formula_prefixed() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\tcmd"), "'\tcmd");
+        assert_eq!(csv_field("\r=1"), "\"'\r=1\"");
+        assert_eq!(csv_field("phone"), "phone");
+    }
+
+    #[test]
+    fn test_audit_dir_is_temporary() {
+        // 测试时数据目录换成临时目录，审计日志不会写进真实的数据目录
+        assert!(get_audit_dir().starts_with(std::env::temp_dir()));
+    }
+}
@@ -0,0 +1,15 @@
+//! 本地中转服务器，用法：`cargo run --bin mock_relay -- 127.0.0.1:9876`
+use lqmy_desk_lib::relay::MockRelay;
+
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9876".to_string());
+    let relay = MockRelay::start(&addr).await?;
+    println!("[RELAY]桌面端请设置 SERVER_ADDRESS={}", relay.ws_url());
+
+    tokio::signal::ctrl_c().await?;
+    relay.stop().await;
+    Ok(())
+}
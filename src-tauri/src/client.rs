@@ -1,73 +1,188 @@
 use std::{
-    sync::{atomic::AtomicBool, Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Instant,
 };
 
 use actix_web::web;
-use awc::{
-    ws::{Frame, Message},
-    Client,
-};
+use awc::ws::{Frame, Message};
 use futures_util::{SinkExt, StreamExt};
 use lazy_static::lazy_static;
-use serde::Deserialize;
 use serde_json::{json, Value};
 use tokio::{
-    sync::Notify,
+    sync::{oneshot, Notify},
     time::{self, Duration},
 };
 
-// 你的 payload 和消息结构
-#[derive(Debug, Deserialize)]
-struct ServerMessage {
-    #[serde(rename = "type")]
-    msg_type: String,
-    from: String,
-    payload: Value,
-}
-
-#[derive(Debug, Deserialize)]
-struct PayloadWithCmd {
-    cmd: String,
-    data: Value,
-}
-
 // auth.rs 里有定义
 use crate::{
+    audit,
     client_utils::{
-        auth::{validate_jwt, AuthRequest},
-        current_user::{CrtlAns, CrtlReq, CurUsersInfo},
-        dialog::show_iknow_dialog,
-        disconnect::DisconnectReq,
-        password::generate_connection_password,
+        auth::{authorize, record_password_failure, refresh_jwt, resume},
+        conn_state::{set_state, ConnectionState},
+        credentials::CREDENTIALS,
+        current_user::{CrtlAns, CrtlReq},
+        device_key::CHALLENGES,
+        e2e::{self, E2eError, Inbound, PakeKind},
+        lockout::LOCKOUT,
+        outbound::{DeliveryResult, Priority, QueueError, OUTBOUND},
+        password::{self, ensure_connection_password},
+        permissions::Quality,
+        proxy::build_ws_client,
+        reconnect::{self, Backoff},
+        tokens::TOKEN_REGISTRY,
+        unattended,
+    },
+    config::{
+        sync_active_relay, update_uuid, CURRENT_USERS_INFO, NOT_CONNECTED_UUID, RELAY_POOL, UUID,
     },
-    config::{update_uuid, CONFIG, CURRENT_USERS_INFO, UUID},
-    webrtc::webrtc_connect::{close_peerconnection, JWTCandidateRequest, JWTOfferRequest},
+    events::{emit, ServerEvent},
+    lan,
+    protocol::{check_version, Command, ErrorReply, SignalMessage, PROTOCOL_VERSION},
+    webrtc::webrtc_connect::{close_peerconnection, flush_local_candidates},
 };
 lazy_static! {
     pub static ref CLOSE_NOTIFY: Arc<Notify> = Arc::new(Notify::new());
     pub static ref SEND_NOTIFY: Arc<Notify> = Arc::new(Notify::new());
-    pub static ref PENDING: Mutex<Vec<Value>> = Mutex::new(vec![]);
 }
-pub async fn start_client(_exit_flag: Arc<AtomicBool>) -> Result<(), Box<dyn std::error::Error>> {
-    let server_ws = CONFIG.lock().unwrap().server_address.clone(); // ws:// 或 wss://
-    generate_connection_password().await;
+
+/// 把 payload 包装成 message 发给 target_uuid，由主循环统一发送
+pub fn send_to_peer(target_uuid: &str, payload: Value) {
+    let _ = send_to_peer_with_priority(target_uuid, payload, Priority::Normal);
+}
+
+/// 按指定优先级入队，返回的 Receiver 可以拿到投递结果
+pub fn send_to_peer_with_priority(
+    target_uuid: &str,
+    payload: Value,
+    priority: Priority,
+) -> Result<oneshot::Receiver<DeliveryResult>, QueueError> {
+    // 已完成握手的对端一律加密
+    let payload = e2e::seal_for(target_uuid, payload);
+    // 局域网直连的手机不经过中转服务器
+    if lan::server::try_deliver(target_uuid, &payload) {
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(DeliveryResult::Sent);
+        return Ok(rx);
+    }
+    let uuid = UUID.lock().unwrap().clone();
+    let msg = SignalMessage::message(&uuid, target_uuid, payload);
+    let res = OUTBOUND.lock().unwrap().push(msg.to_json(), priority);
+    match &res {
+        Ok(_) => SEND_NOTIFY.notify_one(),
+        Err(e) => println!("[CLIENT]消息入队失败{:?}：{}", target_uuid, e),
+    }
+    res
+}
+
+/// 命令处理失败，回复错误信息而不是断开与服务器的连接
+pub fn reply_error(target_uuid: &str, reply: ErrorReply) {
+    println!("[CLIENT]向{:?}返回错误：{:?}", target_uuid, reply);
+    emit(ServerEvent::SessionError {
+        uuid: target_uuid.to_string(),
+        reason: reply.reason.clone(),
+    });
+    if !target_uuid.is_empty() {
+        send_to_peer(target_uuid, reply.to_payload());
+    }
+}
+
+/// 一次信令连接结束的原因
+#[derive(Debug)]
+enum SessionEnd {
+    /// 本地要求退出
+    Closed,
+    /// 与服务器的连接丢失，需要重连
+    Lost(String),
+    /// 服务器拒绝注册，不再重连
+    Rejected(String),
+}
+
+/// 信令连接的守护循环：断线后按指数退避重连，
+/// 期间保留连接口令和已建立的 PeerConnection，直到本地要求退出或被服务器拒绝
+pub async fn start_client(exit_flag: Arc<AtomicBool>) -> Result<(), Box<dyn std::error::Error>> {
+    ensure_connection_password().await;
+    let mut backoff = Backoff::default();
+    reconnect::reset_status();
+    RELAY_POOL.lock().unwrap().reset();
+
+    let result = loop {
+        let server_ws = sync_active_relay(); // ws:// 或 wss://
+        set_state(ConnectionState::Connecting {
+            attempt: backoff.attempt(),
+        });
+        let reason = match run_session(&server_ws, &mut backoff).await {
+            Ok(SessionEnd::Closed) => break Ok(()),
+            Ok(SessionEnd::Rejected(reason)) => {
+                // 被拒绝后不再重连，原因交给前端展示
+                set_state(ConnectionState::Rejected { reason });
+                reconnect::reset_status();
+                OUTBOUND.lock().unwrap().clear("服务器拒绝注册");
+                return Ok(());
+            }
+            Ok(SessionEnd::Lost(reason)) => reason,
+            Err(e) => e.to_string(),
+        };
+        set_state(ConnectionState::Failed {
+            reason: reason.clone(),
+        });
+        if exit_flag.load(Ordering::Relaxed) {
+            break Ok(());
+        }
+
+        // 还有健康的备用中转服务器时立即切换，否则退避后重试
+        let switched = RELAY_POOL.lock().unwrap().mark_failure(&reason);
+        let next_url = sync_active_relay();
+        if next_url != server_ws {
+            println!(
+                "[CLIENT]中转服务器{:?}失败，切换到{:?}",
+                server_ws, next_url
+            );
+            emit(ServerEvent::ActiveRelayChanged {
+                url: next_url.clone(),
+            });
+        }
+        if switched {
+            continue;
+        }
+
+        let delay = backoff.next_delay();
+        reconnect::mark_retrying(backoff.attempt(), delay, &reason);
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = CLOSE_NOTIFY.notified() => {
+                println!("[CLIENT] 重连等待中收到退出请求");
+                break Ok(());
+            }
+        }
+    };
+    reconnect::reset_status();
+    OUTBOUND.lock().unwrap().clear("信令连接已关闭");
+    set_state(ConnectionState::Disconnected);
+    result
+}
+
+/// 建立一次信令连接并处理消息，直到连接结束
+async fn run_session(
+    server_ws: &str,
+    backoff: &mut Backoff,
+) -> Result<SessionEnd, Box<dyn std::error::Error>> {
     println!("[CLIENT] Connecting to {}...", server_ws);
 
-    let (response, mut connection) = Client::new().ws(&server_ws).connect().await?;
-    //println!("[CLIENT] Connected, status: {:?}", response.status());
+    let (response, mut connection) = build_ws_client()?.ws(server_ws).connect().await?;
     println!("[CLIENT] Connected, status: {:?}", response.status());
 
-    // split Sink (for sending) and Stream (for receiving)
-    //let (sink, mut stream) = connection.split();
-    // wrap the sink in an Arc<Mutex<>> so we can share it between tasks
-    //let sink = Arc::new(Mutex::new(sink));
-
     {
-        let register_json = json!({
-            "type": "register",
-            "client_type": "desktop"
-        });
+        // 重连时带上之前分配的 uuid，服务器可以沿用，手机端不必重新配对
+        let previous_uuid = UUID.lock().unwrap().clone();
+        let register_json = SignalMessage::Register {
+            client_type: "desktop".to_string(),
+            version: PROTOCOL_VERSION,
+            uuid: is_assigned_uuid(&previous_uuid).then_some(previous_uuid),
+        }
+        .to_json();
         connection
             .send(Message::Text(register_json.to_string().into()))
             .await?;
@@ -80,372 +195,99 @@ pub async fn start_client(_exit_flag: Arc<AtomicBool>) -> Result<(), Box<dyn std
     const PONG_TIMEOUT: Duration = Duration::from_secs(6);
     // 心跳检测的开关
     let mut registered_flag = false;
-    // 发送锁
-    let send_lock = Mutex::new("lock".to_string());
 
     // --- 主动发送
     loop {
         tokio::select! {
-                // 发送消息
-                _=SEND_NOTIFY.notified()=>{
-                    let mut pending=PENDING.lock().unwrap();
-                    while let Some(json) = pending.pop(){
-                        connection.send(Message::Text(json.to_string().into())).await?;
-                        println!("[CLIENT]发送消息:{:?}",json);
-
+            // 发送消息
+            _ = SEND_NOTIFY.notified() => {
+                loop {
+                    let item = OUTBOUND.lock().unwrap().pop();
+                    let Some(item) = item else { break };
+                    match connection.send(Message::Text(item.msg.to_string().into())).await {
+                        Ok(()) => {
+                            println!("[CLIENT]发送消息:{:?}", item.msg);
+                            item.complete(DeliveryResult::Sent);
+                        }
+                        Err(e) => {
+                            // 放回队首，重连后继续发送
+                            let reason = format!("发送失败: {:?}", e);
+                            OUTBOUND.lock().unwrap().requeue_front(item, &reason);
+                            return Ok(SessionEnd::Lost(reason));
+                        }
                     }
-                    println!{"[CLIENT]pending应该为空{:?}",pending};
-                    drop(pending);
-
-
                 }
-                // 先检查退出
-                _ = CLOSE_NOTIFY.notified() => {
-                    println!("[CLIENT] Exit requested, sending close JSON");
-                    let close_json = json!({ "type": "close" });
-                    if let Err(e) =  send_lock.lock(){
-                        println!("[CLIENT] send lock {:?}",e)
-                    };
-                    connection.send(Message::Text(close_json.to_string().into())).await?;
-                    drop(send_lock.lock().unwrap());
-                    // 继续等服务器发协议层的 Close 帧，或者直接 break 结束
-                    break;
+            }
+            // 先检查退出
+            _ = CLOSE_NOTIFY.notified() => {
+                println!("[CLIENT] Exit requested, sending close JSON");
+                let close_json = SignalMessage::Close.to_json();
+                connection.send(Message::Text(close_json.to_string().into())).await?;
+                return Ok(SessionEnd::Closed);
+            }
+            // ping信息
+            _ = interval.tick() => {
+                if registered_flag {
+                    println!("[CLIENT] Ping");
+                    let uuid = UUID.lock().unwrap().clone();
+                    let ping_json = SignalMessage::Ping { from: uuid }.to_json();
+                    connection.send(Message::Text(ping_json.to_string().into())).await?;
                 }
-                // ping信息
-                _=interval.tick()=>{
-                    if registered_flag
-                    {
-                        println!("[CLIENT] Ping");
-                        let uuid=UUID.lock().unwrap().clone();
-                        let ping_json=json!({
-                            "type":"ping",
-                            "from":uuid
-                        });
-                        drop(uuid);
-                        if let Err(e) =  send_lock.lock(){
-                        println!("[CLIENT] send lock {:?}",e)
-                    };
-                        connection.send(Message::Text(ping_json.to_string().into())).await?;
-                        drop(send_lock.lock().unwrap());
-                    }
-                    // heartbeat check
-                    let now = Instant::now();
-                    if now.duration_since(last_heartbeat)>PONG_TIMEOUT{
-                        show_iknow_dialog("服务器断开", "请检查本地网络，或更换服务器").await;
-                        println!("[CLIENT]pong超时，last{:?},check time{:?}",last_heartbeat,now);
-                        return Ok(());
-                    }
-
+                // heartbeat check
+                let now = Instant::now();
+                if now.duration_since(last_heartbeat) > PONG_TIMEOUT {
+                    println!("[CLIENT]pong超时，last{:?},check time{:?}", last_heartbeat, now);
+                    return Ok(SessionEnd::Lost("pong超时".to_string()));
                 }
-                Some(Ok(frame)) = connection.next()=> {
-                    // if exit_flag.load(Ordering::Relaxed) {
-                    //     println!(
-                    //         "[CLIENT] Exit requested, sending close frame,{:?}",
-                    //         exit_flag
-                    //     );
-                    //     let json = json! ({
-                    //         "type":"close"
-                    //     });
-                    //     match connection
-                    //         .send(Message::Text(json.to_string().into()))
-                    //         .await{
-                    //             Ok(ok)=>{
-                    //                 println!("[CLOSE FRAME SEND]Success{:?}",ok);
-                    //             },
-                    //             Err(e)=>{
-                    //                 println!("[CLOSE FRAME SEND]Failure{:?}",e);
-                    //                 return Err(Box::new(e));
-                    //             }
-                    //         };
-                    //     break;
-                    // }
+            }
+            Some(Ok(frame)) = connection.next() => {
                 match frame {
                     Frame::Text(txt_bytes) => {
                         let txt_str = String::from_utf8_lossy(&txt_bytes);
-                        let v: Value = match serde_json::from_str(&txt_str) {
-                            Ok(v) => v,
+                        let msg = match serde_json::from_str::<SignalMessage>(&txt_str) {
+                            Ok(msg) => msg,
                             Err(e) => {
-                                eprintln!("[CLIENT] 非法的 JSON: {} ({})", txt_str, e);
-                                break;
-                            }
-                        };
-                        println!("[CLIENT]收到JSON{:?}", v);
-                        let msg_type = match v.get("type").and_then(Value::as_str) {
-                            Some(t) => t,
-                            None => {
-                                eprintln!("[CLIENT] 找不到 type 字段: {}", txt_str);
-                                break;
+                                eprintln!("[CLIENT] 无法识别的信令: {} ({})", txt_str, e);
+                                continue;
                             }
                         };
-
-                        match msg_type {
-                            "register_ack" => {
-                                let uuid = v.get("uuid").and_then(Value::as_str);
-                                match uuid {
-                                    Some(id) => {
-                                        update_uuid(id);
-                                        //更新heartbeat
-                                        last_heartbeat = Instant::now();
-                                        registered_flag=true;
-                                    }
-                                    None => {
-                                        eprintln!("[CLIENT] 找不到 uuid 字段: {}", txt_str);
-                                        break;
-                                    }
-                                }
+                        println!("[CLIENT]收到信令{:?}", msg);
+
+                        match msg {
+                            SignalMessage::RegisterAck { uuid } => {
+                                update_uuid(&uuid);
+                                set_state(ConnectionState::Registered { uuid: uuid.clone() });
+                                //更新heartbeat
+                                last_heartbeat = Instant::now();
+                                registered_flag = true;
+                                backoff.reset();
+                                RELAY_POOL.lock().unwrap().mark_success();
+                                reconnect::mark_connected();
+                                // 断线期间积压的消息
+                                SEND_NOTIFY.notify_one();
                             }
-                            "register_reject" => {
-                                let reason = v.get("reason").and_then(Value::as_str);
-                                match reason {
-                                    Some(re) => {
-                                        println!("[CLIENT]服务器注册拒绝：{:?}", re);
-                                        //服务器连接失败，需要修改前端连接状态为关闭
-                                        todo!()
-                                    }
-                                    None => {
-                                        eprintln!("[CLIENT] 找不到 reason 字段: {}", txt_str);
-                                        break;
-                                    }
-                                }
+                            SignalMessage::RegisterReject { reason } => {
+                                println!("[CLIENT]服务器注册拒绝：{:?}", reason);
+                                return Ok(SessionEnd::Rejected(reason));
                             }
-                            "message" => {
-                                let msg = serde_json::from_str::<ServerMessage>(&txt_str).unwrap();
-                                if let Ok(p) = serde_json::from_str::<PayloadWithCmd>(msg.payload.clone().as_str().unwrap())
-                                {
-                                    println!("[message]payload {:?}",p);
-                                    match p.cmd.as_str() {
-                                        "auth" => {
-                                            // let authreq=json!(AuthRequest{ device_name: p.data.get("device_name").and_then(Value::as_str).unwrap().to_string(),
-                                            // device_serial: p.data.get("device_serial").and_then(Value::as_str).unwrap().to_string(),
-                                            // password: p.data.get("password").and_then(Value::as_str).unwrap().to_string(),
-                                            // uuid: msg.from.clone() });
-
-                                            if let Ok(auth_req) =
-                                                serde_json::from_str::<AuthRequest>(p.data.as_str().unwrap())
-                                            {
-                                                //println!("[message]payload value {:?}",auth_req);
-                                                tokio::spawn(async move{
-
-                                                    let result =
-                                                        crate::client_utils::auth::authenticate(web::Json(auth_req))
-                                                    .await;
-                                                    let uuid=UUID.lock().unwrap().clone();
-                                                    let reply = json!({
-                                                        "type": "message",
-                                                        "target_uuid": msg.from,
-                                                        "from":uuid,
-                                                        "payload": json!(result),
-                                                    });
-                                                    drop(uuid);
-                                                    let mut pending=PENDING.lock().unwrap();
-                                                    pending.push(reply.clone());
-                                                    drop(pending);
-                                                    SEND_NOTIFY.notify_one();
-                                                    // send_lock.lock();
-                                                    // connection
-                                                    //     .send(Message::Text(reply.to_string().into()))
-                                                    //     .await;
-                                                    // drop(send_lock);
-                                                    println!("[CLIENT]认证返回：{:?}",reply)
-                                                });
-
-                                            }
-                                        }
-                                        "offer"=>{
-                                            if let Ok(jwt_offer_req)=
-                                                serde_json::from_str::<JWTOfferRequest>(p.data.as_str().unwrap())
-                                            {
-                                                //println!("[message]payload value {:?}",jwt_offer_req);
-                                                if !validate_jwt(&jwt_offer_req.jwt){
-                                                    println!("[OFFER_HANDLER]来自{:?}的JWT验证失败",msg.from);
-                                                    break;
-                                                }
-                                                //println!("[message]payload value {:?}",offer_req);
-                                                tokio::spawn(async move{
-                                                    let res=crate::webrtc::webrtc_connect::handle_webrtc_offer(&web::Json(jwt_offer_req)).await;
-                                                    {
-                                                        let uuid=UUID.lock().unwrap().clone();
-
-                                                        let payload=json!({"cmd":"answear","value":res});
-                                                        let reply = json!({
-                                                            "type": "message",
-                                                            "target_uuid": msg.from,
-                                                            "from":uuid,
-                                                            "payload": json!(payload),
-                                                        });
-                                                        drop(uuid);
-
-                                                        let mut pending=PENDING.lock().unwrap();
-                                                        pending.push(reply.clone());
-                                                        drop(pending);
-                                                        SEND_NOTIFY.notify_one();
-                                                        println!("[CLIENT]RTC返回Answear：{:?}",reply);
-                                                    }
-                                                    // 发送ICE
-                                                    // let uuid=UUID.lock().unwrap().clone();
-                                                    // let res=get_ice_candidates(&msg.from).await;
-                                                    // let payload=json!({"cmd":"answear","value":res});
-                                                    // let reply = json!({
-                                                    //     "type": "message",
-                                                    //     "target_uuid": msg.from,
-                                                    //     "from":uuid,
-                                                    //     "payload": json!(payload),
-                                                    // });
-                                                    // drop(uuid);
-
-                                                    // let mut pending=PENDING.lock().unwrap();
-                                                    // pending.push(reply.clone());
-                                                    // drop(pending);
-                                                    // SEND_NOTIFY.notify_one();
-                                                    // println!("[CLIENT]RTC返回ICE：{:?}",reply);
-                                                });
-
-                                            }else {
-                                                println!("[CLIENT]OFFER解析失败")
-                                            }
-                                        }
-                                        "candidate"=>{
-                                            if let Ok(candidate_req)=
-                                                serde_json::from_str::<JWTCandidateRequest>(p.data.as_str().unwrap())
-                                            {
-                                                if !validate_jwt(&candidate_req.jwt){
-                                                    println!("[CNADIDATE_HANDLER]来自{:?}的JWT验证失败",msg.from);
-                                                    break;
-                                                }
-                                                let res=crate::webrtc::webrtc_connect::handle_ice_candidate(&web::Json(candidate_req)).await;
-                                                // let uuid=UUID.lock().unwrap().clone();
-                                                // let payload=json!({"cmd":"candiate","value":res});
-                                                // let reply = json!({
-                                                //     "type": "message",
-                                                //     "target_uuid": msg.from,
-                                                //     "from":uuid,
-                                                //     "payload": json!(res),
-                                                // });
-                                                // drop(uuid);
-
-                                                // let mut pending=PENDING.lock().unwrap();
-                                                // pending.push(reply.clone());
-                                                // drop(pending);
-                                                // SEND_NOTIFY.notify_one();
-
-                                                println!("[CLIENT]接受对方Candidate：{:?}",res);
-                                            }
-                                        }
-                                        "disconnect"=>{
-                                             if let Ok(disconnect_req)=
-                                                serde_json::from_str::<DisconnectReq>(p.data.as_str().unwrap())
-                                                {
-                                                    println!("[message]payload value {:?}",disconnect_req);
-                                                    tokio::spawn(async move{
-                                                    //let res=crate::webrtc::webrtc_connect::handle_webrtc_offer(web::Json(disconnect_req)).await;
-                                                        // JWT验证
-                                                        if !disconnect_req.verify(){
-                                                            println!("[DISCONNECT]JWT 验证失败")
-
-                                                        }else {
-
-                                                            CURRENT_USERS_INFO.lock().unwrap().delete_by_uuid(&msg.from);
-                                                        };
-                                                    });
-                                                }
-                                        }
-                                        "control"=>{
-                                            if let Ok(control_req)=
-                                            serde_json::from_str::<CrtlReq>(p.data.as_str().unwrap()){
-                                                tokio::spawn(async move{
-                                                    //let res=crate::webrtc::webrtc_connect::handle_webrtc_offer(web::Json(disconnect_req)).await;
-                                                    // JWT验证
-                                                    let body:String;
-                                                    let status =if !validate_jwt(&control_req.jwt)||CURRENT_USERS_INFO.lock().unwrap().has_controller(){
-                                                        println!("[CONTROL]已有控制者");
-                                                        body="已有控制者".to_string();
-                                                        "400"
-
-                                                    }else if CURRENT_USERS_INFO.lock().unwrap().set_ptr_by_serial(&control_req.device_serial) {
-                                                        body="获得控制权".to_string();
-                                                        "200"
-                                                    }else{
-                                                        body="用户不存在".to_string();
-                                                        "400"
-                                                    };
-                                                    let result=CrtlAns{ status: status.to_string(), body:body };
-                                                    let uuid=UUID.lock().unwrap().clone();
-                                                    let reply = json!({
-                                                        "type": "message",
-                                                        "target_uuid": msg.from,
-                                                        "from":uuid,
-                                                        "payload": json!(result),
-                                                    });
-                                                    drop(uuid);
-                                                    let mut pending=PENDING.lock().unwrap();
-                                                    pending.push(reply.clone());
-                                                    drop(pending);
-                                                    SEND_NOTIFY.notify_one();
-
-                                                    });
-                                            }
-                                        }
-                                        "revokectrl"=>{
-                                            if let Ok(control_req)=
-                                            serde_json::from_str::<CrtlReq>(p.data.as_str().unwrap()){
-                                                tokio::spawn(async move{
-
-                                                    if !validate_jwt(&control_req.jwt){
-                                                        return ;
-                                                    }
-                                                    if CURRENT_USERS_INFO.lock().unwrap().is_controller_by_uuid(control_req.uuid.clone()){
-                                                        close_peerconnection(&control_req.uuid).await
-                                                    }
-
-                                                    });
-                                            }
-                                        }
-                                        "closertc"=>{
-                                            if let Ok(control_req)=
-                                            serde_json::from_str::<CrtlReq>(p.data.as_str().unwrap()){
-                                                tokio::spawn(async move{
-                                                    //let res=crate::webrtc::webrtc_connect::handle_webrtc_offer(web::Json(disconnect_req)).await;
-                                                    // JWT验证
-                                                    let body:String;
-                                                    let status =if !validate_jwt(&control_req.jwt)||CURRENT_USERS_INFO.lock().unwrap().has_controller(){
-                                                        println!("[CONTROL]已有控制者");
-                                                        body="已有控制者".to_string();
-                                                        "400"
-
-                                                    }else if CURRENT_USERS_INFO.lock().unwrap().set_ptr_by_serial(&control_req.device_serial) {
-                                                        body="获得控制权".to_string();
-                                                        "200"
-                                                    }else{
-                                                        body="用户不存在".to_string();
-                                                        "400"
-                                                    };
-                                                    let result=CrtlAns{ status: status.to_string(), body:body };
-                                                    let uuid=UUID.lock().unwrap().clone();
-                                                    let reply = json!({
-                                                        "type": "message",
-                                                        "target_uuid": msg.from,
-                                                        "from":uuid,
-                                                        "payload": json!(result),
-                                                    });
-                                                    drop(uuid);
-                                                    let mut pending=PENDING.lock().unwrap();
-                                                    pending.push(reply.clone());
-                                                    drop(pending);
-                                                    SEND_NOTIFY.notify_one();
-
-                                                    });
-                                            }
-                                        }
-
-                                        _ => println!("[CLIENT] Unknown cmd: {}", p.cmd),
-                                    }
-                                }
+                            SignalMessage::Message { from, payload, version, .. } => {
+                                handle_message(from, &payload, version).await;
                             }
-                            "pong"=>{
-                                last_heartbeat=Instant::now();
+                            SignalMessage::Pong => {
+                                last_heartbeat = Instant::now();
                                 println!("[CLIENT]pong!")
                             }
-                            other => println!("[CLIENT] Unknown message type: {}", other),
+                            SignalMessage::Close => {
+                                println!("[CLIENT]服务器要求关闭连接");
+                                return Ok(SessionEnd::Lost("服务器关闭连接".to_string()));
+                            }
+                            SignalMessage::PeerOffline { uuid } => {
+                                // 对端下线，未完成的加密会话不再保留
+                                e2e::forget(&uuid);
+                            }
+                            SignalMessage::Register { .. } | SignalMessage::Ping { .. } => {
+                                println!("[CLIENT] 忽略发往桌面端的信令: {}", txt_str)
+                            }
                         }
                     }
                     Frame::Ping(msg) => {
@@ -453,13 +295,417 @@ pub async fn start_client(_exit_flag: Arc<AtomicBool>) -> Result<(), Box<dyn std
                     }
                     Frame::Close(reason) => {
                         println!("[CLIENT] Connection closed: {:?}", reason);
-                        return Ok(());
+                        return Ok(SessionEnd::Lost(format!("连接关闭: {:?}", reason)));
                     }
                     _ => {}
                 }
             }
         }
     }
+}
+
+/// UUID 是否为服务器分配的值，而不是未连接时的占位
+fn is_assigned_uuid(uuid: &str) -> bool {
+    uuid != NOT_CONNECTED_UUID
+}
 
-    Ok(())
+/// 处理一条 message：协议版本不支持时回复错误，否则交给 handle_payload
+pub async fn handle_message(from: String, payload: &Value, version: u32) {
+    if let Err(e) = check_version(version) {
+        eprintln!("[CLIENT]来自{:?}的消息版本不支持: {}", from, e);
+        reply_error(&from, e.to_reply());
+        return;
+    }
+    handle_payload(from, payload).await
+}
+
+/// 处理 message 的 payload：先完成端到端加密的握手或解密，再解析成命令
+pub async fn handle_payload(from: String, payload: &Value) {
+    if let Err(remaining) = LOCKOUT.lock().unwrap().check(None, Some(&from)) {
+        let reason = format!("尝试次数过多，请{}秒后再试", remaining.as_secs() + 1);
+        reply_error(&from, ErrorReply::new(429, reason, None));
+        return;
+    }
+    let payload = match e2e::open_inbound(&from, payload, pake_secret) {
+        Ok(Inbound::Command(payload)) => payload,
+        Ok(Inbound::Handshake { reply, session }) => {
+            // pake_reply 以明文发出，之后的消息才加密
+            send_to_peer(&from, reply);
+            e2e::install(&from, session);
+            return;
+        }
+        Err(e) => {
+            eprintln!("[CLIENT]来自{:?}的加密消息处理失败: {}", from, e);
+            if let E2eError::BadSecret(kind) = &e {
+                // 第一条就解不开说明握手用的秘密不对，和口令错误一样计入锁定和审计
+                match kind {
+                    PakeKind::Unattended => unattended::record_failure(&from),
+                    PakeKind::Password => password::record_failure(&from),
+                    PakeKind::Credential(_) => {}
+                }
+                record_password_failure(None, &from, "握手秘密错误").await;
+            }
+            reply_error(&from, e.to_reply());
+            return;
+        }
+    };
+    match Command::from_payload(&payload) {
+        Ok(cmd) => handle_command(from, cmd).await,
+        Err(e) => {
+            eprintln!("[CLIENT]来自{:?}的命令解析失败: {}", from, e);
+            reply_error(&from, e.to_reply());
+        }
+    }
+}
+
+/// 按握手请求的种类给出本机一侧的秘密
+fn pake_secret(kind: &PakeKind) -> Result<String, E2eError> {
+    match kind {
+        PakeKind::Password => password::pake_secret().ok_or(E2eError::Handshake),
+        PakeKind::Unattended => unattended::pake_secret().ok_or(E2eError::Handshake),
+        PakeKind::Credential(unsigned) => CREDENTIALS
+            .lock()
+            .unwrap()
+            .pake_secret(unsigned)
+            .map_err(|e| {
+                println!("[E2E]设备凭据不能用于握手：{}", e);
+                E2eError::Handshake
+            }),
+    }
+}
+
+/// 处理手机端发来的命令，结果通过 send_to_peer 回复给 from
+pub async fn handle_command(from: String, cmd: Command) {
+    println!("[message]cmd {:?}", cmd.name());
+    // token 必须属于发送方，且请求里的 uuid、序列号与 token 一致
+    let claims = match authorize(&from, &cmd) {
+        Ok(Some(claims)) => {
+            // 带着有效 token 的对端已经认证过，重新握手后的会话也不再过期
+            e2e::mark_authenticated(&from);
+            Some(claims)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            println!("[AUTHZ]来自{:?}的{}被拒绝：{}", from, cmd.name(), e);
+            reply_error(&from, e.to_reply(cmd.name()));
+            return;
+        }
+    };
+    match cmd {
+        Command::Auth(mut auth_req) => {
+            // 以中转服务器给出的 from 为准，签发的 token 绑定到它
+            auth_req.uuid = from.clone();
+            tokio::spawn(async move {
+                let result = crate::client_utils::auth::authenticate(web::Json(auth_req)).await;
+                println!("[CLIENT]认证返回：{:?}", result);
+                if result.status == "200" {
+                    e2e::mark_authenticated(&from);
+                }
+                send_to_peer(&from, json!(result));
+            });
+        }
+        Command::Offer(mut jwt_offer_req) => {
+            let Some(claims) = claims else { return };
+            // 画质不超过权限配置的上限
+            let quality = Quality::from_mode(&jwt_offer_req.mode).min(claims.max_quality);
+            jwt_offer_req.mode = quality.as_mode().to_string();
+            tokio::spawn(async move {
+                let res =
+                    crate::webrtc::webrtc_connect::handle_webrtc_offer(&web::Json(jwt_offer_req))
+                        .await;
+                let client_uuid = res.client_uuid.clone();
+                let payload = json!({"cmd":"answear","value":res});
+                println!("[CLIENT]RTC返回Answear：{:?}", payload);
+                let delivery = send_to_peer_with_priority(&from, payload, Priority::Normal);
+                // answer 入队后才放出本地 ICE 候选，保证对方先收到 answer
+                flush_local_candidates(&client_uuid);
+                if let Ok(rx) = delivery {
+                    println!("[CLIENT]Answear投递结果：{:?}", rx.await);
+                }
+            });
+        }
+        Command::Candidate(candidate_req) => {
+            let res =
+                crate::webrtc::webrtc_connect::handle_ice_candidate(&web::Json(candidate_req))
+                    .await;
+            println!("[CLIENT]接受对方Candidate：{:?}", res);
+        }
+        Command::Disconnect(disconnect_req) => {
+            println!("[message]payload value {:?}", disconnect_req);
+            // 主动断开后 token 不再可用
+            if let Some(claims) = &claims {
+                TOKEN_REGISTRY.lock().unwrap().revoke(&claims.jti);
+            }
+            CURRENT_USERS_INFO.lock().unwrap().delete_by_uuid(&from);
+        }
+        Command::Refresh(_) => {
+            let Some(claims) = claims else { return };
+            tokio::spawn(async move {
+                let result = refresh_jwt(&claims).await;
+                println!("[CLIENT]刷新token返回：{:?}", result.status);
+                send_to_peer(&from, json!({"cmd":"refresh","value":result}));
+            });
+        }
+        Command::Resume(resume_req) => {
+            tokio::spawn(async move {
+                let result = resume(&from, &resume_req).await;
+                println!("[CLIENT]恢复会话返回：{:?}", result.status);
+                if result.status == "200" {
+                    e2e::mark_authenticated(&from);
+                }
+                send_to_peer(&from, json!({"cmd":"resume","value":result}));
+            });
+        }
+        Command::Challenge(_) => {
+            let nonce = CHALLENGES.lock().unwrap().issue(&from);
+            send_to_peer(&from, json!({"cmd":"challenge","value":{"nonce":nonce}}));
+        }
+        Command::Control(control_req) | Command::CloseRtc(control_req) => {
+            let result = grant_control(&control_req);
+            let _ = send_to_peer_with_priority(&from, json!(result), Priority::High);
+        }
+        Command::RevokeCtrl(control_req) => {
+            tokio::spawn(async move {
+                if CURRENT_USERS_INFO
+                    .lock()
+                    .unwrap()
+                    .is_controller_by_uuid(control_req.uuid.clone())
+                {
+                    close_peerconnection(&control_req.uuid).await
+                }
+            });
+        }
+    }
+}
+
+/// 请求控制权：已通过 authorize 且当前没有控制者时授予
+fn grant_control(control_req: &CrtlReq) -> CrtlAns {
+    let mut cur_users = CURRENT_USERS_INFO.lock().unwrap();
+    let (status, body) = if cur_users.has_controller() {
+        println!("[CONTROL]已有控制者");
+        ("400", "已有控制者")
+    } else if cur_users.set_ptr_by_serial(&control_req.device_serial) {
+        emit(ServerEvent::ControlGranted {
+            device_id: control_req.device_serial.clone(),
+            uuid: control_req.uuid.clone(),
+        });
+        ("200", "获得控制权")
+    } else {
+        ("400", "用户不存在")
+    };
+    let (event, outcome) = if status == "200" {
+        ("control_granted", audit::Outcome::Success)
+    } else {
+        ("control_denied", audit::Outcome::Denied)
+    };
+    audit::record(
+        event,
+        outcome,
+        Some(&control_req.device_serial),
+        Some(&control_req.uuid),
+        body,
+    );
+    CrtlAns {
+        status: status.to_string(),
+        body: body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_utils::approval::{ApprovalDecision, FixedApprover, UiApprover, APPROVALS};
+    use crate::client_utils::e2e::{E2eSession, Role, PAKE_IDENTITY};
+    use crate::config::set_relay_endpoints;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use lqmy_desk_lib::relay::MockRelay;
+    use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+    type Conn = actix_codec::Framed<awc::BoxedSocket, awc::ws::Codec>;
+
+    async fn send(conn: &mut Conn, msg: Value) {
+        conn.send(Message::Text(msg.to_string().into()))
+            .await
+            .unwrap();
+    }
+
+    /// 等待第一条满足条件的 message，sealed 的先解密，跳过 candidate 等其他消息
+    async fn recv_payload(
+        conn: &mut Conn,
+        mut session: Option<&mut E2eSession>,
+        accept: impl Fn(&Value) -> bool,
+    ) -> Value {
+        let wait = async {
+            loop {
+                let Frame::Text(bytes) = conn.next().await.unwrap().unwrap() else {
+                    continue;
+                };
+                let msg: Value = serde_json::from_slice(&bytes).unwrap();
+                if msg["type"] != "message" {
+                    continue;
+                }
+                let payload = match session.as_deref_mut() {
+                    Some(session) if msg["payload"]["cmd"] == "sealed" => {
+                        session.open(&msg["payload"]["data"]).unwrap()
+                    }
+                    _ => msg["payload"].clone(),
+                };
+                if accept(&payload) {
+                    return payload;
+                }
+            }
+        };
+        time::timeout(Duration::from_secs(10), wait).await.unwrap()
+    }
+
+    async fn wait_until(cond: impl Fn() -> bool) {
+        let wait = async {
+            while !cond() {
+                time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(10), wait).await.unwrap()
+    }
+
+    fn pake_start(password: &str) -> (Spake2<Ed25519Group>, Value) {
+        let (state, msg) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(password.as_bytes()),
+            &Identity::new(PAKE_IDENTITY),
+        );
+        let start = json!({ "cmd": "pake_start", "data": { "msg": BASE64.encode(msg) } });
+        (state, start)
+    }
+
+    #[actix_rt::test]
+    async fn test_start_client_against_mock_relay() {
+        let relay = MockRelay::start("127.0.0.1:0").await.unwrap();
+        set_relay_endpoints(vec![relay.ws_url()]).unwrap();
+        APPROVALS.set_approver(Arc::new(FixedApprover(ApprovalDecision {
+            approve: true,
+            remember: false,
+        })));
+        let exit_flag = Arc::new(AtomicBool::new(false));
+        let client = actix_rt::spawn(start_client(exit_flag.clone()));
+
+        let desktop_uuid = || {
+            relay
+                .clients()
+                .into_iter()
+                .find(|(_, client_type)| client_type == "desktop")
+                .map(|(uuid, _)| uuid)
+        };
+        wait_until(|| desktop_uuid().is_some()).await;
+        let desktop = desktop_uuid().unwrap();
+
+        let (_, mut phone) = awc::Client::new()
+            .ws(relay.ws_url())
+            .connect()
+            .await
+            .unwrap();
+        send(
+            &mut phone,
+            json!({ "type": "register", "client_type": "mobile" }),
+        )
+        .await;
+        let Frame::Text(ack) = phone.next().await.unwrap().unwrap() else {
+            panic!("register_ack 不是文本帧");
+        };
+        let ack: Value = serde_json::from_slice(&ack).unwrap();
+        let phone_uuid = ack["uuid"].as_str().unwrap().to_string();
+        let message = |payload: Value| {
+            json!({
+                "type": "message",
+                "target_uuid": desktop,
+                "payload": payload,
+            })
+        };
+
+        // 带口令的明文 auth 直接拒绝
+        let auth = json!({ "cmd": "auth", "data": {
+            "device_name": "test-phone",
+            "device_serial": "client-test-serial",
+            "password": password::current_password(),
+            "uuid": "",
+        }});
+        send(&mut phone, message(auth.clone())).await;
+        let rejected = recv_payload(&mut phone, None, |p| p["cmd"] == "error").await;
+        assert_eq!(rejected["value"]["code"], 403);
+
+        // 用连接口令握手，之后的命令都加密
+        let (state, start) = pake_start(&password::current_password());
+        send(&mut phone, message(start)).await;
+        let reply = recv_payload(&mut phone, None, |p| p["cmd"] == "pake_reply").await;
+        let host_msg = BASE64
+            .decode(reply["value"]["msg"].as_str().unwrap())
+            .unwrap();
+        let mut session = E2eSession::derive(&state.finish(&host_msg).unwrap(), Role::Mobile);
+
+        // 认证：口令正确，审批自动通过
+        send(&mut phone, message(session.seal(&auth))).await;
+        let auth = recv_payload(&mut phone, Some(&mut session), |p| {
+            p.get("status").is_some()
+        })
+        .await;
+        assert_eq!(auth["status"], "200", "{}", auth);
+        let jwt = auth["body"].as_str().unwrap().to_string();
+        assert_eq!(
+            session_serial(&phone_uuid).as_deref(),
+            Some("client-test-serial")
+        );
+
+        // offer：SDP 无效时也要收到 answear，说明 token 校验通过
+        let offer = json!({ "cmd": "offer", "data": {
+            "client_uuid": phone_uuid,
+            "sdp": "invalid",
+            "mode": "balanced",
+            "jwt": jwt,
+        }});
+        send(&mut phone, message(session.seal(&offer))).await;
+        let answer = recv_payload(&mut phone, Some(&mut session), |p| p["cmd"] == "answear").await;
+        assert_eq!(answer["value"]["client_uuid"], phone_uuid.as_str());
+
+        let disconnect = json!({ "cmd": "disconnect", "data": {
+            "jwt": jwt,
+            "device_serial": "client-test-serial",
+        }});
+        send(&mut phone, message(session.seal(&disconnect))).await;
+        wait_until(|| session_serial(&phone_uuid).is_none()).await;
+        close_peerconnection(&phone_uuid).await;
+
+        // 中转服务器断开桌面端后，桌面端退避重连并沿用原来的 uuid，
+        // 重连前中转服务器回复 404，重连后重新握手能收到 pake_reply
+        assert!(relay.disconnect(&desktop).await);
+        let reconnected = async {
+            loop {
+                let (_, start) = pake_start(&password::current_password());
+                send(&mut phone, message(start)).await;
+                let reply = recv_payload(&mut phone, None, |p| {
+                    p["cmd"] == "pake_reply" || p["cmd"] == "error"
+                })
+                .await;
+                if reply["cmd"] == "pake_reply" {
+                    break;
+                }
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(10), reconnected)
+            .await
+            .unwrap();
+        assert_eq!(*UUID.lock().unwrap(), desktop);
+
+        exit_flag.store(true, Ordering::Relaxed);
+        CLOSE_NOTIFY.notify_one();
+        time::timeout(Duration::from_secs(10), client)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        APPROVALS.set_approver(Arc::new(UiApprover));
+        relay.stop().await;
+    }
+
+    fn session_serial(uuid: &str) -> Option<String> {
+        CURRENT_USERS_INFO.lock().unwrap().serial_by_uuid(uuid)
+    }
 }
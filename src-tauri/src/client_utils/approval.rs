@@ -0,0 +1,322 @@
+//! 连接请求的审批队列
+//!
+//! 口令正确后不再弹出阻塞的对话框，而是生成一条待审批请求，通过 `approval-requested`
+//! 事件推给前端，前端调用 `answer_approval` 作答；超时自动拒绝。
+//! 无界面的主机和测试可以用 [`Approver`] 直接给出结论。
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use super::user_manager::UserType;
+use crate::events::{emit, ServerEvent};
+
+/// 同时等待审批的请求上限，防止刷请求
+const MAX_PENDING: usize = 16;
+
+/// 一条待审批的连接请求
+#[derive(Debug, Clone, Serialize)]
+pub struct ApprovalRequest {
+    pub id: String,
+    pub device_name: String,
+    pub device_serial: String,
+    pub uuid: String,
+    /// 已记录的用户类别，新设备为 None
+    pub user_type: Option<UserType>,
+    pub requested_at_ms: u64,
+    pub timeout_ms: u64,
+}
+
+/// 审批结论
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApprovalDecision {
+    pub approve: bool,
+    /// 记住这次决定：同意时把设备设为信任用户
+    #[serde(default)]
+    pub remember: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApprovalOutcome {
+    Approved { remember: bool },
+    Denied,
+    TimedOut,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ApprovalError {
+    #[error("该设备已有请求在等待审批")]
+    AlreadyPending,
+
+    #[error("待审批的请求过多")]
+    QueueFull,
+
+    #[error("审批请求{0}不存在或已结束")]
+    NotFound(String),
+}
+
+/// 审批方式，返回 None 表示交给界面处理
+pub trait Approver: Send + Sync {
+    fn decide(&self, request: &ApprovalRequest) -> Option<ApprovalDecision>;
+}
+
+/// 默认：全部交给界面
+pub struct UiApprover;
+
+impl Approver for UiApprover {
+    fn decide(&self, _request: &ApprovalRequest) -> Option<ApprovalDecision> {
+        None
+    }
+}
+
+/// 固定结论，用于无界面主机和测试
+pub struct FixedApprover(pub ApprovalDecision);
+
+impl Approver for FixedApprover {
+    fn decide(&self, _request: &ApprovalRequest) -> Option<ApprovalDecision> {
+        Some(self.0)
+    }
+}
+
+struct Pending {
+    request: ApprovalRequest,
+    reply: oneshot::Sender<ApprovalDecision>,
+}
+
+/// 请求结束或等待的 future 被丢弃时移出队列，避免设备一直处于 AlreadyPending
+struct PendingGuard<'a> {
+    pending: &'a Mutex<HashMap<String, Pending>>,
+    id: String,
+    finished: bool,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let removed = self.pending.lock().unwrap().remove(&self.id).is_some();
+        if removed && !self.finished {
+            println!("[APPROVAL]请求{:?}已取消", self.id);
+            emit(ServerEvent::ApprovalResolved {
+                id: self.id.clone(),
+                outcome: "cancelled".to_string(),
+            });
+        }
+    }
+}
+
+pub struct ApprovalQueue {
+    pending: Mutex<HashMap<String, Pending>>,
+    approver: Mutex<Arc<dyn Approver>>,
+    timeout: Mutex<Duration>,
+}
+
+impl ApprovalQueue {
+    pub fn new(approver: Arc<dyn Approver>, timeout: Duration) -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            approver: Mutex::new(approver),
+            timeout: Mutex::new(timeout),
+        }
+    }
+
+    pub fn set_approver(&self, approver: Arc<dyn Approver>) {
+        *self.approver.lock().unwrap() = approver;
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) {
+        *self.timeout.lock().unwrap() = timeout;
+    }
+
+    /// 当前等待审批的请求，按时间排序
+    pub fn pending(&self) -> Vec<ApprovalRequest> {
+        let mut list: Vec<ApprovalRequest> = self
+            .pending
+            .lock()
+            .unwrap()
+            .values()
+            .map(|p| p.request.clone())
+            .collect();
+        list.sort_by_key(|r| r.requested_at_ms);
+        list
+    }
+
+    /// 提交请求并等待结论，超时视为拒绝
+    pub async fn request(
+        &self,
+        device_name: &str,
+        device_serial: &str,
+        uuid: &str,
+        user_type: Option<UserType>,
+    ) -> Result<ApprovalOutcome, ApprovalError> {
+        let timeout = *self.timeout.lock().unwrap();
+        let request = ApprovalRequest {
+            id: uuid::Uuid::new_v4().to_string(),
+            device_name: device_name.to_string(),
+            device_serial: device_serial.to_string(),
+            uuid: uuid.to_string(),
+            user_type,
+            requested_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            timeout_ms: timeout.as_millis() as u64,
+        };
+
+        let approver = self.approver.lock().unwrap().clone();
+        if let Some(decision) = approver.decide(&request) {
+            return Ok(to_outcome(decision));
+        }
+
+        let rx = {
+            let mut pending = self.pending.lock().unwrap();
+            // 同一设备或同一 uuid 只保留一条请求
+            if pending.values().any(|p| {
+                p.request.device_serial == request.device_serial || p.request.uuid == request.uuid
+            }) {
+                return Err(ApprovalError::AlreadyPending);
+            }
+            if pending.len() >= MAX_PENDING {
+                return Err(ApprovalError::QueueFull);
+            }
+            let (tx, rx) = oneshot::channel();
+            pending.insert(
+                request.id.clone(),
+                Pending {
+                    request: request.clone(),
+                    reply: tx,
+                },
+            );
+            rx
+        };
+        let mut guard = PendingGuard {
+            pending: &self.pending,
+            id: request.id.clone(),
+            finished: false,
+        };
+        println!("[APPROVAL]等待审批：{:?}", request);
+        emit(ServerEvent::ApprovalRequested(request.clone()));
+
+        let outcome = match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(decision)) => to_outcome(decision),
+            // 超时或者请求被丢弃，由 guard 移出队列
+            _ => ApprovalOutcome::TimedOut,
+        };
+        guard.finished = true;
+        drop(guard);
+        println!("[APPROVAL]请求{:?}结束：{:?}", request.id, outcome);
+        emit(ServerEvent::ApprovalResolved {
+            id: request.id,
+            outcome: match outcome {
+                ApprovalOutcome::Approved { .. } => "approved",
+                ApprovalOutcome::Denied => "denied",
+                ApprovalOutcome::TimedOut => "timed_out",
+            }
+            .to_string(),
+        });
+        Ok(outcome)
+    }
+
+    /// 界面作答
+    pub fn answer(&self, id: &str, decision: ApprovalDecision) -> Result<(), ApprovalError> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| ApprovalError::NotFound(id.to_string()))?;
+        pending
+            .reply
+            .send(decision)
+            .map_err(|_| ApprovalError::NotFound(id.to_string()))
+    }
+}
+
+fn to_outcome(decision: ApprovalDecision) -> ApprovalOutcome {
+    if decision.approve {
+        ApprovalOutcome::Approved {
+            remember: decision.remember,
+        }
+    } else {
+        ApprovalOutcome::Denied
+    }
+}
+
+lazy_static! {
+    pub static ref APPROVALS: ApprovalQueue =
+        ApprovalQueue::new(Arc::new(UiApprover), Duration::from_secs(60));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_answer_from_ui() {
+        let queue = Arc::new(ApprovalQueue::new(
+            Arc::new(UiApprover),
+            Duration::from_secs(5),
+        ));
+        let waiting = {
+            let queue = queue.clone();
+            actix_rt::spawn(async move { queue.request("phone", "serial-1", "u1", None).await })
+        };
+        while queue.pending().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        // 同一设备再次请求不会重复排队
+        assert_eq!(
+            queue.request("phone", "serial-1", "u2", None).await,
+            Err(ApprovalError::AlreadyPending)
+        );
+        let id = queue.pending()[0].id.clone();
+        let decision = ApprovalDecision {
+            approve: true,
+            remember: true,
+        };
+        queue.answer(&id, decision).unwrap();
+        assert_eq!(
+            waiting.await.unwrap(),
+            Ok(ApprovalOutcome::Approved { remember: true })
+        );
+        assert!(queue.answer(&id, decision).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_timeout_and_fixed_approver() {
+        let queue = ApprovalQueue::new(Arc::new(UiApprover), Duration::from_millis(20));
+        assert_eq!(
+            queue.request("phone", "serial-2", "u3", None).await,
+            Ok(ApprovalOutcome::TimedOut)
+        );
+        assert!(queue.pending().is_empty());
+
+        queue.set_approver(Arc::new(FixedApprover(ApprovalDecision {
+            approve: false,
+            remember: false,
+        })));
+        assert_eq!(
+            queue.request("phone", "serial-2", "u3", None).await,
+            Ok(ApprovalOutcome::Denied)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_dropped_request_leaves_queue() {
+        let queue = ApprovalQueue::new(Arc::new(UiApprover), Duration::from_secs(5));
+        // 等待方断线，future 在审批前被丢弃
+        let waiting = queue.request("phone", "serial-3", "u4", None);
+        assert!(tokio::time::timeout(Duration::from_millis(20), waiting)
+            .await
+            .is_err());
+        assert!(queue.pending().is_empty());
+
+        queue.set_timeout(Duration::from_millis(20));
+        assert_eq!(
+            queue.request("phone", "serial-3", "u5", None).await,
+            Ok(ApprovalOutcome::TimedOut)
+        );
+    }
+}
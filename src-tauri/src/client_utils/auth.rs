@@ -1,47 +1,118 @@
+use super::approval::{ApprovalError, ApprovalOutcome, APPROVALS};
+use super::credentials::{CREDENTIALS, CREDENTIAL_LIFETIME_SECS};
 use super::current_user::CurInfo;
-use super::dialog::show_confirmation_dialog;
-use super::user_manager::UserType;
-use crate::client_utils::user_manager::{add_device, get_user_by_serial};
-use crate::config::{CONFIG, CURRENT_USERS_INFO, JWT_KEY, THIS_TIME};
+use super::device_key::{prove, DeviceKeyError};
+use super::lockout::{FailureOutcome, LOCKOUT};
+use super::password::{
+    self, generate_connection_password, on_pairing_success, rotates_on_lockout, verify_password,
+};
+use super::permissions::{profile_for, PermissionProfile, Quality, ALL_PERMISSIONS};
+use super::policy::{evaluate_device, evaluate_device_among, Verdict};
+use super::tokens::{TOKEN_LIFETIMES, TOKEN_REGISTRY};
+use super::unattended;
+use super::user_manager::{UserType, USER_LIST};
+use crate::audit;
+use crate::client_utils::user_manager::{
+    add_device, bind_device_key, get_user_by_serial, set_user_type,
+};
+use crate::config::{CURRENT_USERS_INFO, JWT_KEY, THIS_TIME, UUID};
+use crate::events::{emit, ServerEvent};
+use crate::protocol::{Command, ErrorReply, ResumeReq};
 use actix_web::web;
 use chrono;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::sync::Mutex;
+use thiserror::Error;
 
-#[derive(Debug, Serialize, Deserialize)]
+pub use crate::protocol::AuthRequest;
+
+pub use super::permissions::Permission;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
-    device_serial: String,
+    pub device_serial: String,
+    /// 手机端在中转服务器上的 uuid，即消息的 from
+    pub uuid: String,
+    /// 签发时本机的 uuid，换了中转身份后旧 token 失效
+    pub aud: String,
+    pub role: UserType,
+    pub perms: Vec<Permission>,
+    /// 画质上限
+    #[serde(default)]
+    pub max_quality: Quality,
+    /// token 编号，用于撤销
+    pub jti: String,
+    pub iat: usize,
     this_time: String,
-    exp: usize, // 过期时间
+    pub exp: usize, // 过期时间
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct AuthRequest {
-    pub device_name: String,
-    pub device_serial: String,
-    pub password: String,
-    pub uuid: String,
+impl Claims {
+    pub fn has(&self, perm: Permission) -> bool {
+        self.perms.contains(&perm)
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("JWT验证失败: {0}")]
+    InvalidToken(String),
+
+    #[error("JWT已被撤销")]
+    Revoked,
+
+    #[error("JWT与发送方不符")]
+    UuidMismatch,
+
+    #[error("JWT与设备序列号不符")]
+    SerialMismatch,
+
+    #[error("缺少{0:?}权限")]
+    Forbidden(Permission),
+}
+
+impl AuthError {
+    pub fn to_reply(&self, cmd: &str) -> ErrorReply {
+        let code = match self {
+            AuthError::Forbidden(_) => 403,
+            _ => 401,
+        };
+        ErrorReply::new(code, self.to_string(), Some(cmd))
+    }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Serialize)]
 pub struct AuthResponse {
     pub status: String,
     pub body: String,
+    /// 首次配对时签发的长期设备凭据，主机重启后用 resume 命令免审批恢复
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
 }
 
-lazy_static! {
-    /// 正在等待用户确认的 device_serial 集合
-    static ref CONFIRMING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+/// 凭据和 jwt（成功时的 body）不打印到日志里
+impl std::fmt::Debug for AuthResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let body = if self.status == "200" {
+            "***"
+        } else {
+            &self.body
+        };
+        f.debug_struct("AuthResponse")
+            .field("status", &self.status)
+            .field("body", &body)
+            .field("credential", &self.credential.as_ref().map(|_| "***"))
+            .finish()
+    }
 }
+
 /// websocket连接,处理逻辑: 1.判断服务端是否空闲
-///                        2.根据用户类别处理
+///                        2.连接策略命中拒绝规则时直接拒绝，命中允许规则时按规则决定是否免确认、是否只读
+///                        3.根据用户类别处理
 ///                             （1）黑名单：直接拒绝
 ///                             （2）信任：返回jwt，不验证口令，更新CURRENT——USER
-///                             （3）普通：口令正确，并且ui确认，则返回jwt，更新CURRENT——USER
-///                             （4）新用户：口令正确，并且ui确认，则返回jwt，更新CURRENT——USER，添加新用户信息
+///                             （3）普通：口令正确，并且审批通过，则返回jwt，更新CURRENT——USER
+///                             （4）新用户：口令正确，并且审批通过，则返回jwt，更新CURRENT——USER，添加新用户信息
 pub async fn authenticate(info: web::Json<AuthRequest>) -> AuthResponse {
     {
         let cur_users = CURRENT_USERS_INFO.lock().unwrap();
@@ -54,180 +125,469 @@ pub async fn authenticate(info: web::Json<AuthRequest>) -> AuthResponse {
             return AuthResponse {
                 status: "403".to_owned(),
                 body: "连接被拒绝".to_owned(),
+                credential: None,
             };
         }
     }
+    // 附带公钥时校验对 challenge 的签名
+    let proven_key = match prove(&info) {
+        Ok(key) => key,
+        Err(e) => {
+            println!("[AUTH_INFO]{:?}设备密钥校验失败: {}", info.device_serial, e);
+            audit::record(
+                "auth_failure",
+                audit::Outcome::Failure,
+                Some(&info.device_serial),
+                Some(&info.uuid),
+                &e.to_string(),
+            );
+            return AuthResponse {
+                status: "401".to_owned(),
+                body: e.to_string(),
+                credential: None,
+            };
+        }
+    };
     //let users = USER_LIST.lock().unwrap();
     let this_user = get_user_by_serial(&info.device_serial).await;
+    // 已绑定公钥的序列号只认这把公钥，防止冒用序列号
+    let key_verified = match this_user.as_ref().and_then(|user| user.public_key.as_ref()) {
+        Some(bound) if proven_key.as_ref() != Some(bound) => {
+            println!(
+                "[AUTH_INFO]{:?}未能证明持有已绑定的设备密钥",
+                info.device_serial
+            );
+            audit::record(
+                "auth_failure",
+                audit::Outcome::Failure,
+                Some(&info.device_serial),
+                Some(&info.uuid),
+                &DeviceKeyError::KeyMismatch.to_string(),
+            );
+            return AuthResponse {
+                status: "401".to_owned(),
+                body: DeviceKeyError::KeyMismatch.to_string(),
+                credential: None,
+            };
+        }
+        Some(_) => true,
+        None => false,
+    };
+    // 连接策略在默认流程和审批之前判定
+    let (require_approval, view_only) =
+        match evaluate_device(&info.device_serial, this_user.as_ref()) {
+            Verdict::Deny { rule, reason } => {
+                audit::record(
+                    "policy_deny",
+                    audit::Outcome::Denied,
+                    Some(&info.device_serial),
+                    Some(&info.uuid),
+                    &rule,
+                );
+                return AuthResponse {
+                    status: "403".to_owned(),
+                    body: reason,
+                    credential: None,
+                };
+            }
+            Verdict::Allow {
+                require_approval,
+                view_only,
+                ..
+            } => (require_approval, view_only),
+            Verdict::NoMatch => (None, false),
+        };
+    let trusted = key_verified
+        && this_user
+            .as_ref()
+            .is_some_and(|user| user.user_type == UserType::Trusted);
 
     match this_user {
         // 黑名单用户直接拒绝
         Some(user) if user.user_type == UserType::Blacklist => {
+            audit::record(
+                "blacklist_deny",
+                audit::Outcome::Denied,
+                Some(&info.device_serial),
+                Some(&info.uuid),
+                &info.device_name,
+            );
             //HttpResponse::Forbidden().body("连接被拒绝")
             AuthResponse {
                 status: "403".to_owned(),
                 body: "连接被拒绝".to_owned(),
+                credential: None,
             }
         }
-        // 信任用户直接返回jwt
-        Some(user) if user.user_type == UserType::Trusted => {
-            //update_cur_user(&info, UserType::Trusted);
-            let userinfo = CurInfo {
-                device_name: info.device_name.clone(),
-                device_id: info.device_serial.clone(),
-                user_type: UserType::Trusted,
-                uuid: info.uuid.clone(),
-            };
-            CURRENT_USERS_INFO
-                .lock()
-                .unwrap()
-                .add_new_cur_user(&userinfo);
-            let token = generate_jwt(&info.device_serial);
-            //HttpResponse::Ok().json(token)
-            AuthResponse {
-                status: "200".to_owned(),
-                body: token,
-            }
+        // 信任用户证明持有设备密钥后直接返回jwt，早于凭据功能信任的设备在这里补发凭据。
+        // 还没有绑定公钥的信任设备需要重新输入口令并审批，审批通过时绑定
+        // 策略要求确认时信任设备也要走审批
+        Some(_) if trusted && require_approval != Some(true) => {
+            let issue = !CREDENTIALS.lock().unwrap().has_active(&info.device_serial);
+            admit(&info, UserType::Trusted, issue, view_only)
         }
-        //普通用户，验证成功则返回jwt
-        Some(user) if user.user_type == UserType::Normal => {
-            let stored_pw = CONFIG.lock().unwrap().connection_password.clone();
-            if stored_pw == info.password {
-                // 添加至连接队列
-
-                {
-                    let mut confirming = CONFIRMING.lock().unwrap();
-                    if !confirming.insert(info.device_serial.clone()) {
-                        // 已经有一个对话框在等此 serial 的确认
-                        // 直接返回“pending”，不弹新框
-                        return AuthResponse {
-                            status: "202".into(),
-                            body: "请求已在处理，请稍后".into(),
-                        };
-                    }
-                }
-                let msg = format!(
-                    "是否允许来自{:?}({:?})的连接？",
-                    info.device_name.clone(),
-                    info.device_serial.clone()
-                );
-                let approved: bool =
-                    tokio::task::spawn_blocking(move || show_confirmation_dialog("连接请求", &msg))
-                        .await
-                        .expect("blocking task panicked");
-                // 从确认队列去除
-                {
-                    let mut confirming = CONFIRMING.lock().unwrap();
-                    confirming.remove(&info.device_serial);
+        //普通用户或新用户，口令正确并审批通过则返回jwt；信任设备到这里时只需要审批
+        known => {
+            let kind = if trusted {
+                None
+            } else {
+                match check_password(&info).await {
+                    Ok(kind) => Some(kind),
+                    Err(res) => return res,
                 }
-                if approved {
-                    //update_cur_user(&info, UserType::Normal);
-                    let userinfo = CurInfo {
-                        device_name: info.device_name.clone(),
-                        device_id: info.device_serial.clone(),
-                        user_type: UserType::Normal,
-                        uuid: info.uuid.clone(),
-                    };
-                    CURRENT_USERS_INFO
-                        .lock()
-                        .unwrap()
-                        .add_new_cur_user(&userinfo);
-                    let token = generate_jwt(&info.device_serial);
-                    //HttpResponse::Ok().json(token)
-                    AuthResponse {
-                        status: "200".to_owned(),
-                        body: token,
-                    }
+            };
+            // 命中的策略决定是否免确认，没有命中时只有无人值守的固定口令免确认
+            let auto_approve = match require_approval {
+                Some(required) => !required,
+                None => kind == Some(PasswordKind::Unattended),
+            };
+            let known_type = known.map(|user| user.user_type);
+            let outcome = if auto_approve {
+                if kind == Some(PasswordKind::Unattended) {
+                    // 无人值守：固定口令正确即放行，不需要本机确认
+                    println!(
+                        "[AUTH_INFO]{:?}使用固定口令连接，免确认",
+                        info.device_serial
+                    );
+                    audit::record(
+                        "unattended_login",
+                        audit::Outcome::Success,
+                        Some(&info.device_serial),
+                        Some(&info.uuid),
+                        &info.device_name,
+                    );
                 } else {
-                    //HttpResponse::Unauthorized().body("连接被拒绝")
-                    AuthResponse {
-                        status: "403".to_owned(),
-                        body: "连接被拒绝".to_owned(),
-                    }
+                    println!("[AUTH_INFO]{:?}按连接策略免确认", info.device_serial);
                 }
+                Ok(ApprovalOutcome::Approved { remember: false })
             } else {
-                //HttpResponse::Unauthorized().body("连接口令错误")
-                AuthResponse {
-                    status: "403".to_owned(),
-                    body: "连接口令错误".to_owned(),
-                }
-            }
-        }
-        //新用户，验证成功则记录信息并返回jwt
-        _ => {
-            let stored_pw = CONFIG.lock().unwrap().connection_password.clone();
-            println!("here");
-            if stored_pw == info.password {
-                {
-                    let mut confirming = CONFIRMING.lock().unwrap();
-                    if !confirming.insert(info.device_serial.clone()) {
-                        // 已经有一个对话框在等此 serial 的确认
-                        // 直接返回“pending”，不弹新框
-                        return AuthResponse {
-                            status: "202".into(),
-                            body: "请求已在处理，请稍后".into(),
-                        };
+                APPROVALS
+                    .request(
+                        &info.device_name,
+                        &info.device_serial,
+                        &info.uuid,
+                        known_type.clone(),
+                    )
+                    .await
+            };
+            match outcome {
+                Ok(ApprovalOutcome::Approved { remember }) => {
+                    if known_type.is_none() {
+                        add_device(&info.device_name, &info.device_serial).await;
                     }
-                }
-                let msg = format!(
-                    "是否允许来自{:?}({:?})的连接？",
-                    info.device_name.clone(),
-                    info.device_serial.clone()
-                );
-
-                let approved: bool =
-                    tokio::task::spawn_blocking(move || show_confirmation_dialog("连接请求", &msg))
-                        .await
-                        .expect("blocking task panicked");
-                // 从确认队列去除
-                {
-                    let mut confirming = CONFIRMING.lock().unwrap();
-                    confirming.remove(&info.device_serial);
-                }
-                if approved {
-                    //update_cur_user(&info, UserType::Normal);
-                    let userinfo = CurInfo {
-                        device_name: info.device_name.clone(),
-                        device_id: info.device_serial.clone(),
-                        user_type: UserType::Normal,
-                        uuid: info.uuid.clone(),
-                    };
-                    CURRENT_USERS_INFO
-                        .lock()
-                        .unwrap()
-                        .add_new_cur_user(&userinfo);
-                    let token = generate_jwt(&info.device_serial);
-                    add_device(&info.device_name, &info.device_serial).await;
-                    println!("[AUTH_INFO]生成jwt{:?}", token);
-                    //HttpResponse::Ok().json(token)
-                    AuthResponse {
-                        status: "200".to_owned(),
-                        body: token,
+                    if let (Some(key), false) = (&proven_key, key_verified) {
+                        bind_device_key(&info.device_serial, key);
                     }
-                } else {
+                    let user_type = if remember {
+                        // 记住决定：以后这台设备直接连接
+                        // 保存失败时已经通知界面，本次连接照常进行
+                        let _ = set_user_type(&info.device_serial, UserType::Trusted);
+                        UserType::Trusted
+                    } else if known_type == Some(UserType::Trusted) {
+                        // 补绑公钥的信任设备保持原类别
+                        UserType::Trusted
+                    } else {
+                        UserType::Normal
+                    };
+                    // 首次配对：签发长期凭据
+                    admit(&info, user_type, true, view_only)
+                }
+                Ok(denied) => {
+                    audit::record(
+                        "approval_denied",
+                        audit::Outcome::Denied,
+                        Some(&info.device_serial),
+                        Some(&info.uuid),
+                        &format!("{:?}", denied),
+                    );
                     //HttpResponse::Unauthorized().body("连接被拒绝")
                     AuthResponse {
                         status: "403".to_owned(),
                         body: "连接被拒绝".to_owned(),
+                        credential: None,
                     }
                 }
-            } else {
-                //HttpResponse::Unauthorized().body("连接口令错误")
-                AuthResponse {
-                    status: "403".to_owned(),
-                    body: "连接口令错误".to_owned(),
-                }
+                // 已经有一个请求在等待审批，直接返回“pending”
+                Err(ApprovalError::AlreadyPending) => AuthResponse {
+                    status: "202".into(),
+                    body: "请求已在处理，请稍后".into(),
+                    credential: None,
+                },
+                Err(e) => AuthResponse {
+                    status: "429".into(),
+                    body: e.to_string(),
+                    credential: None,
+                },
             }
         }
     }
 }
 
-fn generate_jwt(device_serial: &str) -> String {
+/// 记录为当前连接用户并签发 jwt，`issue_credential` 时同时签发长期设备凭据，
+/// `view_only` 时 token 不带控制权限。
+/// 等待审批期间可能有其他设备连上，连接上限和策略的会话上限在加入时持锁再检查一次
+fn admit(
+    info: &AuthRequest,
+    user_type: UserType,
+    issue_credential: bool,
+    view_only: bool,
+) -> AuthResponse {
+    let userinfo = CurInfo {
+        device_name: info.device_name.clone(),
+        device_id: info.device_serial.clone(),
+        user_type: user_type.clone(),
+        uuid: info.uuid.clone(),
+    };
+    let user = USER_LIST.lock().unwrap().get(&info.device_serial).cloned();
+    let denied = {
+        let mut cur_users = CURRENT_USERS_INFO.lock().unwrap();
+        let online: Vec<String> = cur_users
+            .usersinfo
+            .iter()
+            .map(|cur| cur.device_id.clone())
+            .collect();
+        match evaluate_device_among(&info.device_serial, user.as_ref(), &online) {
+            Verdict::Deny { rule, reason } => Some((Some(rule), reason)),
+            _ if !cur_users.add_new_cur_user(&userinfo) => Some((None, "连接被拒绝".to_owned())),
+            _ => None,
+        }
+    };
+    if let Some((rule, reason)) = denied {
+        println!(
+            "[AUTH_INFO]{:?}加入时被拒绝：{}",
+            info.device_serial, reason
+        );
+        if let Some(rule) = rule {
+            audit::record(
+                "policy_deny",
+                audit::Outcome::Denied,
+                Some(&info.device_serial),
+                Some(&info.uuid),
+                &rule,
+            );
+        }
+        return AuthResponse {
+            status: "403".to_owned(),
+            body: reason,
+            credential: None,
+        };
+    }
+    audit::record(
+        "session_start",
+        audit::Outcome::Success,
+        Some(&info.device_serial),
+        Some(&info.uuid),
+        &format!("{:?}", user_type),
+    );
+    let mut profile = profile_for(user.as_ref()).restrict(&role_permissions(&user_type));
+    if view_only {
+        profile = profile.view_only();
+    }
+    let token = generate_jwt_with_profile(&info.device_serial, &info.uuid, user_type, profile);
+    println!("[AUTH_INFO]为{:?}生成jwt", info.device_serial);
+    let credential = issue_credential.then(|| {
+        CREDENTIALS.lock().unwrap().issue(
+            &info.device_serial,
+            &info.device_name,
+            CREDENTIAL_LIFETIME_SECS,
+        )
+    });
+    //HttpResponse::Ok().json(token)
+    AuthResponse {
+        status: "200".to_owned(),
+        body: token,
+        credential,
+    }
+}
+
+/// 主机重启后用长期凭据恢复会话：凭据有效且设备仍是信任用户时直接签发 jwt，不需要口令和审批。
+/// 非信任设备的凭据无法用于恢复，只能重新走 auth
+pub async fn resume(from: &str, req: &ResumeReq) -> AuthResponse {
+    let rejected = |body: &str| AuthResponse {
+        status: "403".to_owned(),
+        body: body.to_owned(),
+        credential: None,
+    };
+    if let Err(e) = CREDENTIALS
+        .lock()
+        .unwrap()
+        .verify(&req.credential, &req.device_serial)
+    {
+        println!("[AUTH_INFO]{:?}恢复会话失败: {}", req.device_serial, e);
+        return AuthResponse {
+            status: "401".to_owned(),
+            body: e.to_string(),
+            credential: None,
+        };
+    }
+    let user = match get_user_by_serial(&req.device_serial).await {
+        Some(user) if user.user_type == UserType::Trusted => user,
+        _ => return rejected("设备不是信任用户，请重新连接"),
+    };
+    let info = AuthRequest {
+        device_name: req.device_name.clone(),
+        device_serial: req.device_serial.clone(),
+        uuid: from.to_string(),
+        password: String::new(),
+        public_key: user.public_key.clone(),
+        signature: req.signature.clone(),
+    };
+    // 绑定了设备密钥的设备，凭据之外还要对 challenge 签名，凭据泄露也不能冒用
+    if let Err(e) = prove(&info) {
+        println!(
+            "[AUTH_INFO]{:?}恢复会话时设备密钥校验失败: {}",
+            req.device_serial, e
+        );
+        audit::record(
+            "auth_failure",
+            audit::Outcome::Failure,
+            Some(&req.device_serial),
+            Some(from),
+            &e.to_string(),
+        );
+        return AuthResponse {
+            status: "401".to_owned(),
+            body: e.to_string(),
+            credential: None,
+        };
+    }
+    let view_only = match evaluate_device(&req.device_serial, Some(&user)) {
+        Verdict::Deny { reason, .. } => return rejected(&reason),
+        Verdict::Allow {
+            require_approval: Some(true),
+            ..
+        } => return rejected("连接策略要求本机确认，请重新连接"),
+        Verdict::Allow { view_only, .. } => view_only,
+        Verdict::NoMatch => false,
+    };
+    if !CURRENT_USERS_INFO.lock().unwrap().is_avail() {
+        return rejected("连接被拒绝");
+    }
+    println!("[AUTH_INFO]{:?}使用设备凭据恢复会话", req.device_serial);
+    admit(&info, UserType::Trusted, false, view_only)
+}
+
+/// 通过校验的口令种类
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PasswordKind {
+    /// 临时连接口令，之后还需要审批
+    Connection,
+    /// 无人值守模式的固定口令
+    Unattended,
+}
+
+/// 校验连接口令，同时计入防暴力破解：锁定中直接拒绝，失败累计过多时更换口令
+async fn check_password(info: &AuthRequest) -> Result<PasswordKind, AuthResponse> {
+    let (serial, uuid) = (Some(info.device_serial.as_str()), Some(info.uuid.as_str()));
+    if let Err(remaining) = LOCKOUT.lock().unwrap().check(serial, uuid) {
+        println!(
+            "[AUTH_INFO]{:?}处于锁定中，剩余{:?}",
+            info.device_serial, remaining
+        );
+        return Err(AuthResponse {
+            status: "429".to_owned(),
+            body: format!("尝试次数过多，请{}秒后再试", remaining.as_secs() + 1),
+            credential: None,
+        });
+    }
+    if verify_password(&info.password).await {
+        LOCKOUT.lock().unwrap().record_success(serial, uuid);
+        // 一次性口令在这里作废
+        on_pairing_success().await;
+        return Ok(PasswordKind::Connection);
+    }
+    if unattended::verify_password(&info.password).await {
+        LOCKOUT.lock().unwrap().record_success(serial, uuid);
+        return Ok(PasswordKind::Unattended);
+    }
+    // 猜错的口令也可能是冲着固定口令或 TOTP 口令来的
+    unattended::record_failure(&info.uuid);
+    password::record_failure(&info.uuid);
+    record_password_failure(serial, &info.uuid, "口令错误").await;
+    //HttpResponse::Unauthorized().body("连接口令错误")
+    Err(AuthResponse {
+        status: "403".to_owned(),
+        body: "连接口令错误".to_owned(),
+        credential: None,
+    })
+}
+
+/// 记录一次口令猜测失败：计入锁定、写审计、通知界面，累计失败过多时更换连接口令（TOTP 模式除外）。
+/// 口令校验失败和端到端握手秘密不对都走这里，`what` 写进审计说明
+pub async fn record_password_failure(
+    serial: Option<&str>,
+    uuid: &str,
+    what: &str,
+) -> FailureOutcome {
+    let outcome = LOCKOUT.lock().unwrap().record_failure(serial, Some(uuid));
+    let rotate = outcome.rotate && rotates_on_lockout();
+    println!(
+        "[AUTH_INFO]来自{:?}({:?})的{}，第{}次",
+        serial.unwrap_or_default(),
+        uuid,
+        what,
+        outcome.failures
+    );
+    audit::record(
+        "auth_failure",
+        audit::Outcome::Failure,
+        serial,
+        Some(uuid),
+        &format!("第{}次{}", outcome.failures, what),
+    );
+    emit(ServerEvent::AuthFailure {
+        device_serial: serial.unwrap_or_default().to_string(),
+        uuid: uuid.to_string(),
+        failures: outcome.failures,
+        lockout_secs: outcome.lockout.map(|d| d.as_secs()).unwrap_or(0),
+        password_rotated: rotate,
+    });
+    if rotate {
+        println!("[AUTH_INFO]口令错误次数过多，更换连接口令");
+        generate_connection_password().await;
+    }
+    outcome
+}
+
+/// 用户类别允许的权限上限，具体权限再由权限配置决定
+fn role_permissions(role: &UserType) -> Vec<Permission> {
+    match role {
+        UserType::Trusted | UserType::Normal => ALL_PERMISSIONS.to_vec(),
+        UserType::Blacklist => Vec::new(),
+    }
+}
+
+fn generate_jwt(device_serial: &str, uuid: &str, role: UserType) -> String {
+    let profile = PermissionProfile::default().restrict(&role_permissions(&role));
+    generate_jwt_with_profile(device_serial, uuid, role, profile)
+}
+
+/// 签发 token，有效期按用户类别决定，并登记 jti 以便撤销
+fn generate_jwt_with_profile(
+    device_serial: &str,
+    uuid: &str,
+    role: UserType,
+    profile: PermissionProfile,
+) -> String {
+    let now = chrono::Utc::now().timestamp() as usize;
+    let lifetime = TOKEN_LIFETIMES.lock().unwrap().for_role(&role) as usize;
     let claims = Claims {
         device_serial: device_serial.to_string(),
+        uuid: uuid.to_string(),
+        aud: UUID.lock().unwrap().clone(),
+        perms: profile.perms,
+        max_quality: profile.max_quality,
+        role,
+        jti: uuid::Uuid::new_v4().to_string(),
+        iat: now,
         this_time: THIS_TIME.lock().unwrap().clone(),
-        exp: chrono::Utc::now().timestamp() as usize + 3600, // 1 小时有效
+        exp: now + lifetime,
     };
+    {
+        let mut registry = TOKEN_REGISTRY.lock().unwrap();
+        registry.prune(now);
+        registry.record(&claims.jti, device_serial, uuid, &claims.perms, claims.exp);
+    }
     encode(
         &Header::default(),
         &claims,
@@ -236,16 +596,378 @@ fn generate_jwt(device_serial: &str) -> String {
     .unwrap()
 }
 
-pub fn validate_jwt(token: &str) -> bool {
-    match decode::<Claims>(
+/// 校验签名、有效期、本次启动标识和签发对象（本机 uuid）
+pub fn verify_jwt(token: &str) -> Result<Claims, AuthError> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[UUID.lock().unwrap().as_str()]);
+    let decoded = decode::<Claims>(
         token,
         &DecodingKey::from_secret(JWT_KEY.lock().unwrap().as_ref()),
-        &Validation::default(),
-    ) {
-        Ok(decoded) => {
-            let this_time = THIS_TIME.lock().unwrap().to_string();
-            decoded.claims.this_time == this_time
+        &validation,
+    )
+    .map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+    if decoded.claims.this_time != *THIS_TIME.lock().unwrap() {
+        return Err(AuthError::InvalidToken("不是本次启动签发的".to_string()));
+    }
+    if !TOKEN_REGISTRY
+        .lock()
+        .unwrap()
+        .is_active(&decoded.claims.jti)
+    {
+        return Err(AuthError::Revoked);
+    }
+    Ok(decoded.claims)
+}
+
+/// 用仍然有效的 token 换一个新的，旧 token 随即作废。
+/// 设备必须仍在连接中，且没有被拉黑；新 token 按当前的用户类别签发
+pub async fn refresh_jwt(claims: &Claims) -> AuthResponse {
+    if !CURRENT_USERS_INFO
+        .lock()
+        .unwrap()
+        .lookup_by_serial(&claims.device_serial)
+    {
+        return AuthResponse {
+            status: "403".to_owned(),
+            body: "设备未连接".to_owned(),
+            credential: None,
+        };
+    }
+    let user = get_user_by_serial(&claims.device_serial).await;
+    let role = match &user {
+        Some(user) => user.user_type.clone(),
+        None => claims.role.clone(),
+    };
+    TOKEN_REGISTRY.lock().unwrap().revoke(&claims.jti);
+    if role == UserType::Blacklist {
+        return AuthResponse {
+            status: "403".to_owned(),
+            body: "连接被拒绝".to_owned(),
+            credential: None,
+        };
+    }
+    // 连接后策略可能已经变了，比如过了允许的时间段，按当前策略重新判定
+    let view_only = match evaluate_device(&claims.device_serial, user.as_ref()) {
+        Verdict::Deny { rule, reason } => {
+            audit::record(
+                "policy_deny",
+                audit::Outcome::Denied,
+                Some(&claims.device_serial),
+                Some(&claims.uuid),
+                &rule,
+            );
+            return AuthResponse {
+                status: "403".to_owned(),
+                body: reason,
+                credential: None,
+            };
         }
-        Err(_) => false,
+        Verdict::Allow { view_only, .. } => view_only,
+        Verdict::NoMatch => false,
+    };
+    // 按当前的权限配置签发，但不能超出原 token 已有的权限
+    let mut profile = profile_for(user.as_ref())
+        .restrict(&role_permissions(&role))
+        .restrict(&claims.perms)
+        .cap_quality(claims.max_quality);
+    if view_only {
+        profile = profile.view_only();
+    }
+    let token = generate_jwt_with_profile(&claims.device_serial, &claims.uuid, role, profile);
+    println!("[AUTH_INFO]为{:?}刷新jwt", claims.device_serial);
+    AuthResponse {
+        status: "200".to_owned(),
+        body: token,
+        credential: None,
+    }
+}
+
+pub fn validate_jwt(token: &str) -> bool {
+    verify_jwt(token).is_ok()
+}
+
+/// 每条命令处理前的统一鉴权：token 有效，且 uuid、序列号与权限都和请求一致。
+/// auth、resume 和 challenge 命令不带 token，返回 None
+pub fn authorize(from: &str, cmd: &Command) -> Result<Option<Claims>, AuthError> {
+    let (jwt, uuid, serial, perm) = match cmd {
+        Command::Auth(_) | Command::Resume(_) | Command::Challenge(_) => return Ok(None),
+        Command::Refresh(req) => (&req.jwt, None, None, Permission::View),
+        Command::Offer(req) => (&req.jwt, Some(&req.client_uuid), None, Permission::View),
+        Command::Candidate(req) => (&req.jwt, Some(&req.client_uuid), None, Permission::View),
+        Command::Disconnect(req) => (&req.jwt, None, Some(&req.device_serial), Permission::View),
+        Command::Control(req) | Command::RevokeCtrl(req) | Command::CloseRtc(req) => (
+            &req.jwt,
+            Some(&req.uuid),
+            Some(&req.device_serial),
+            Permission::Control,
+        ),
+    };
+    let claims = verify_jwt(jwt)?;
+    if claims.uuid != from || uuid.is_some_and(|uuid| *uuid != claims.uuid) {
+        return Err(AuthError::UuidMismatch);
+    }
+    if serial.is_some_and(|serial| *serial != claims.device_serial) {
+        return Err(AuthError::SerialMismatch);
+    }
+    if !claims.has(perm) {
+        return Err(AuthError::Forbidden(perm));
+    }
+    Ok(Some(claims))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_utils::lockout::{LockoutPolicy, LockoutTracker};
+    use crate::protocol::CrtlReq;
+
+    fn control(jwt: &str, uuid: &str, serial: &str) -> Command {
+        Command::Control(CrtlReq {
+            jwt: jwt.to_string(),
+            uuid: uuid.to_string(),
+            device_serial: serial.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_authorize_binds_uuid_and_serial() {
+        let token = generate_jwt("serial-a", "phone-a", UserType::Normal);
+        let claims = authorize("phone-a", &control(&token, "phone-a", "serial-a"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(claims.role, UserType::Normal);
+
+        // 其他设备拿着 A 的 token，或者用自己的 token 冒充 A
+        assert_eq!(
+            authorize("phone-b", &control(&token, "phone-b", "serial-a")).unwrap_err(),
+            AuthError::UuidMismatch
+        );
+        assert_eq!(
+            authorize("phone-a", &control(&token, "phone-a", "serial-b")).unwrap_err(),
+            AuthError::SerialMismatch
+        );
+        assert!(matches!(
+            authorize("phone-a", &control("garbage", "phone-a", "serial-a")),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_refresh_revokes_old_token() {
+        let token = generate_jwt("serial-r", "phone-r", UserType::Normal);
+        let claims = verify_jwt(&token).unwrap();
+        assert_eq!(claims.exp - claims.iat, 3600);
+
+        // 未连接的设备不能刷新
+        assert_eq!(refresh_jwt(&claims).await.status, "403");
+        let token = generate_jwt("serial-r", "phone-r", UserType::Normal);
+        let claims = verify_jwt(&token).unwrap();
+        CURRENT_USERS_INFO
+            .lock()
+            .unwrap()
+            .add_new_cur_user(&CurInfo {
+                device_name: "phone".to_string(),
+                device_id: "serial-r".to_string(),
+                user_type: UserType::Normal,
+                uuid: "phone-r".to_string(),
+            });
+        let res = refresh_jwt(&claims).await;
+        assert_eq!(res.status, "200");
+        assert_eq!(verify_jwt(&token).unwrap_err(), AuthError::Revoked);
+        let fresh = verify_jwt(&res.body).unwrap();
+        assert_ne!(fresh.jti, claims.jti);
+
+        crate::client_utils::tokens::revoke_device("serial-r");
+        assert_eq!(verify_jwt(&res.body).unwrap_err(), AuthError::Revoked);
+        CURRENT_USERS_INFO.lock().unwrap().delete_by_uuid("phone-r");
+    }
+
+    #[actix_rt::test]
+    async fn test_refresh_rechecks_policy() {
+        use crate::client_utils::policy::{get_rules, set_rules, PolicyRule};
+
+        CURRENT_USERS_INFO
+            .lock()
+            .unwrap()
+            .add_new_cur_user(&CurInfo {
+                device_name: "phone".to_string(),
+                device_id: "serial-rp".to_string(),
+                user_type: UserType::Normal,
+                uuid: "phone-rp".to_string(),
+            });
+        let saved = get_rules();
+        let rule: PolicyRule = serde_json::from_value(serde_json::json!(
+            { "id": "no-rp", "devices": ["serial-rp"], "action": "deny" }
+        ))
+        .unwrap();
+        let mut rules = saved.clone();
+        rules.insert(0, rule);
+        set_rules(rules).unwrap();
+
+        let token = generate_jwt("serial-rp", "phone-rp", UserType::Normal);
+        let res = refresh_jwt(&verify_jwt(&token).unwrap()).await;
+        set_rules(saved).unwrap();
+        assert_eq!(res.status, "403");
+        assert_eq!(verify_jwt(&token).unwrap_err(), AuthError::Revoked);
+        CURRENT_USERS_INFO
+            .lock()
+            .unwrap()
+            .delete_by_uuid("phone-rp");
+    }
+
+    /// 测试期间换上使用假时钟的 LOCKOUT，结束时（包括断言失败）换回原来的
+    struct LockoutGuard(Option<LockoutTracker>);
+
+    impl Drop for LockoutGuard {
+        fn drop(&mut self) {
+            if let Some(saved) = self.0.take() {
+                *LOCKOUT.lock().unwrap_or_else(|e| e.into_inner()) = saved;
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_authenticate_locks_out_with_fake_clock() {
+        use crate::client_utils::lockout::FakeClock;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let clock = Arc::new(FakeClock::new());
+        let local = LockoutTracker::new(LockoutPolicy::default(), clock.clone());
+        let _guard = LockoutGuard(Some(std::mem::replace(
+            &mut *LOCKOUT.lock().unwrap(),
+            local,
+        )));
+        let request = |password: &str| AuthRequest {
+            device_name: "attacker".to_string(),
+            device_serial: "serial-bf".to_string(),
+            password: password.to_string(),
+            uuid: "phone-bf".to_string(),
+            public_key: None,
+            signature: None,
+        };
+
+        for _ in 0..3 {
+            let res = authenticate(web::Json(request("00000000"))).await;
+            assert_eq!(res.body, "连接口令错误");
+        }
+        // 锁定期内即使口令正确也直接拒绝，不会弹出确认框
+        let password = crate::client_utils::password::current_password();
+        assert_eq!(
+            authenticate(web::Json(request(&password))).await.status,
+            "429"
+        );
+
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(
+            authenticate(web::Json(request("00000000"))).await.status,
+            "403"
+        );
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(
+            authenticate(web::Json(request("00000000"))).await.status,
+            "429"
+        );
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(
+            authenticate(web::Json(request("00000000"))).await.status,
+            "403"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_resume_requires_bound_key_proof() {
+        use crate::client_utils::device_key::{signed_message, CHALLENGES};
+        use crate::client_utils::user_manager::UserInfo;
+        use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let key = SigningKey::from_bytes(&[9u8; 32]);
+        USER_LIST.lock().unwrap().insert(
+            "serial-rk".to_string(),
+            UserInfo {
+                device_name: "phone".to_string(),
+                device_id: "serial-rk".to_string(),
+                user_type: UserType::Trusted,
+                public_key: Some(BASE64.encode(key.verifying_key().as_bytes())),
+                fingerprint: None,
+                groups: Vec::new(),
+                profile: None,
+            },
+        );
+        let credential = CREDENTIALS
+            .lock()
+            .unwrap()
+            .issue("serial-rk", "phone", 3600);
+        let mut req = ResumeReq {
+            credential,
+            device_name: "phone".to_string(),
+            device_serial: "serial-rk".to_string(),
+            signature: None,
+        };
+        // 只有凭据不够
+        assert_eq!(resume("phone-rk", &req).await.status, "401");
+
+        let nonce = CHALLENGES.lock().unwrap().issue("phone-rk");
+        let signature = key.sign(&signed_message(&nonce, "serial-rk", "phone-rk"));
+        req.signature = Some(BASE64.encode(signature.to_bytes()));
+        let res = resume("phone-rk", &req).await;
+        assert_eq!(res.status, "200");
+
+        CURRENT_USERS_INFO
+            .lock()
+            .unwrap()
+            .delete_by_uuid("phone-rk");
+        USER_LIST.lock().unwrap().remove("serial-rk");
+        CREDENTIALS.lock().unwrap().revoke_device("serial-rk");
+    }
+
+    #[test]
+    fn test_auth_response_hides_credential() {
+        let res = AuthResponse {
+            status: "200".to_owned(),
+            body: "secret-jwt".to_owned(),
+            credential: Some("secret-credential".to_owned()),
+        };
+        let printed = format!("{:?}", res);
+        assert!(!printed.contains("secret-credential"));
+        assert!(!printed.contains("secret-jwt"));
+        // 失败时的 body 是错误原因，照常打印
+        let res = AuthResponse {
+            status: "403".to_owned(),
+            body: "连接被拒绝".to_owned(),
+            credential: None,
+        };
+        assert!(format!("{:?}", res).contains("连接被拒绝"));
+    }
+
+    #[test]
+    fn test_view_only_token_cannot_control() {
+        let info = AuthRequest {
+            device_name: "viewer".to_string(),
+            device_serial: "serial-v".to_string(),
+            password: String::new(),
+            uuid: "phone-v".to_string(),
+            public_key: None,
+            signature: None,
+        };
+        let res = admit(&info, UserType::Normal, false, true);
+        let claims = verify_jwt(&res.body).unwrap();
+        assert!(claims.has(Permission::View));
+        assert!(!claims.has(Permission::Clipboard));
+        assert_eq!(
+            authorize("phone-v", &control(&res.body, "phone-v", "serial-v")).unwrap_err(),
+            AuthError::Forbidden(Permission::Control)
+        );
+        CURRENT_USERS_INFO.lock().unwrap().delete_by_uuid("phone-v");
+    }
+
+    #[test]
+    fn test_blacklist_has_no_permissions() {
+        let token = generate_jwt("serial-c", "phone-c", UserType::Blacklist);
+        assert_eq!(
+            authorize("phone-c", &control(&token, "phone-c", "serial-c")).unwrap_err(),
+            AuthError::Forbidden(Permission::Control)
+        );
     }
 }
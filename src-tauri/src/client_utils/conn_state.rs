@@ -0,0 +1,83 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::sync::Mutex;
+
+use crate::events::{emit, ServerEvent};
+
+/// 与中转服务器的连接状态
+///
+/// Disconnected → Connecting → Registered，注册被拒绝进入 Rejected，连接失败进入 Failed，
+/// Failed 与 Registered 断线后回到 Connecting 重连
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting { attempt: u32 },
+    Registered { uuid: String },
+    Rejected { reason: String },
+    Failed { reason: String },
+}
+
+impl ConnectionState {
+    /// 是否允许从当前状态切换到 next
+    pub fn can_transition(&self, next: &ConnectionState) -> bool {
+        use ConnectionState::*;
+        match (self, next) {
+            (_, Disconnected) => true,
+            (Disconnected | Rejected { .. } | Failed { .. }, Connecting { .. }) => true,
+            (Connecting { .. }, Connecting { .. }) => true,
+            (Connecting { .. }, Registered { .. } | Rejected { .. } | Failed { .. }) => true,
+            (Registered { .. }, Connecting { .. } | Failed { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref CONNECTION_STATE: Mutex<ConnectionState> =
+        Mutex::new(ConnectionState::Disconnected);
+}
+
+/// 切换连接状态并通知前端，非法的切换只记录不生效
+pub fn set_state(next: ConnectionState) {
+    {
+        let mut state = CONNECTION_STATE.lock().unwrap();
+        if *state == next {
+            return;
+        }
+        if !state.can_transition(&next) {
+            println!("[CONN_STATE]忽略非法的状态切换：{:?} -> {:?}", *state, next);
+            return;
+        }
+        println!("[CONN_STATE]{:?} -> {:?}", *state, next);
+        *state = next.clone();
+    }
+    emit(ServerEvent::RelayState(next));
+}
+
+pub fn get_state() -> ConnectionState {
+    CONNECTION_STATE.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionState::*;
+
+    #[test]
+    fn test_transitions() {
+        let connecting = Connecting { attempt: 0 };
+        let registered = Registered {
+            uuid: "u".to_string(),
+        };
+        let rejected = Rejected {
+            reason: "full".to_string(),
+        };
+        assert!(Disconnected.can_transition(&connecting));
+        assert!(!Disconnected.can_transition(&registered));
+        assert!(connecting.can_transition(&registered));
+        assert!(connecting.can_transition(&rejected));
+        assert!(!rejected.can_transition(&registered));
+        assert!(registered.can_transition(&Connecting { attempt: 1 }));
+        assert!(rejected.can_transition(&Disconnected));
+    }
+}
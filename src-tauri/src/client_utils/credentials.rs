@@ -0,0 +1,394 @@
+//! 长期设备凭据
+//!
+//! 会话 JWT 的密钥每次启动都会重新生成，主机重启后所有 token 失效。
+//! 首次配对时另外签发一份长期凭据，用持久化的主机签名密钥签名，记录在 device_credentials.json。
+//! 主机重启后，仍为信任用户的设备可以用凭据发送 `resume` 免审批恢复会话；
+//! 每台设备的凭据可以单独撤销，拉黑或删除设备时自动撤销。
+//! 撤销和过期的记录在下次保存时清理，被清理的凭据同样校验不通过。
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use jsonwebtoken::crypto::sign;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+
+use crate::config::{get_credentials_path, get_host_key_path};
+use crate::storage::{self, StorageError};
+
+/// 设备凭据的默认有效期：90 天
+pub const CREDENTIAL_LIFETIME_SECS: u64 = 90 * 24 * 3600;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CredentialClaims {
+    pub device_serial: String,
+    /// 凭据编号
+    pub jti: String,
+    /// 签发主机的标识，和中转服务器分配的 uuid 无关，重启不变
+    pub aud: String,
+    pub iat: usize,
+    pub exp: usize,
+}
+
+/// 一份已签发凭据的记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CredentialRecord {
+    pub id: String,
+    pub device_serial: String,
+    pub device_name: String,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub revoked: bool,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CredentialError {
+    #[error("设备凭据无效: {0}")]
+    Invalid(String),
+
+    #[error("设备凭据已撤销")]
+    Revoked,
+
+    #[error("设备凭据与设备序列号不符")]
+    SerialMismatch,
+}
+
+/// 持久化的主机签名密钥
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HostKey {
+    host_id: String,
+    /// base64 编码的 32 字节 HMAC 密钥
+    key: String,
+}
+
+impl HostKey {
+    fn generate() -> Self {
+        let mut key = [0u8; 32];
+        rand::rng().fill(&mut key);
+        Self {
+            host_id: uuid::Uuid::new_v4().to_string(),
+            key: BASE64.encode(key),
+        }
+    }
+}
+
+pub struct CredentialStore {
+    host_key: HostKey,
+    key: Vec<u8>,
+    records: HashMap<String, CredentialRecord>,
+    /// (主机密钥文件, 凭据记录文件)，为 None 时只在内存中
+    paths: Option<(PathBuf, PathBuf)>,
+}
+
+impl CredentialStore {
+    /// 读取主机密钥和凭据记录，密钥不存在时生成并保存
+    pub fn load(key_path: PathBuf, records_path: PathBuf) -> Self {
+        let host_key = read_json::<HostKey>(&key_path)
+            .filter(|k| BASE64.decode(&k.key).is_ok_and(|key| key.len() == 32))
+            .unwrap_or_else(|| {
+                println!("[CREDENTIAL]生成新的主机签名密钥");
+                let host_key = HostKey::generate();
+                if let Ok(json) = serde_json::to_string_pretty(&host_key) {
+                    write_reported(&key_path, json.as_bytes(), true);
+                }
+                host_key
+            });
+        let records = read_json(&records_path).unwrap_or_default();
+        let mut store = Self::with_key(host_key, records);
+        store.paths = Some((key_path, records_path));
+        store.prune();
+        store
+    }
+
+    pub fn in_memory() -> Self {
+        Self::with_key(HostKey::generate(), HashMap::new())
+    }
+
+    fn with_key(host_key: HostKey, records: HashMap<String, CredentialRecord>) -> Self {
+        let key = BASE64.decode(&host_key.key).unwrap_or_default();
+        Self {
+            host_key,
+            key,
+            records,
+            paths: None,
+        }
+    }
+
+    pub fn host_id(&self) -> &str {
+        &self.host_key.host_id
+    }
+
+    /// 清理已撤销和已过期的记录
+    fn prune(&mut self) {
+        let now = chrono::Utc::now().timestamp() as u64;
+        self.records.retain(|_, r| !r.revoked && r.expires_at > now);
+    }
+
+    fn save(&mut self) {
+        self.prune();
+        let Some((_, records_path)) = &self.paths else {
+            return;
+        };
+        if let Ok(json) = serde_json::to_string_pretty(&self.records) {
+            write_reported(records_path, json.as_bytes(), false);
+        }
+    }
+
+    /// 签发凭据，同一设备只保留最新的一份
+    pub fn issue(&mut self, device_serial: &str, device_name: &str, lifetime_secs: u64) -> String {
+        self.revoke_device(device_serial);
+        let now = chrono::Utc::now().timestamp() as u64;
+        let claims = CredentialClaims {
+            device_serial: device_serial.to_string(),
+            jti: uuid::Uuid::new_v4().to_string(),
+            aud: self.host_key.host_id.clone(),
+            iat: now as usize,
+            exp: (now + lifetime_secs) as usize,
+        };
+        self.records.insert(
+            claims.jti.clone(),
+            CredentialRecord {
+                id: claims.jti.clone(),
+                device_serial: device_serial.to_string(),
+                device_name: device_name.to_string(),
+                issued_at: now,
+                expires_at: now + lifetime_secs,
+                revoked: false,
+            },
+        );
+        self.save();
+        println!("[CREDENTIAL]为设备{:?}签发凭据", device_serial);
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(&self.key),
+        )
+        .unwrap()
+    }
+
+    /// 校验签名、有效期、签发主机、撤销状态和设备序列号
+    pub fn verify(
+        &self,
+        credential: &str,
+        device_serial: &str,
+    ) -> Result<CredentialClaims, CredentialError> {
+        let claims = self.verify_token(credential)?;
+        if claims.device_serial != device_serial {
+            return Err(CredentialError::SerialMismatch);
+        }
+        Ok(claims)
+    }
+
+    /// 端到端加密握手用的秘密：对凭据的 `header.payload` 重新签名得到的签名部分。
+    /// 签名只有主机和持有凭据的设备知道，中转服务器只能看到 `header.payload`
+    pub fn pake_secret(&self, unsigned: &str) -> Result<String, CredentialError> {
+        let signature = sign(
+            unsigned.as_bytes(),
+            &EncodingKey::from_secret(&self.key),
+            Algorithm::HS256,
+        )
+        .map_err(|e| CredentialError::Invalid(e.to_string()))?;
+        // 过期或撤销的凭据不能用来握手
+        self.verify_token(&format!("{}.{}", unsigned, signature))?;
+        Ok(signature)
+    }
+
+    fn verify_token(&self, credential: &str) -> Result<CredentialClaims, CredentialError> {
+        let mut validation = Validation::default();
+        validation.set_audience(&[self.host_key.host_id.as_str()]);
+        let claims = decode::<CredentialClaims>(
+            credential,
+            &DecodingKey::from_secret(&self.key),
+            &validation,
+        )
+        .map_err(|e| CredentialError::Invalid(e.to_string()))?
+        .claims;
+        match self.records.get(&claims.jti) {
+            Some(record) if !record.revoked => Ok(claims),
+            _ => Err(CredentialError::Revoked),
+        }
+    }
+
+    /// 撤销某台设备的全部凭据，返回撤销的数量
+    pub fn revoke_device(&mut self, device_serial: &str) -> usize {
+        let mut count = 0;
+        for record in self.records.values_mut() {
+            if record.device_serial == device_serial && !record.revoked {
+                record.revoked = true;
+                count += 1;
+            }
+        }
+        if count > 0 {
+            self.save();
+        }
+        count
+    }
+
+    /// 设备是否持有未撤销且未过期的凭据
+    pub fn has_active(&self, device_serial: &str) -> bool {
+        let now = chrono::Utc::now().timestamp() as u64;
+        self.records
+            .values()
+            .any(|r| r.device_serial == device_serial && !r.revoked && r.expires_at > now)
+    }
+
+    /// 所有未撤销且未过期的凭据
+    pub fn list(&self) -> Vec<CredentialRecord> {
+        let now = chrono::Utc::now().timestamp() as u64;
+        let mut list: Vec<CredentialRecord> = self
+            .records
+            .values()
+            .filter(|r| !r.revoked && r.expires_at > now)
+            .cloned()
+            .collect();
+        list.sort_by_key(|r| r.issued_at);
+        list
+    }
+}
+
+/// 读取 JSON 文件。文件不存在时返回 None；读不出来或解析失败时备份并通知界面
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Option<T> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            storage::report(StorageError {
+                file: path.display().to_string(),
+                reason: format!("读取失败：{}", e),
+                backup: None,
+            });
+            return None;
+        }
+    };
+    match serde_json::from_str(&data) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            // 主机密钥读不出来时会重新生成，旧文件备份起来，不直接覆盖
+            let backup = storage::backup_unreadable(path)
+                .map(|backup| backup.display().to_string())
+                .map_err(|e| println!("[CREDENTIAL]备份{:?}失败：{:?}", path, e))
+                .ok();
+            storage::report(StorageError {
+                file: path.display().to_string(),
+                reason: format!("解析失败：{}", e),
+                backup,
+            });
+            None
+        }
+    }
+}
+
+/// 原子写入，主机密钥只有本人可读写；失败时通知界面
+fn write_reported(path: &Path, data: &[u8], private: bool) {
+    let result = if private {
+        storage::write_private(path, data)
+    } else {
+        storage::write_atomic(path, data)
+    };
+    if let Err(e) = result {
+        storage::report(StorageError {
+            file: path.display().to_string(),
+            reason: format!("保存失败：{}", e),
+            backup: None,
+        });
+    }
+}
+
+lazy_static! {
+    pub static ref CREDENTIALS: Mutex<CredentialStore> = Mutex::new(CredentialStore::load(
+        get_host_key_path(),
+        get_credentials_path()
+    ));
+}
+
+/// 撤销设备凭据，拉黑、删除设备或用户手动撤销时调用
+pub fn revoke_device_credentials(device_serial: &str) {
+    let count = CREDENTIALS.lock().unwrap().revoke_device(device_serial);
+    if count > 0 {
+        println!("[CREDENTIAL]撤销设备{:?}的{}份凭据", device_serial, count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issue_verify_revoke() {
+        let mut store = CredentialStore::in_memory();
+        let credential = store.issue("serial-1", "phone", 3600);
+        let claims = store.verify(&credential, "serial-1").unwrap();
+        assert_eq!(claims.aud, store.host_id());
+        assert_eq!(
+            store.verify(&credential, "serial-2").unwrap_err(),
+            CredentialError::SerialMismatch
+        );
+
+        // 重新签发后旧凭据失效
+        let newer = store.issue("serial-1", "phone", 3600);
+        assert_eq!(
+            store.verify(&credential, "serial-1").unwrap_err(),
+            CredentialError::Revoked
+        );
+        assert_eq!(store.list().len(), 1);
+        // 撤销的记录已经清理
+        assert_eq!(store.records.len(), 1);
+
+        assert_eq!(store.revoke_device("serial-1"), 1);
+        assert_eq!(
+            store.verify(&newer, "serial-1").unwrap_err(),
+            CredentialError::Revoked
+        );
+    }
+
+    #[test]
+    fn test_pake_secret() {
+        let mut store = CredentialStore::in_memory();
+        let credential = store.issue("serial-1", "phone", 3600);
+        let (unsigned, signature) = credential.rsplit_once('.').unwrap();
+        assert_eq!(store.pake_secret(unsigned).unwrap(), signature);
+
+        store.revoke_device("serial-1");
+        assert_eq!(
+            store.pake_secret(unsigned).unwrap_err(),
+            CredentialError::Revoked
+        );
+        assert!(CredentialStore::in_memory().pake_secret(unsigned).is_err());
+    }
+
+    #[test]
+    fn test_other_host_rejected() {
+        let mut store = CredentialStore::in_memory();
+        let other = CredentialStore::in_memory();
+        let credential = store.issue("serial-1", "phone", 3600);
+        assert!(matches!(
+            other.verify(&credential, "serial-1"),
+            Err(CredentialError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_persisted_key_survives_reload() {
+        let dir = std::env::temp_dir().join(format!("lqmy-cred-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (key_path, records_path) = (dir.join("host_key.json"), dir.join("creds.json"));
+
+        let credential = CredentialStore::load(key_path.clone(), records_path.clone())
+            .issue("serial-1", "phone", 3600);
+        // 模拟主机重启
+        let reloaded = CredentialStore::load(key_path.clone(), records_path);
+        assert!(reloaded.verify(&credential, "serial-1").is_ok());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+}
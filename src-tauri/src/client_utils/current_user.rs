@@ -1,10 +1,13 @@
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::{
-    client::{PENDING, SEND_NOTIFY},
-    config::UUID,
-};
+use crate::audit;
+use crate::client::send_to_peer_with_priority;
+use crate::events::{emit, ServerEvent};
+
+use super::e2e;
+use super::outbound::Priority;
+pub use crate::protocol::CrtlReq;
 
 use super::user_manager::UserType;
 
@@ -43,20 +46,40 @@ impl CurUsersInfo {
     //     self.pointer=self.max;
     // }
 
-    /// 添加新的连接用户信息
-    pub fn add_new_cur_user(&mut self, new_user: &CurInfo) {
+    /// 添加新的连接用户信息，已到连接上限时返回 false
+    pub fn add_new_cur_user(&mut self, new_user: &CurInfo) -> bool {
         if self.usersinfo.len() < self.max {
             self.usersinfo.push(new_user.clone());
-            println!("[CONFIG]成功添加新的用户信息：{:?}", new_user)
+            println!("[CONFIG]成功添加新的用户信息：{:?}", new_user);
+            emit(ServerEvent::UserJoined {
+                device_name: new_user.device_name.clone(),
+                device_id: new_user.device_id.clone(),
+                uuid: new_user.uuid.clone(),
+                user_type: new_user.user_type.clone(),
+            });
+            true
         } else {
-            println!("[CONFIG]失败添加新的用户信息：{:?}", new_user)
+            println!("[CONFIG]失败添加新的用户信息：{:?}", new_user);
+            false
         }
     }
 
     /// 重置信息
     pub fn reset(&mut self) {
         self.pointer = self.max;
-        self.usersinfo = Vec::new();
+        for cur_info in std::mem::take(&mut self.usersinfo) {
+            e2e::forget(&cur_info.uuid);
+            audit::record(
+                "session_end",
+                audit::Outcome::Info,
+                Some(&cur_info.device_id),
+                Some(&cur_info.uuid),
+                "reset",
+            );
+            emit(ServerEvent::UserLeft {
+                uuid: cur_info.uuid,
+            });
+        }
     }
 
     /// 序列号确认信息存在与否
@@ -68,6 +91,27 @@ impl CurUsersInfo {
         res
     }
 
+    /// 最大连接数
+    /// 按 uuid 查设备序列号
+    pub fn serial_by_uuid(&self, uuid: &str) -> Option<String> {
+        self.usersinfo
+            .iter()
+            .find(|cur_info| cur_info.uuid == uuid)
+            .map(|cur_info| cur_info.device_id.clone())
+    }
+
+    /// 按设备序列号查 uuid
+    pub fn uuid_by_serial(&self, serial: &str) -> Option<String> {
+        self.usersinfo
+            .iter()
+            .find(|cur_info| cur_info.device_id == serial)
+            .map(|cur_info| cur_info.uuid.clone())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.max
+    }
+
     /// 是否有空余
     pub fn is_avail(&self) -> bool {
         self.usersinfo.len() < self.max
@@ -86,6 +130,15 @@ impl CurUsersInfo {
         if target < self.usersinfo.len() {
             let removed = self.usersinfo.swap_remove(target);
             println!("[CURUSER]连接用户信息删除：{:?}", removed);
+            e2e::forget(&removed.uuid);
+            audit::record(
+                "session_end",
+                audit::Outcome::Info,
+                Some(&removed.device_id),
+                Some(&removed.uuid),
+                "",
+            );
+            emit(ServerEvent::UserLeft { uuid: removed.uuid });
             true
         } else {
             println!("[CURUSER]连接用户信息删除失败：{:?}", uuid);
@@ -113,18 +166,16 @@ impl CurUsersInfo {
             status: "100".to_string(),
             body: "控制权取回".to_string(),
         };
-        let uuid = UUID.lock().unwrap().clone();
-        let reply = json!({
-            "type": "message",
-            "target_uuid": self.usersinfo[self.pointer].uuid,
-            "from":uuid,
-            "payload": json!(result),
-        });
-        drop(uuid);
-        let mut pending = PENDING.lock().unwrap();
-        pending.push(reply.clone());
-        drop(pending);
-        SEND_NOTIFY.notify_one();
+        let uuid = self.usersinfo[self.pointer].uuid.clone();
+        audit::record(
+            "control_revoked",
+            audit::Outcome::Success,
+            Some(&self.usersinfo[self.pointer].device_id),
+            Some(&uuid),
+            "",
+        );
+        let _ = send_to_peer_with_priority(&uuid, json!(result), Priority::High);
+        emit(ServerEvent::ControlRevoked { uuid });
         self.pointer = self.max
     }
 }
@@ -137,12 +188,6 @@ pub struct CurInfo {
     pub uuid: String,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct CrtlReq {
-    pub jwt: String,
-    pub uuid: String,
-    pub device_serial: String,
-}
 #[derive(Debug, Serialize)]
 pub struct CrtlAns {
     pub status: String,
@@ -0,0 +1,180 @@
+//! 设备公钥配对
+//!
+//! device_serial 是手机自己上报的字符串，不能单独作为身份依据。
+//! 首次配对时手机提交 Ed25519 公钥，审批通过后和设备绑定；之后每次连接先用 `challenge`
+//! 命令取一个随机数，再在 auth 中附上对它的签名。绑定了公钥的序列号只能由持有私钥的设备使用。
+//!
+//! 签名内容为 `lqmy-auth\n<nonce>\n<device_serial>\n<uuid>`，其中 uuid 是中转服务器给出的 from。
+
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64, STANDARD_NO_PAD},
+    Engine,
+};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use lazy_static::lazy_static;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::protocol::AuthRequest;
+
+/// challenge 的有效期
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DeviceKeyError {
+    #[error("公钥格式错误")]
+    InvalidKey,
+
+    #[error("签名格式错误")]
+    InvalidSignature,
+
+    #[error("没有可用的 challenge，请先发送 challenge 命令")]
+    NoChallenge,
+
+    #[error("设备密钥签名校验失败")]
+    BadSignature,
+
+    #[error("该设备已绑定其他公钥")]
+    KeyMismatch,
+}
+
+/// 按发送方 uuid 记录未使用的 challenge，每个 uuid 只保留最新的一个
+#[derive(Default)]
+pub struct ChallengeStore {
+    pending: HashMap<String, (String, Instant)>,
+}
+
+impl ChallengeStore {
+    /// 生成新的 challenge，返回 base64 编码的 32 字节随机数
+    pub fn issue(&mut self, uuid: &str) -> String {
+        self.pending
+            .retain(|_, (_, issued)| issued.elapsed() < CHALLENGE_TTL);
+        let mut nonce = [0u8; 32];
+        rand::rng().fill(&mut nonce);
+        let nonce = BASE64.encode(nonce);
+        self.pending
+            .insert(uuid.to_string(), (nonce.clone(), Instant::now()));
+        nonce
+    }
+
+    /// 取出并作废 challenge，过期视为不存在
+    pub fn take(&mut self, uuid: &str) -> Option<String> {
+        self.pending
+            .remove(uuid)
+            .filter(|(_, issued)| issued.elapsed() < CHALLENGE_TTL)
+            .map(|(nonce, _)| nonce)
+    }
+}
+
+lazy_static! {
+    pub static ref CHALLENGES: Mutex<ChallengeStore> = Mutex::new(ChallengeStore::default());
+}
+
+fn decode_key(public_key: &str) -> Result<VerifyingKey, DeviceKeyError> {
+    let raw: [u8; 32] = BASE64
+        .decode(public_key)
+        .ok()
+        .and_then(|raw| raw.try_into().ok())
+        .ok_or(DeviceKeyError::InvalidKey)?;
+    VerifyingKey::from_bytes(&raw).map_err(|_| DeviceKeyError::InvalidKey)
+}
+
+/// 公钥指纹，格式同 OpenSSH：`SHA256:<base64 无填充>`
+pub fn fingerprint(public_key: &str) -> Result<String, DeviceKeyError> {
+    let key = decode_key(public_key)?;
+    Ok(format!(
+        "SHA256:{}",
+        STANDARD_NO_PAD.encode(Sha256::digest(key.as_bytes()))
+    ))
+}
+
+/// 设备需要签名的内容
+pub fn signed_message(nonce: &str, device_serial: &str, uuid: &str) -> Vec<u8> {
+    format!("lqmy-auth\n{}\n{}\n{}", nonce, device_serial, uuid).into_bytes()
+}
+
+pub fn verify_signature(
+    public_key: &str,
+    signature: &str,
+    message: &[u8],
+) -> Result<(), DeviceKeyError> {
+    let key = decode_key(public_key)?;
+    let signature = BASE64
+        .decode(signature)
+        .ok()
+        .and_then(|raw| Signature::from_slice(&raw).ok())
+        .ok_or(DeviceKeyError::InvalidSignature)?;
+    key.verify(message, &signature)
+        .map_err(|_| DeviceKeyError::BadSignature)
+}
+
+/// 校验 auth 请求中的持钥证明。没有附带公钥时返回 None；
+/// 附带公钥时必须对本 uuid 最近一次的 challenge 签名，成功返回该公钥
+pub fn prove(info: &AuthRequest) -> Result<Option<String>, DeviceKeyError> {
+    let Some(public_key) = &info.public_key else {
+        return Ok(None);
+    };
+    let nonce = CHALLENGES
+        .lock()
+        .unwrap()
+        .take(&info.uuid)
+        .ok_or(DeviceKeyError::NoChallenge)?;
+    let signature = info
+        .signature
+        .as_deref()
+        .ok_or(DeviceKeyError::InvalidSignature)?;
+    verify_signature(
+        public_key,
+        signature,
+        &signed_message(&nonce, &info.device_serial, &info.uuid),
+    )?;
+    Ok(Some(public_key.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn request(key: &SigningKey, nonce: &str, serial: &str, uuid: &str) -> AuthRequest {
+        let signature = key.sign(&signed_message(nonce, serial, uuid));
+        AuthRequest {
+            device_name: "phone".to_string(),
+            device_serial: serial.to_string(),
+            password: String::new(),
+            uuid: uuid.to_string(),
+            public_key: Some(BASE64.encode(key.verifying_key().as_bytes())),
+            signature: Some(BASE64.encode(signature.to_bytes())),
+        }
+    }
+
+    #[test]
+    fn test_challenge_response() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let nonce = CHALLENGES.lock().unwrap().issue("phone-k");
+        let req = request(&key, &nonce, "serial-k", "phone-k");
+        assert_eq!(prove(&req).unwrap(), req.public_key);
+        // challenge 只能用一次
+        assert_eq!(prove(&req).unwrap_err(), DeviceKeyError::NoChallenge);
+
+        // 签名绑定了 uuid，别的连接不能重放
+        let nonce = CHALLENGES.lock().unwrap().issue("phone-x");
+        let mut req = request(&key, &nonce, "serial-k", "phone-k");
+        req.uuid = "phone-x".to_string();
+        assert_eq!(prove(&req).unwrap_err(), DeviceKeyError::BadSignature);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let key = SigningKey::from_bytes(&[9u8; 32]);
+        let public_key = BASE64.encode(key.verifying_key().as_bytes());
+        let fp = fingerprint(&public_key).unwrap();
+        assert!(fp.starts_with("SHA256:"));
+        assert_eq!(fp.len(), "SHA256:".len() + 43);
+        assert_eq!(fingerprint("abcd").unwrap_err(), DeviceKeyError::InvalidKey);
+    }
+}
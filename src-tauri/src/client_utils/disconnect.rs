@@ -1,24 +1,16 @@
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use serde_json::json;
 
-use crate::{
-    client::{PENDING, SEND_NOTIFY},
-    client_utils::disconnect,
-    config::{CURRENT_USERS_INFO, UUID},
-};
+use crate::{client::send_to_peer_with_priority, config::CURRENT_USERS_INFO};
 
-use super::auth::validate_jwt;
+use super::{auth::verify_jwt, outbound::Priority, tokens::revoke_device};
 
-#[derive(Debug, Deserialize)]
-/// 被动断连
-pub struct DisconnectReq {
-    pub jwt: String,
-    pub device_serial: String,
-}
+pub use crate::protocol::DisconnectReq;
 
 impl DisconnectReq {
+    /// token 有效且属于请求中的设备
     pub fn verify(&self) -> bool {
-        validate_jwt(&self.jwt)
+        verify_jwt(&self.jwt).is_ok_and(|claims| claims.device_serial == self.device_serial)
     }
 }
 
@@ -27,22 +19,22 @@ pub struct Disconnect {
     pub cmd: String,
 }
 
-/// 这个函数不仅要删除当前连接用户的信息，还要返回一个消息告诉对方关闭连接了
+/// 这个函数不仅要删除当前连接用户的信息，还要返回一个消息告诉对方关闭连接了。
+/// 删除时记 session_end 审计；主机主动踢出另由调用方记 kick
 pub fn disconnect_cur_user_by_uuid(uuid: &str) {
+    let mut cur_users = CURRENT_USERS_INFO.lock().unwrap();
+    // 断开的设备已发出的 token 一并作废
+    if let Some(serial) = cur_users.serial_by_uuid(uuid) {
+        revoke_device(&serial);
+    }
     //删除连接信息
-    if CURRENT_USERS_INFO.lock().unwrap().delete_by_uuid(uuid) {
+    let removed = cur_users.delete_by_uuid(uuid);
+    drop(cur_users);
+    if removed {
         // 告知对方
         let res = Disconnect {
             cmd: "disconnect".to_owned(),
         };
-
-        let reply = json!({
-            "type": "message",
-            "target_uuid": uuid,
-            "from":UUID.lock().unwrap().clone(),
-            "payload": json!(res),
-        });
-        PENDING.lock().unwrap().push(reply.clone());
-        SEND_NOTIFY.notify_one();
+        let _ = send_to_peer_with_priority(uuid, json!(res), Priority::High);
     }
 }
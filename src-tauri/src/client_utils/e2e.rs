@@ -0,0 +1,582 @@
+//! 信令端到端加密
+//!
+//! 中转服务器能看到 message 的全部 payload，其中有连接口令、JWT 和带 ICE 凭据的 SDP。
+//! 手机端先发送 `pake_start`，双方用共同知道的秘密跑一次 SPAKE2（对称模式）得到共享密钥，
+//! 再用 HKDF-SHA256 按方向派生两把 ChaCha20-Poly1305 密钥，之后的 payload 都包装成
+//! `{"cmd":"sealed","data":{"n":计数,"ct":base64密文}}`。
+//! 中转服务器只能看到密文，也无法在不知道秘密的情况下伪造命令。
+//!
+//! `pake_start` 的 `kind` 决定用哪个秘密，见 [`PakeKind`]：
+//! - `password`（默认）：8 位临时连接口令
+//! - `unattended`：无人值守固定口令的派生值，见 [`unattended_secret`]
+//! - `credential`：长期设备凭据的签名部分，`id` 里带上凭据去掉签名后的 `header.payload`。
+//!   信任设备和 `resume` 都用这种方式，不需要连接口令
+//!
+//! 默认强制加密，环境变量 `LQMY_REQUIRE_E2E=0` 可以关闭；即使关闭，带口令的明文 `auth` 也一律拒绝。
+//!
+//! 握手不需要任何凭据，所以尚未认证的会话有数量上限，超过 [`PENDING_TTL`] 仍未认证就作废；
+//! 中转服务器通知对端下线（`peer_offline`）时也会丢弃会话。
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use thiserror::Error;
+
+use crate::protocol::{unwrap_json_string, ErrorReply};
+
+pub const PAKE_START: &str = "pake_start";
+pub const PAKE_REPLY: &str = "pake_reply";
+pub const SEALED: &str = "sealed";
+
+/// 双方共用的 SPAKE2 身份标识，协议变化时一起修改
+pub const PAKE_IDENTITY: &[u8] = b"lqmy-desk/e2e/v1";
+const HOST_TO_MOBILE: &[u8] = b"lqmy-desk/e2e/host->mobile";
+const MOBILE_TO_HOST: &[u8] = b"lqmy-desk/e2e/mobile->host";
+/// 允许乱序到达的消息窗口
+const REPLAY_WINDOW: u64 = 64;
+/// 无人值守口令派生握手秘密时使用的盐
+const UNATTENDED_SALT: &[u8] = b"lqmy-desk/e2e/unattended";
+/// 握手后多久之内必须完成认证
+pub const PENDING_TTL: Duration = Duration::from_secs(120);
+/// 同时存在的未认证会话上限
+const MAX_PENDING_SESSIONS: usize = 32;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum E2eError {
+    #[error("malformed e2e message: {0}")]
+    Malformed(String),
+
+    #[error("pake handshake failed")]
+    Handshake,
+
+    #[error("no e2e session with peer")]
+    NoSession,
+
+    #[error("decryption failed")]
+    Decrypt,
+
+    #[error("replayed message {0}")]
+    Replay(u64),
+
+    #[error("decryption failed, handshake secret mismatch")]
+    BadSecret(PakeKind),
+
+    #[error("plaintext payload rejected, e2e required")]
+    PlaintextRejected,
+
+    #[error("too many pending e2e sessions")]
+    TooManySessions,
+}
+
+impl E2eError {
+    pub fn to_reply(&self) -> ErrorReply {
+        let code = match self {
+            E2eError::Malformed(_) => 400,
+            E2eError::PlaintextRejected => 403,
+            E2eError::TooManySessions => 429,
+            _ => 401,
+        };
+        ErrorReply::new(code, self.to_string(), Some(SEALED))
+    }
+}
+
+/// 会话中的哪一端
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Host,
+    Mobile,
+}
+
+/// 握手使用的秘密种类
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PakeKind {
+    /// 临时连接口令
+    Password,
+    /// 无人值守固定口令
+    Unattended,
+    /// 长期设备凭据，带凭据的 `header.payload`
+    Credential(String),
+}
+
+impl PakeKind {
+    fn from_data(data: &Value) -> Result<Self, E2eError> {
+        match data.get("kind").and_then(Value::as_str) {
+            None | Some("password") => Ok(PakeKind::Password),
+            Some("unattended") => Ok(PakeKind::Unattended),
+            Some("credential") => data
+                .get("id")
+                .and_then(Value::as_str)
+                .map(|id| PakeKind::Credential(id.to_string()))
+                .ok_or_else(|| E2eError::Malformed("missing `id`".to_string())),
+            Some(other) => Err(E2eError::Malformed(format!("unknown kind `{}`", other))),
+        }
+    }
+}
+
+/// 无人值守固定口令用于握手的派生值：Argon2id，盐固定，手机端用同样的参数计算
+pub fn unattended_secret(password: &str) -> Result<String, String> {
+    let mut out = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), UNATTENDED_SALT, &mut out)
+        .map_err(|e| format!("口令派生失败: {}", e))?;
+    Ok(BASE64.encode(out))
+}
+
+/// 接收方向的重放检测：记录最大计数和其下 64 个计数是否出现过
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, n: u64) -> bool {
+        if n == 0 {
+            return false;
+        }
+        if n > self.highest {
+            let shift = n - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = n;
+            return true;
+        }
+        let offset = self.highest - n;
+        if offset >= REPLAY_WINDOW || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+/// 握手完成后的一条加密通道
+pub struct E2eSession {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    send_aad: &'static [u8],
+    recv_aad: &'static [u8],
+    send_counter: u64,
+    replay: ReplayWindow,
+}
+
+impl E2eSession {
+    /// 由 SPAKE2 输出的共享密钥派生收发两个方向的密钥
+    pub fn derive(shared: &[u8], role: Role) -> Self {
+        let hk = Hkdf::<Sha256>::new(None, shared);
+        let expand = |info: &[u8]| {
+            let mut okm = [0u8; 32];
+            hk.expand(info, &mut okm)
+                .expect("32 bytes is a valid hkdf length");
+            ChaCha20Poly1305::new(Key::from_slice(&okm))
+        };
+        let (send_aad, recv_aad) = match role {
+            Role::Host => (HOST_TO_MOBILE, MOBILE_TO_HOST),
+            Role::Mobile => (MOBILE_TO_HOST, HOST_TO_MOBILE),
+        };
+        Self {
+            send: expand(send_aad),
+            recv: expand(recv_aad),
+            send_aad,
+            recv_aad,
+            send_counter: 0,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    /// 加密一个 payload，返回 sealed 命令
+    pub fn seal(&mut self, payload: &Value) -> Value {
+        self.send_counter += 1;
+        let n = self.send_counter;
+        let ct = self
+            .send
+            .encrypt(
+                &nonce(n),
+                Payload {
+                    msg: payload.to_string().as_bytes(),
+                    aad: self.send_aad,
+                },
+            )
+            .expect("chacha20poly1305 encryption does not fail");
+        json!({ "cmd": SEALED, "data": { "n": n, "ct": BASE64.encode(ct) } })
+    }
+
+    /// 解密 sealed 命令的 data 部分
+    pub fn open(&mut self, data: &Value) -> Result<Value, E2eError> {
+        let n = data
+            .get("n")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| E2eError::Malformed("missing `n`".to_string()))?;
+        let ct = data
+            .get("ct")
+            .and_then(Value::as_str)
+            .ok_or_else(|| E2eError::Malformed("missing `ct`".to_string()))?;
+        let ct = BASE64
+            .decode(ct)
+            .map_err(|e| E2eError::Malformed(e.to_string()))?;
+        let plain = self
+            .recv
+            .decrypt(
+                &nonce(n),
+                Payload {
+                    msg: &ct,
+                    aad: self.recv_aad,
+                },
+            )
+            .map_err(|_| E2eError::Decrypt)?;
+        // 认证通过后才记录计数，伪造的消息不会挤占窗口
+        if !self.replay.accept(n) {
+            return Err(E2eError::Replay(n));
+        }
+        serde_json::from_slice(&plain).map_err(|e| E2eError::Malformed(e.to_string()))
+    }
+}
+
+fn nonce(n: u64) -> Nonce {
+    let mut bytes = [0u8; 12];
+    bytes[4..].copy_from_slice(&n.to_be_bytes());
+    *Nonce::from_slice(&bytes)
+}
+
+/// 用口令和对方的 SPAKE2 消息完成握手，返回本端消息与会话
+pub fn respond(
+    password: &str,
+    inbound: &[u8],
+    role: Role,
+) -> Result<(Vec<u8>, E2eSession), E2eError> {
+    let (state, outbound) = Spake2::<Ed25519Group>::start_symmetric(
+        &Password::new(password.as_bytes()),
+        &Identity::new(PAKE_IDENTITY),
+    );
+    let shared = state.finish(inbound).map_err(|_| E2eError::Handshake)?;
+    Ok((outbound, E2eSession::derive(&shared, role)))
+}
+
+/// 一个手机端的加密会话
+struct SessionEntry {
+    /// 握手用的秘密种类
+    kind: PakeKind,
+    session: E2eSession,
+    created_at: Instant,
+    /// 已经通过 auth / resume 或者带着有效 token 发过命令
+    authenticated: bool,
+}
+
+impl SessionEntry {
+    fn expired(&self, now: Instant) -> bool {
+        !self.authenticated && now.duration_since(self.created_at) >= PENDING_TTL
+    }
+}
+
+lazy_static! {
+    // 每个手机端（按 uuid）的加密会话
+    static ref SESSIONS: Mutex<HashMap<String, SessionEntry>> = Mutex::new(HashMap::new());
+    // 拒绝所有未加密的命令，默认开启，环境变量 LQMY_REQUIRE_E2E=0 关闭
+    static ref REQUIRE_E2E: AtomicBool = AtomicBool::new(
+        std::env::var("LQMY_REQUIRE_E2E").map(|v| v != "0").unwrap_or(true)
+    );
+}
+
+pub fn set_require_e2e(required: bool) {
+    println!("[E2E]强制端到端加密：{}", required);
+    REQUIRE_E2E.store(required, Ordering::Relaxed);
+}
+
+pub fn is_e2e_required() -> bool {
+    REQUIRE_E2E.load(Ordering::Relaxed)
+}
+
+pub fn has_session(uuid: &str) -> bool {
+    SESSIONS.lock().unwrap().contains_key(uuid)
+}
+
+/// 用户离开、对端下线或握手失败时丢弃会话
+pub fn forget(uuid: &str) {
+    if SESSIONS.lock().unwrap().remove(uuid).is_some() {
+        println!("[E2E]丢弃与{:?}的加密会话", uuid);
+    }
+}
+
+/// 对端已通过认证，会话不再过期
+pub fn mark_authenticated(uuid: &str) {
+    if let Some(entry) = SESSIONS.lock().unwrap().get_mut(uuid) {
+        entry.authenticated = true;
+    }
+}
+
+/// 清理过期的未认证会话，再检查 from 能否新建会话
+fn check_capacity(
+    sessions: &mut HashMap<String, SessionEntry>,
+    from: &str,
+    now: Instant,
+) -> Result<(), E2eError> {
+    sessions.retain(|_, entry| !entry.expired(now));
+    let pending = sessions
+        .iter()
+        .filter(|(uuid, entry)| !entry.authenticated && uuid.as_str() != from)
+        .count();
+    if pending >= MAX_PENDING_SESSIONS {
+        println!("[E2E]未认证的加密会话过多，拒绝{:?}的握手", from);
+        return Err(E2eError::TooManySessions);
+    }
+    Ok(())
+}
+
+/// 已和 target 建立会话时加密 payload，否则原样返回
+pub fn seal_for(target: &str, payload: Value) -> Value {
+    match SESSIONS.lock().unwrap().get_mut(target) {
+        Some(entry) => entry.session.seal(&payload),
+        None => payload,
+    }
+}
+
+/// 收到的 payload 经过解密后的结果
+#[derive(Debug)]
+pub enum Inbound {
+    /// 握手请求，已生成回复，回复发出后再调用 [`install`]
+    Handshake {
+        reply: Value,
+        session: PendingSession,
+    },
+    /// 普通命令（可能来自解密）
+    Command(Value),
+}
+
+/// 握手得到、尚未启用的会话，保证 pake_reply 本身以明文发出
+pub struct PendingSession(PakeKind, E2eSession);
+
+impl std::fmt::Debug for PendingSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PendingSession")
+    }
+}
+
+pub fn install(uuid: &str, session: PendingSession) {
+    println!("[E2E]与{:?}建立加密会话", uuid);
+    SESSIONS.lock().unwrap().insert(
+        uuid.to_string(),
+        SessionEntry {
+            kind: session.0,
+            session: session.1,
+            created_at: Instant::now(),
+            authenticated: false,
+        },
+    );
+}
+
+/// 处理来自 from 的 payload：握手、解密，或按策略拒绝明文。
+/// `secret` 按握手请求的种类给出本机一侧的秘密，无法提供时返回错误
+pub fn open_inbound(
+    from: &str,
+    payload: &Value,
+    secret: impl FnOnce(&PakeKind) -> Result<String, E2eError>,
+) -> Result<Inbound, E2eError> {
+    let payload = unwrap_json_string(payload).map_err(|e| E2eError::Malformed(e.to_string()))?;
+    let data = payload.get("data").cloned().unwrap_or(Value::Null);
+    let data = unwrap_json_string(&data).map_err(|e| E2eError::Malformed(e.to_string()))?;
+    match payload.get("cmd").and_then(Value::as_str) {
+        Some(PAKE_START) => {
+            let msg = data
+                .get("msg")
+                .and_then(Value::as_str)
+                .ok_or_else(|| E2eError::Malformed("missing `msg`".to_string()))?;
+            let msg = BASE64
+                .decode(msg)
+                .map_err(|e| E2eError::Malformed(e.to_string()))?;
+            let kind = PakeKind::from_data(&data)?;
+            check_capacity(&mut SESSIONS.lock().unwrap(), from, Instant::now())?;
+            let (outbound, session) = respond(&secret(&kind)?, &msg, Role::Host)?;
+            // 重新握手时旧会话作废
+            forget(from);
+            Ok(Inbound::Handshake {
+                reply: json!({ "cmd": PAKE_REPLY, "value": { "msg": BASE64.encode(outbound) } }),
+                session: PendingSession(kind, session),
+            })
+        }
+        Some(SEALED) => {
+            let mut sessions = SESSIONS.lock().unwrap();
+            if sessions
+                .get(from)
+                .is_some_and(|entry| entry.expired(Instant::now()))
+            {
+                println!("[E2E]与{:?}的加密会话超时未认证，作废", from);
+                sessions.remove(from);
+            }
+            let entry = sessions.get_mut(from).ok_or(E2eError::NoSession)?;
+            let first = entry.session.replay.highest == 0;
+            match entry.session.open(&data) {
+                Ok(inner) => Ok(Inbound::Command(inner)),
+                Err(E2eError::Decrypt) => {
+                    // 秘密不一致时握手本身不会报错，解不开就作废会话；第一条就解不开说明秘密不对
+                    let kind = sessions.remove(from).expect("session checked above").kind;
+                    Err(if first {
+                        E2eError::BadSecret(kind)
+                    } else {
+                        E2eError::Decrypt
+                    })
+                }
+                Err(e) => Err(e),
+            }
+        }
+        _ if is_e2e_required() || has_session(from) || carries_password(&payload, &data) => {
+            Err(E2eError::PlaintextRejected)
+        }
+        _ => Ok(Inbound::Command(payload)),
+    }
+}
+
+/// 带口令的明文 auth，不管是否强制加密都不能让中转服务器看到口令
+fn carries_password(payload: &Value, data: &Value) -> bool {
+    payload.get("cmd").and_then(Value::as_str) == Some("auth")
+        && data
+            .get("password")
+            .and_then(Value::as_str)
+            .is_some_and(|password| !password.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(host_pw: &str, mobile_pw: &str) -> (E2eSession, E2eSession) {
+        let (mobile_state, mobile_msg) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(mobile_pw.as_bytes()),
+            &Identity::new(PAKE_IDENTITY),
+        );
+        let (host_msg, host) = respond(host_pw, &mobile_msg, Role::Host).unwrap();
+        let shared = mobile_state.finish(&host_msg).unwrap();
+        (host, E2eSession::derive(&shared, Role::Mobile))
+    }
+
+    #[test]
+    fn test_roundtrip_and_replay() {
+        let (mut host, mut mobile) = handshake("12345678", "12345678");
+        let auth = json!({ "cmd": "auth", "data": { "password": "12345678" } });
+        let sealed = mobile.seal(&auth);
+        assert!(!sealed.to_string().contains("12345678"));
+        assert_eq!(host.open(&sealed["data"]).unwrap(), auth);
+        assert_eq!(host.open(&sealed["data"]), Err(E2eError::Replay(1)));
+
+        let answer = json!({ "cmd": "answear", "value": {} });
+        let sealed = host.seal(&answer);
+        assert_eq!(mobile.open(&sealed["data"]).unwrap(), answer);
+        // 方向不同，自己发出的消息不能被反射回来
+        assert_eq!(host.open(&sealed["data"]), Err(E2eError::Decrypt));
+    }
+
+    #[test]
+    fn test_wrong_password_and_tamper() {
+        let (mut host, mut mobile) = handshake("12345678", "87654321");
+        let sealed = mobile.seal(&json!({ "cmd": "offer" }));
+        assert_eq!(host.open(&sealed["data"]), Err(E2eError::Decrypt));
+
+        let (mut host, mut mobile) = handshake("12345678", "12345678");
+        let mut sealed = mobile.seal(&json!({ "cmd": "offer" }));
+        sealed["data"]["n"] = json!(2);
+        assert_eq!(host.open(&sealed["data"]), Err(E2eError::Decrypt));
+    }
+
+    #[test]
+    fn test_plaintext_auth_and_kinds() {
+        // 不管是否强制加密，带口令的明文 auth 都拒绝
+        let auth = json!({ "cmd": "auth", "data": { "password": "12345678" } });
+        assert_eq!(
+            open_inbound("e2e-test-plain", &auth, |_| unreachable!()).unwrap_err(),
+            E2eError::PlaintextRejected
+        );
+
+        assert_eq!(PakeKind::from_data(&Value::Null), Ok(PakeKind::Password));
+        assert_eq!(
+            PakeKind::from_data(&json!({ "kind": "credential", "id": "a.b" })),
+            Ok(PakeKind::Credential("a.b".to_string()))
+        );
+        assert!(PakeKind::from_data(&json!({ "kind": "credential" })).is_err());
+        assert!(PakeKind::from_data(&json!({ "kind": "other" })).is_err());
+    }
+
+    #[test]
+    fn test_bad_secret_reports_kind() {
+        let peer = "e2e-test-bad-secret";
+        let (state, msg) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(b"wrong-secret"),
+            &Identity::new(PAKE_IDENTITY),
+        );
+        let start = json!({ "cmd": PAKE_START, "data": {
+            "kind": "unattended",
+            "msg": BASE64.encode(msg),
+        }});
+        let inbound = open_inbound(peer, &start, |kind| {
+            assert_eq!(*kind, PakeKind::Unattended);
+            Ok("host-secret".to_string())
+        });
+        let (reply, session) = match inbound {
+            Ok(Inbound::Handshake { reply, session }) => (reply, session),
+            other => panic!("应该是握手请求：{:?}", other),
+        };
+        install(peer, session);
+
+        let host_msg = BASE64
+            .decode(reply["value"]["msg"].as_str().unwrap())
+            .unwrap();
+        let shared = state.finish(&host_msg).unwrap();
+        let sealed = E2eSession::derive(&shared, Role::Mobile).seal(&json!({ "cmd": "auth" }));
+        assert_eq!(
+            open_inbound(peer, &sealed, |_| unreachable!()).unwrap_err(),
+            E2eError::BadSecret(PakeKind::Unattended)
+        );
+        assert!(!has_session(peer));
+    }
+
+    #[test]
+    fn test_pending_sessions_capped_and_expire() {
+        let entry = |authenticated: bool| SessionEntry {
+            kind: PakeKind::Password,
+            session: handshake("12345678", "12345678").0,
+            created_at: Instant::now(),
+            authenticated,
+        };
+        let mut sessions = HashMap::new();
+        sessions.insert("trusted".to_string(), entry(true));
+        for i in 0..MAX_PENDING_SESSIONS {
+            sessions.insert(format!("peer-{}", i), entry(false));
+        }
+        let now = Instant::now();
+        assert_eq!(
+            check_capacity(&mut sessions, "new-peer", now),
+            Err(E2eError::TooManySessions)
+        );
+        // 已有会话的对端可以重新握手
+        assert!(check_capacity(&mut sessions, "peer-0", now).is_ok());
+
+        // 超时后未认证的会话全部作废，认证过的保留
+        assert!(check_capacity(&mut sessions, "new-peer", now + PENDING_TTL).is_ok());
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions.contains_key("trusted"));
+    }
+
+    #[test]
+    fn test_replay_window_out_of_order() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(3));
+        assert!(window.accept(1));
+        assert!(!window.accept(1));
+        assert!(window.accept(100));
+        assert!(!window.accept(3));
+        assert!(window.accept(99));
+    }
+}
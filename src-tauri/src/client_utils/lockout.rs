@@ -0,0 +1,291 @@
+//! 连接口令的防暴力破解
+//!
+//! 按设备序列号和 uuid 分别计数失败次数，超过免费次数后按指数增长锁定；
+//! 所有来源的失败累计到一定次数后直接更换口令，防止换着序列号和 uuid 枚举。
+//! 一段时间没有新的失败时计数逐步衰减，锁定结束且计数归零的来源会被清理，
+//! 避免大量一次性的序列号和 uuid 让表无限增长。
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 时间来源，测试时用 FakeClock 代替
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// 手动拨动的时钟
+#[cfg(test)]
+pub struct FakeClock(Mutex<Instant>);
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new() -> Self {
+        Self(Mutex::new(Instant::now()))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    /// 不锁定的失败次数
+    pub free_attempts: u32,
+    /// 第一次锁定的时长，之后每次失败翻倍
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+    /// 累计失败多少次后更换口令
+    pub rotate_after: u32,
+    /// 每隔这么久没有新的失败，计数减一
+    pub decay_after: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            base_lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(3600),
+            rotate_after: 10,
+            decay_after: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Attempts {
+    failures: u32,
+    locked_until: Option<Instant>,
+    /// 上次失败（或上次衰减）的时间
+    last_failure: Instant,
+}
+
+/// 一次失败的处理结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailureOutcome {
+    /// 该来源（序列号和 uuid 中较多的一个）的连续失败次数
+    pub failures: u32,
+    /// 本次失败触发的锁定时长
+    pub lockout: Option<Duration>,
+    /// 需要更换连接口令
+    pub rotate: bool,
+}
+
+pub struct LockoutTracker {
+    policy: LockoutPolicy,
+    clock: Arc<dyn Clock>,
+    attempts: HashMap<String, Attempts>,
+    /// 上次换口令以来所有来源的失败次数
+    failures_since_rotation: u32,
+}
+
+impl LockoutTracker {
+    pub fn new(policy: LockoutPolicy, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            policy,
+            clock,
+            attempts: HashMap::new(),
+            failures_since_rotation: 0,
+        }
+    }
+
+    fn keys(serial: Option<&str>, uuid: Option<&str>) -> Vec<String> {
+        let mut keys = Vec::new();
+        if let Some(serial) = serial.filter(|s| !s.is_empty()) {
+            keys.push(format!("serial:{}", serial));
+        }
+        if let Some(uuid) = uuid.filter(|s| !s.is_empty()) {
+            keys.push(format!("uuid:{}", uuid));
+        }
+        keys
+    }
+
+    /// 按空闲时间衰减失败次数，清理锁定已结束且计数归零的来源
+    fn prune(&mut self, now: Instant) {
+        let decay = self.policy.decay_after;
+        self.attempts.retain(|_, entry| {
+            if !decay.is_zero() {
+                let steps = (now
+                    .saturating_duration_since(entry.last_failure)
+                    .as_secs_f64()
+                    / decay.as_secs_f64()) as u32;
+                if steps > 0 {
+                    entry.failures = entry.failures.saturating_sub(steps);
+                    entry.last_failure += decay.saturating_mul(steps);
+                }
+            }
+            entry.failures > 0 || entry.locked_until.is_some_and(|until| until > now)
+        });
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.attempts.len()
+    }
+
+    /// 仍在锁定中时返回剩余时间
+    pub fn check(&mut self, serial: Option<&str>, uuid: Option<&str>) -> Result<(), Duration> {
+        let now = self.clock.now();
+        self.prune(now);
+        let remaining = Self::keys(serial, uuid)
+            .iter()
+            .filter_map(|key| self.attempts.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max();
+        match remaining {
+            Some(remaining) => Err(remaining),
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&mut self, serial: Option<&str>, uuid: Option<&str>) -> FailureOutcome {
+        let now = self.clock.now();
+        self.prune(now);
+        let policy = self.policy;
+        let mut outcome = FailureOutcome {
+            failures: 0,
+            lockout: None,
+            rotate: false,
+        };
+        for key in Self::keys(serial, uuid) {
+            let entry = self.attempts.entry(key).or_insert(Attempts {
+                failures: 0,
+                locked_until: None,
+                last_failure: now,
+            });
+            entry.failures += 1;
+            entry.last_failure = now;
+            outcome.failures = outcome.failures.max(entry.failures);
+            if entry.failures >= policy.free_attempts {
+                let exp = (entry.failures - policy.free_attempts).min(16);
+                let lockout = policy
+                    .base_lockout
+                    .saturating_mul(1 << exp)
+                    .min(policy.max_lockout);
+                entry.locked_until = Some(now + lockout);
+                outcome.lockout = outcome.lockout.max(Some(lockout));
+            }
+        }
+        self.failures_since_rotation += 1;
+        if self.failures_since_rotation >= policy.rotate_after {
+            self.failures_since_rotation = 0;
+            outcome.rotate = true;
+        }
+        outcome
+    }
+
+    /// 口令正确后清除该来源的计数
+    pub fn record_success(&mut self, serial: Option<&str>, uuid: Option<&str>) {
+        for key in Self::keys(serial, uuid) {
+            self.attempts.remove(&key);
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref LOCKOUT: Mutex<LockoutTracker> = Mutex::new(LockoutTracker::new(
+        LockoutPolicy::default(),
+        Arc::new(SystemClock)
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_lockout_and_rotation() {
+        let clock = Arc::new(FakeClock::new());
+        let mut tracker = LockoutTracker::new(
+            LockoutPolicy {
+                free_attempts: 2,
+                base_lockout: Duration::from_secs(10),
+                max_lockout: Duration::from_secs(25),
+                rotate_after: 4,
+                decay_after: Duration::from_secs(600),
+            },
+            clock.clone(),
+        );
+        let (serial, uuid) = (Some("s"), Some("u"));
+
+        assert_eq!(tracker.record_failure(serial, uuid).lockout, None);
+        assert!(tracker.check(serial, uuid).is_ok());
+        let outcome = tracker.record_failure(serial, uuid);
+        assert_eq!(outcome.lockout, Some(Duration::from_secs(10)));
+        // 换一个序列号，uuid 仍然锁定
+        assert!(tracker.check(Some("other"), uuid).is_err());
+
+        clock.advance(Duration::from_secs(11));
+        assert!(tracker.check(serial, uuid).is_ok());
+        assert_eq!(
+            tracker.record_failure(serial, uuid).lockout,
+            Some(Duration::from_secs(20))
+        );
+        clock.advance(Duration::from_secs(21));
+        let outcome = tracker.record_failure(serial, uuid);
+        assert_eq!(outcome.lockout, Some(Duration::from_secs(25)));
+        assert!(outcome.rotate);
+
+        tracker.record_success(serial, uuid);
+        assert!(tracker.check(serial, uuid).is_ok());
+    }
+
+    #[test]
+    fn test_failures_decay_and_idle_entries_are_pruned() {
+        let clock = Arc::new(FakeClock::new());
+        let mut tracker = LockoutTracker::new(
+            LockoutPolicy {
+                free_attempts: 3,
+                base_lockout: Duration::from_secs(10),
+                max_lockout: Duration::from_secs(60),
+                rotate_after: 100,
+                decay_after: Duration::from_secs(100),
+            },
+            clock.clone(),
+        );
+        let (serial, uuid) = (Some("s"), Some("u"));
+
+        tracker.record_failure(serial, uuid);
+        tracker.record_failure(serial, uuid);
+        // 空闲一个周期后计数减一，下一次失败仍在免费次数内
+        clock.advance(Duration::from_secs(100));
+        let outcome = tracker.record_failure(serial, uuid);
+        assert_eq!(outcome.failures, 2);
+        assert_eq!(outcome.lockout, None);
+
+        // 大量一次性来源在空闲足够久之后被清理
+        for i in 0..50 {
+            tracker.record_failure(None, Some(&format!("burst-{}", i)));
+        }
+        assert_eq!(tracker.tracked(), 52);
+        clock.advance(Duration::from_secs(200));
+        assert!(tracker.check(serial, uuid).is_ok());
+        assert_eq!(tracker.tracked(), 0);
+    }
+}
@@ -1,8 +1,24 @@
+pub mod approval;
 pub mod auth;
 
+pub mod conn_state;
+pub mod credentials;
+
 pub mod current_user;
+pub mod device_key;
 pub mod dialog;
 pub mod disconnect;
+pub mod e2e;
+pub mod lockout;
+pub mod outbound;
 pub mod password;
+pub mod permissions;
+pub mod policy;
+pub mod proxy;
+pub mod reconnect;
+pub mod relay_pool;
+pub mod relay_tls;
+pub mod tokens;
+pub mod unattended;
 
 pub mod user_manager;
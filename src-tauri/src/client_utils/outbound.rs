@@ -0,0 +1,199 @@
+use lazy_static::lazy_static;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+/// 发送队列容量
+pub const OUTBOUND_CAPACITY: usize = 256;
+/// 单条消息最多尝试发送的次数
+pub const MAX_SEND_ATTEMPTS: u32 = 3;
+
+/// 消息优先级，控制类与关闭类消息先于普通信令发送
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+}
+
+/// 单条消息的投递结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryResult {
+    /// 已写入 websocket
+    Sent,
+    /// 被丢弃，附带原因
+    Dropped(String),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum QueueError {
+    #[error("outbound queue is full ({0} messages)")]
+    Full(usize),
+}
+
+/// 队列中的一条消息
+#[derive(Debug)]
+pub struct Outbound {
+    pub msg: Value,
+    pub priority: Priority,
+    pub attempts: u32,
+    notify: Option<oneshot::Sender<DeliveryResult>>,
+}
+
+impl Outbound {
+    /// 通知等待方投递结果
+    pub fn complete(mut self, result: DeliveryResult) {
+        if let Some(tx) = self.notify.take() {
+            let _ = tx.send(result);
+        }
+    }
+}
+
+/// 有界、按优先级分组的先进先出发送队列
+#[derive(Debug)]
+pub struct OutboundQueue {
+    capacity: usize,
+    high: VecDeque<Outbound>,
+    normal: VecDeque<Outbound>,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            high: VecDeque::new(),
+            normal: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 入队，队满时高优先级消息会挤掉最早的普通消息
+    pub fn push(
+        &mut self,
+        msg: Value,
+        priority: Priority,
+    ) -> Result<oneshot::Receiver<DeliveryResult>, QueueError> {
+        if self.len() >= self.capacity {
+            match (priority, self.normal.pop_front()) {
+                (Priority::High, Some(evicted)) => {
+                    println!("[OUTBOUND]队列已满，丢弃最早的普通消息");
+                    evicted.complete(DeliveryResult::Dropped("发送队列已满".to_string()));
+                }
+                (_, evicted) => {
+                    // 普通消息不挤占，放回原位
+                    if let Some(evicted) = evicted {
+                        self.normal.push_front(evicted);
+                    }
+                    return Err(QueueError::Full(self.capacity));
+                }
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        let item = Outbound {
+            msg,
+            priority,
+            attempts: 0,
+            notify: Some(tx),
+        };
+        match priority {
+            Priority::High => self.high.push_back(item),
+            Priority::Normal => self.normal.push_back(item),
+        }
+        Ok(rx)
+    }
+
+    /// 取出下一条要发送的消息，高优先级优先，同优先级先进先出
+    pub fn pop(&mut self) -> Option<Outbound> {
+        self.high.pop_front().or_else(|| self.normal.pop_front())
+    }
+
+    /// 发送失败的消息放回队首，超过重试次数则丢弃
+    pub fn requeue_front(&mut self, mut item: Outbound, reason: &str) {
+        item.attempts += 1;
+        if item.attempts >= MAX_SEND_ATTEMPTS {
+            println!(
+                "[OUTBOUND]消息重试{}次仍失败，丢弃：{}",
+                item.attempts, reason
+            );
+            item.complete(DeliveryResult::Dropped(reason.to_string()));
+            return;
+        }
+        match item.priority {
+            Priority::High => self.high.push_front(item),
+            Priority::Normal => self.normal.push_front(item),
+        }
+    }
+
+    /// 清空队列，所有等待方收到丢弃结果
+    pub fn clear(&mut self, reason: &str) {
+        while let Some(item) = self.pop() {
+            item.complete(DeliveryResult::Dropped(reason.to_string()));
+        }
+    }
+}
+
+lazy_static! {
+    // 发往服务器的消息队列，由 client 主循环统一发送
+    pub static ref OUTBOUND: Mutex<OutboundQueue> = Mutex::new(OutboundQueue::new(OUTBOUND_CAPACITY));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_fifo_with_priority() {
+        let mut queue = OutboundQueue::new(8);
+        queue.push(json!("answer"), Priority::Normal).unwrap();
+        queue.push(json!("candidate"), Priority::Normal).unwrap();
+        queue.push(json!("revoke"), Priority::High).unwrap();
+
+        let order: Vec<Value> = std::iter::from_fn(|| queue.pop()).map(|o| o.msg).collect();
+        assert_eq!(
+            order,
+            vec![json!("revoke"), json!("answer"), json!("candidate")]
+        );
+    }
+
+    #[test]
+    fn test_capacity_and_eviction() {
+        let mut queue = OutboundQueue::new(2);
+        let mut first = queue.push(json!(1), Priority::Normal).unwrap();
+        queue.push(json!(2), Priority::Normal).unwrap();
+        assert_eq!(
+            queue.push(json!(3), Priority::Normal).unwrap_err(),
+            QueueError::Full(2)
+        );
+        queue.push(json!("close"), Priority::High).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert!(matches!(first.try_recv(), Ok(DeliveryResult::Dropped(_))));
+        assert_eq!(queue.pop().unwrap().msg, json!("close"));
+        assert_eq!(queue.pop().unwrap().msg, json!(2));
+    }
+
+    #[test]
+    fn test_requeue_keeps_order_then_drops() {
+        let mut queue = OutboundQueue::new(8);
+        let mut rx = queue.push(json!("a"), Priority::Normal).unwrap();
+        queue.push(json!("b"), Priority::Normal).unwrap();
+
+        for _ in 0..MAX_SEND_ATTEMPTS - 1 {
+            let item = queue.pop().unwrap();
+            assert_eq!(item.msg, json!("a"));
+            queue.requeue_front(item, "send failed");
+        }
+        let item = queue.pop().unwrap();
+        queue.requeue_front(item, "send failed");
+        assert!(matches!(rx.try_recv(), Ok(DeliveryResult::Dropped(_))));
+        assert_eq!(queue.pop().unwrap().msg, json!("b"));
+    }
+}
@@ -1,30 +1,569 @@
-use crate::config::CONFIG;
-use rand::{thread_rng, Rng};
+//! 连接口令及其更换策略
+//!
+//! TOTP 的密钥不会因失败过多而更换（手机上的验证器要重新扫码），按来源锁定又挡不住
+//! 换着序列号和 uuid 的枚举，所以和固定口令一样另有一个全局失败计数
+//! （[`AttemptLimiter`]）：窗口内失败过多时暂停 TOTP 口令一段时间，并写入审计。
+
+use crate::audit;
+use crate::client_utils::lockout::SystemClock;
+use crate::client_utils::unattended::{AttemptLimiter, COOLDOWN, FAILURE_WINDOW, MAX_FAILURES};
+use crate::config::{get_password_policy_path, CONFIG, UNINIT_PASSWORD};
+use crate::events::{emit, ServerEvent};
+use crate::storage;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// 口令使用的字符集
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "chars", rename_all = "snake_case")]
+pub enum Alphabet {
+    Numeric,
+    Alphanumeric,
+    Custom(String),
+}
+
+impl Alphabet {
+    fn chars(&self) -> Vec<char> {
+        match self {
+            Alphabet::Numeric => ('0'..='9').collect(),
+            Alphabet::Alphanumeric => ('0'..='9').chain('a'..='z').chain('A'..='Z').collect(),
+            Alphabet::Custom(chars) => {
+                let mut chars: Vec<char> = chars.chars().collect();
+                chars.sort_unstable();
+                chars.dedup();
+                chars
+            }
+        }
+    }
+}
+
+/// 口令的更换方式
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum PasswordMode {
+    /// 每次启动服务生成一次
+    Static,
+    /// 每次配对成功后更换
+    OneTime,
+    /// 每隔 interval_secs 秒更换
+    Rotating { interval_secs: u64 },
+    /// RFC 6238 TOTP，手机上的验证器应用扫码后即可无人值守连接
+    Totp {
+        /// base32 编码的共享密钥，为空时自动生成
+        #[serde(default)]
+        secret: String,
+        step_secs: u64,
+        digits: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PasswordPolicy {
+    #[serde(flatten)]
+    pub mode: PasswordMode,
+    /// 随机口令的长度，TOTP 模式下由 digits 决定
+    pub length: usize,
+    pub alphabet: Alphabet,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            mode: PasswordMode::Static,
+            length: 8,
+            alphabet: Alphabet::Numeric,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(4..=32).contains(&self.length) {
+            return Err("口令长度需要在 4 到 32 之间".to_string());
+        }
+        let chars = self.alphabet.chars();
+        if chars.len() < 2 || chars.iter().any(|c| !c.is_ascii_graphic()) {
+            return Err("字符集至少需要两个可见的 ASCII 字符".to_string());
+        }
+        match &self.mode {
+            PasswordMode::Rotating { interval_secs } if *interval_secs < 30 => {
+                Err("更换间隔不能少于 30 秒".to_string())
+            }
+            PasswordMode::Totp {
+                secret,
+                step_secs,
+                digits,
+            } => {
+                if !(6..=8).contains(digits) || *step_secs == 0 {
+                    return Err("TOTP 需要 6 到 8 位，时间步长大于 0".to_string());
+                }
+                if !secret.is_empty() && base32_decode(secret).is_none() {
+                    return Err("TOTP 密钥不是有效的 base32".to_string());
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 提供给前端展示的口令策略，TOTP 附带供验证器扫码的 otpauth 链接
+#[derive(Debug, Clone, Serialize)]
+pub struct PasswordPolicyView {
+    #[serde(flatten)]
+    pub policy: PasswordPolicy,
+    /// 当前口令还剩多少秒失效，不会自动失效时为 None
+    pub expires_in_secs: Option<u64>,
+    pub totp_uri: Option<String>,
+}
+
+struct PolicyState {
+    policy: PasswordPolicy,
+    generated_at: Instant,
+    /// 最近一次验证通过的 TOTP 时间步，同一步及更早的口令不能再用
+    last_totp_counter: Option<u64>,
+    /// 所有来源共用的 TOTP 失败计数
+    totp_attempts: AttemptLimiter,
+}
+
+impl PolicyState {
+    fn is_totp(&self) -> bool {
+        matches!(self.policy.mode, PasswordMode::Totp { .. })
+    }
+
+    /// TOTP 模式下失败过多时暂停，返回剩余时间
+    fn totp_blocked(&self) -> Option<Duration> {
+        self.totp_attempts.blocked().filter(|_| self.is_totp())
+    }
+}
+
+lazy_static! {
+    static ref PASSWORD_POLICY: Mutex<PolicyState> = Mutex::new(PolicyState {
+        policy: load_policy(),
+        generated_at: Instant::now(),
+        last_totp_counter: None,
+        totp_attempts: AttemptLimiter::new(Arc::new(SystemClock)),
+    });
+}
+
+fn load_policy() -> PasswordPolicy {
+    fs::read_to_string(get_password_policy_path())
+        .ok()
+        .and_then(|data| serde_json::from_str::<PasswordPolicy>(&data).ok())
+        .filter(|policy| policy.validate().is_ok())
+        .unwrap_or_default()
+}
+
+/// TOTP 密钥保存在策略里，所以只允许当前用户读写
+fn save_policy(policy: &PasswordPolicy) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(policy).map_err(|e| e.to_string())?;
+    storage::write_private(&get_password_policy_path(), json.as_bytes())
+        .map_err(|e| format!("保存口令策略失败：{}", e))
+}
 
 /**
- * 生成连接口令的内部函数，口令长度由 .take()的参数决定
- * 不是pub，只能由fn generate_connection_password()调用 */
-#[inline]
-async fn generate_password() -> String {
-    let mut rng = thread_rng();
-    let number: u32 = rng.gen_range(10_000_000..100_000_000);
-    let password = format!("{:?}", number);
-    password
+ * 按策略生成随机口令
+ */
+fn generate_password(policy: &PasswordPolicy) -> String {
+    let chars = policy.alphabet.chars();
+    let mut rng = rand::rng();
+    (0..policy.length)
+        .map(|_| chars[rng.random_range(0..chars.len())])
+        .collect()
 }
+
 /**
  * 设置连接口令
  */
 pub async fn generate_connection_password() {
-    let password = generate_password().await;
-    let mut config = CONFIG.lock().unwrap();
-    config.connection_password = password.clone();
+    let password = {
+        let mut state = PASSWORD_POLICY.lock().unwrap();
+        state.generated_at = Instant::now();
+        match &state.policy.mode {
+            PasswordMode::Totp { .. } => current_totp(&state.policy).unwrap_or_default(),
+            _ => generate_password(&state.policy),
+        }
+    };
+    CONFIG.lock().unwrap().connection_password = password.clone();
     println!("Generated connection password: {:?}", password); // 打印或将口令发送给电脑端
+    emit(ServerEvent::PasswordChanged { password });
 }
 
 /**
- * 验证手机端口令
+ * 口令尚未生成时才生成，中转与局域网直连共用同一个口令
+ */
+pub async fn ensure_connection_password() {
+    if CONFIG.lock().unwrap().connection_password == UNINIT_PASSWORD {
+        generate_connection_password().await;
+    }
+}
+
+/**
+ * 当前有效的口令：定时更换到期时先换新，TOTP 按当前时间计算
+ */
+pub fn current_password() -> String {
+    let (rotated, password) = {
+        let mut state = PASSWORD_POLICY.lock().unwrap();
+        match &state.policy.mode {
+            PasswordMode::Totp { .. } => (false, current_totp(&state.policy).unwrap_or_default()),
+            PasswordMode::Rotating { interval_secs }
+                if state.generated_at.elapsed().as_secs() >= *interval_secs =>
+            {
+                state.generated_at = Instant::now();
+                (true, generate_password(&state.policy))
+            }
+            _ => (false, CONFIG.lock().unwrap().connection_password.clone()),
+        }
+    };
+    let mut config = CONFIG.lock().unwrap();
+    if config.connection_password != password {
+        config.connection_password = password.clone();
+        if rotated {
+            println!("[PASSWORD]定时更换连接口令");
+            emit(ServerEvent::PasswordChanged {
+                password: password.clone(),
+            });
+        }
+    }
+    password
+}
+
+/**
+ * 验证手机端口令，TOTP 允许前后各一个时间步的误差，每个时间步的口令只能用一次
  */
 pub async fn verify_password(input_password: &str) -> bool {
-    let config = CONFIG.lock().unwrap();
-    input_password == config.connection_password
+    {
+        let mut state = PASSWORD_POLICY.lock().unwrap();
+        if state.is_totp() {
+            return verify_totp(&mut state, input_password, unix_now());
+        }
+    }
+    let config_password = current_password();
+    config_password != UNINIT_PASSWORD && input_password == config_password
+}
+
+/// 校验 unix 时间 now 时的 TOTP 口令，暂停中一律不通过
+fn verify_totp(state: &mut PolicyState, input_password: &str, now: u64) -> bool {
+    if let Some(remaining) = state.totp_blocked() {
+        println!("[PASSWORD]TOTP 口令暂停中，剩余{:?}", remaining);
+        return false;
+    }
+    let PasswordMode::Totp {
+        secret,
+        step_secs,
+        digits,
+    } = &state.policy.mode
+    else {
+        return false;
+    };
+    let Some(key) = base32_decode(secret) else {
+        return false;
+    };
+    let Some(counter) = [now.saturating_sub(*step_secs), now, now + step_secs]
+        .iter()
+        .find(|t| totp_at(&key, **t, *step_secs, *digits) == input_password)
+        .map(|t| t / step_secs)
+    else {
+        return false;
+    };
+    accept_totp_counter(state, counter)
+}
+
+/// 端到端加密握手用的连接口令，TOTP 暂停中时没有
+pub fn pake_secret() -> Option<String> {
+    if let Some(remaining) = PASSWORD_POLICY.lock().unwrap().totp_blocked() {
+        println!("[PASSWORD]TOTP 口令暂停中，剩余{:?}", remaining);
+        return None;
+    }
+    Some(current_password())
+}
+
+/// 记录一次可能针对 TOTP 口令的失败：口令校验失败或用连接口令握手失败。
+/// 不是 TOTP 模式时不计数；触发暂停时写入审计
+pub fn record_failure(uuid: &str) {
+    {
+        let mut state = PASSWORD_POLICY.lock().unwrap();
+        if !state.is_totp() || !state.totp_attempts.record_failure() {
+            return;
+        }
+    }
+    println!(
+        "[PASSWORD]TOTP 口令失败次数过多，暂停{}秒",
+        COOLDOWN.as_secs()
+    );
+    audit::record(
+        "totp_burst",
+        audit::Outcome::Denied,
+        None,
+        Some(uuid),
+        &format!(
+            "{}秒内失败{}次，暂停{}秒",
+            FAILURE_WINDOW.as_secs(),
+            MAX_FAILURES,
+            COOLDOWN.as_secs()
+        ),
+    );
+}
+
+/// 拒绝已经用过的时间步，防止截获的口令在有效期内被重放
+fn accept_totp_counter(state: &mut PolicyState, counter: u64) -> bool {
+    if state.last_totp_counter.is_some_and(|last| counter <= last) {
+        println!("[PASSWORD]TOTP 口令已经使用过，拒绝重放");
+        return false;
+    }
+    state.last_totp_counter = Some(counter);
+    true
+}
+
+/**
+ * 连续失败达到上限时是否更换口令。TOTP 的密钥已经录入手机上的验证器，
+ * 换掉需要重新扫码，因此不更换，只靠锁定限制尝试次数
+ */
+pub fn rotates_on_lockout() -> bool {
+    !PASSWORD_POLICY.lock().unwrap().is_totp()
+}
+
+/**
+ * 配对成功，一次性口令随即作废
+ */
+pub async fn on_pairing_success() {
+    let one_time = PASSWORD_POLICY.lock().unwrap().policy.mode == PasswordMode::OneTime;
+    if one_time {
+        println!("[PASSWORD]一次性口令已使用，更换口令");
+        generate_connection_password().await;
+    }
+}
+
+pub fn get_password_policy() -> PasswordPolicyView {
+    let state = PASSWORD_POLICY.lock().unwrap();
+    let policy = state.policy.clone();
+    let (expires_in_secs, totp_uri) = match &policy.mode {
+        PasswordMode::Rotating { interval_secs } => (
+            Some(interval_secs.saturating_sub(state.generated_at.elapsed().as_secs())),
+            None,
+        ),
+        PasswordMode::Totp {
+            secret,
+            step_secs,
+            digits,
+        } => (
+            Some(step_secs - unix_now() % step_secs),
+            Some(format!(
+                "otpauth://totp/LQMY-Desk?secret={}&issuer=LQMY-Desk&algorithm=SHA1&digits={}&period={}",
+                secret, digits, step_secs
+            )),
+        ),
+        _ => (None, None),
+    };
+    PasswordPolicyView {
+        policy,
+        expires_in_secs,
+        totp_uri,
+    }
+}
+
+/**
+ * 更换口令策略并立即按新策略生成口令
+ */
+pub async fn set_password_policy(mut policy: PasswordPolicy) -> Result<(), String> {
+    policy.validate()?;
+    if let PasswordMode::Totp { secret, .. } = &mut policy.mode {
+        if secret.is_empty() {
+            let mut key = [0u8; 20];
+            rand::rng().fill(&mut key);
+            *secret = base32_encode(&key);
+        }
+    }
+    save_policy(&policy)?;
+    println!("[PASSWORD]口令策略已更新");
+    {
+        let mut state = PASSWORD_POLICY.lock().unwrap();
+        state.policy = policy;
+        state.last_totp_counter = None;
+    }
+    generate_connection_password().await;
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn current_totp(policy: &PasswordPolicy) -> Option<String> {
+    match &policy.mode {
+        PasswordMode::Totp {
+            secret,
+            step_secs,
+            digits,
+        } => Some(totp_at(
+            &base32_decode(secret)?,
+            unix_now(),
+            *step_secs,
+            *digits,
+        )),
+        _ => None,
+    }
+}
+
+/// RFC 6238（HMAC-SHA1）在 unix 时间 t 的口令
+pub fn totp_at(key: &[u8], t: u64, step_secs: u64, digits: u32) -> String {
+    let counter = t / step_secs;
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // RFC 4226 动态截取
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        code % 10u32.pow(digits),
+        width = digits as usize
+    )
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32，不带填充，验证器应用通用的密钥格式
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for c in text.trim_end_matches('=').chars() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|b| *b as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    (!out.is_empty()).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_totp_rfc6238_vectors() {
+        let key = b"12345678901234567890";
+        assert_eq!(totp_at(key, 59, 30, 8), "94287082");
+        assert_eq!(totp_at(key, 1111111109, 30, 8), "07081804");
+        assert_eq!(totp_at(key, 1234567890, 30, 8), "89005924");
+        assert_eq!(totp_at(key, 20000000000, 30, 8), "65353130");
+    }
+
+    #[test]
+    fn test_base32_roundtrip() {
+        let key = b"12345678901234567890";
+        let encoded = base32_encode(key);
+        assert_eq!(encoded, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_decode(&encoded).unwrap(), key);
+        assert!(base32_decode("not base32!").is_none());
+    }
+
+    #[test]
+    fn test_generate_with_policy() {
+        let policy = PasswordPolicy {
+            mode: PasswordMode::OneTime,
+            length: 12,
+            alphabet: Alphabet::Custom("ab".to_string()),
+        };
+        assert!(policy.validate().is_ok());
+        let password = generate_password(&policy);
+        assert_eq!(password.len(), 12);
+        assert!(password.chars().all(|c| c == 'a' || c == 'b'));
+
+        let bad = PasswordPolicy {
+            alphabet: Alphabet::Custom("aaa".to_string()),
+            ..PasswordPolicy::default()
+        };
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn test_totp_counter_not_reused() {
+        let mut state = PolicyState {
+            policy: PasswordPolicy::default(),
+            generated_at: Instant::now(),
+            last_totp_counter: None,
+            totp_attempts: AttemptLimiter::new(Arc::new(SystemClock)),
+        };
+        assert!(accept_totp_counter(&mut state, 100));
+        assert!(!accept_totp_counter(&mut state, 100));
+        // 前一个时间步的口令仍在误差窗口内，但已经过时
+        assert!(!accept_totp_counter(&mut state, 99));
+        assert!(accept_totp_counter(&mut state, 101));
+    }
+
+    #[test]
+    fn test_totp_paused_after_burst() {
+        use crate::client_utils::lockout::FakeClock;
+
+        let clock = Arc::new(FakeClock::new());
+        let key = b"12345678901234567890";
+        let mut state = PolicyState {
+            policy: PasswordPolicy {
+                mode: PasswordMode::Totp {
+                    secret: base32_encode(key),
+                    step_secs: 30,
+                    digits: 6,
+                },
+                ..PasswordPolicy::default()
+            },
+            generated_at: Instant::now(),
+            last_totp_counter: None,
+            totp_attempts: AttemptLimiter::new(clock.clone()),
+        };
+        for _ in 0..MAX_FAILURES - 1 {
+            assert!(!state.totp_attempts.record_failure());
+        }
+        assert!(state.totp_attempts.record_failure());
+        assert!(state.totp_blocked().is_some());
+        // 暂停中正确的口令也不通过
+        assert!(!verify_totp(&mut state, &totp_at(key, 1000, 30, 6), 1000));
+
+        clock.advance(COOLDOWN);
+        assert!(state.totp_blocked().is_none());
+        assert!(verify_totp(&mut state, &totp_at(key, 1030, 30, 6), 1030));
+
+        // 换成其他模式后不再受 TOTP 暂停影响
+        for _ in 0..MAX_FAILURES {
+            state.totp_attempts.record_failure();
+        }
+        state.policy = PasswordPolicy::default();
+        assert!(state.totp_blocked().is_none());
+    }
 }
@@ -0,0 +1,278 @@
+//! 权限配置
+//!
+//! 每个设备可以单独设置权限配置（保存在 UserInfo），也可以按分组设置（group_profiles.json）。
+//! 生效顺序：设备自己的配置 > 所属分组配置的并集 > 默认（全部权限、最高画质），
+//! 再受用户类别和连接策略的只读限制。结果写进 JWT，由命令处理和 DataChannel 按它检查。
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+
+use super::user_manager::UserInfo;
+use crate::config::get_group_profiles_path;
+use crate::storage;
+
+/// JWT 授予的权限
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// 建立 WebRTC 连接、观看画面
+    View,
+    /// 申请和使用控制权，发送鼠标键盘输入
+    Control,
+    /// 读写剪贴板
+    Clipboard,
+    /// 传输文件
+    FileTransfer,
+    /// 接收声音
+    Audio,
+}
+
+pub const ALL_PERMISSIONS: [Permission; 5] = [
+    Permission::View,
+    Permission::Control,
+    Permission::Clipboard,
+    Permission::FileTransfer,
+    Permission::Audio,
+];
+
+impl Permission {
+    /// DataChannel 命令需要的权限。默认至少需要 View，只有明确列出的命令不需要权限
+    pub fn required_for(cmd: &str) -> Option<Permission> {
+        match cmd {
+            // 心跳，不涉及会话内容
+            "ping" | "pong" => None,
+            c if c.starts_with("mouse_") || c.starts_with("keyboard_") => Some(Permission::Control),
+            c if c.starts_with("clipboard") => Some(Permission::Clipboard),
+            c if c.starts_with("file_") => Some(Permission::FileTransfer),
+            c if c.starts_with("audio") => Some(Permission::Audio),
+            _ => Some(Permission::View),
+        }
+    }
+}
+
+/// 画质上限，从低到高排列
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    Low,
+    Balanced,
+    #[default]
+    High,
+}
+
+impl Quality {
+    /// 解析 offer 中的 mode，兼容 low_latency / high_quality 的写法
+    pub fn from_mode(mode: &str) -> Quality {
+        match mode {
+            "low" | "low_latency" => Quality::Low,
+            "high" | "high_quality" => Quality::High,
+            _ => Quality::Balanced,
+        }
+    }
+
+    /// 对应 select_mode 使用的 mode
+    pub fn as_mode(&self) -> &'static str {
+        match self {
+            Quality::Low => "low",
+            Quality::Balanced => "balanced",
+            Quality::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionProfile {
+    pub perms: Vec<Permission>,
+    #[serde(default)]
+    pub max_quality: Quality,
+}
+
+impl Default for PermissionProfile {
+    fn default() -> Self {
+        Self {
+            perms: ALL_PERMISSIONS.to_vec(),
+            max_quality: Quality::High,
+        }
+    }
+}
+
+impl PermissionProfile {
+    pub fn has(&self, perm: Permission) -> bool {
+        self.perms.contains(&perm)
+    }
+
+    /// 只保留 allowed 中的权限
+    pub fn restrict(mut self, allowed: &[Permission]) -> Self {
+        self.perms.retain(|perm| allowed.contains(perm));
+        self
+    }
+
+    pub fn cap_quality(mut self, max: Quality) -> Self {
+        self.max_quality = self.max_quality.min(max);
+        self
+    }
+
+    /// 只读：只能观看和听声音
+    pub fn view_only(self) -> Self {
+        self.restrict(&[Permission::View, Permission::Audio])
+    }
+
+    fn union(mut self, other: &PermissionProfile) -> Self {
+        for perm in &other.perms {
+            if !self.has(*perm) {
+                self.perms.push(*perm);
+            }
+        }
+        self.max_quality = self.max_quality.max(other.max_quality);
+        self
+    }
+}
+
+lazy_static! {
+    static ref GROUP_PROFILES: Mutex<HashMap<String, PermissionProfile>> =
+        Mutex::new(load_group_profiles());
+}
+
+fn load_group_profiles() -> HashMap<String, PermissionProfile> {
+    fs::read_to_string(get_group_profiles_path())
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+pub fn get_group_profiles() -> HashMap<String, PermissionProfile> {
+    GROUP_PROFILES.lock().unwrap().clone()
+}
+
+/// 设置分组的权限配置，profile 为 None 时删除
+pub fn set_group_profile(group: &str, profile: Option<PermissionProfile>) -> Result<(), String> {
+    let mut profiles = GROUP_PROFILES.lock().unwrap();
+    let mut updated = profiles.clone();
+    match profile {
+        Some(profile) => updated.insert(group.to_string(), profile),
+        None => updated.remove(group),
+    };
+    let json = serde_json::to_string_pretty(&updated).map_err(|e| e.to_string())?;
+    storage::write_atomic(&get_group_profiles_path(), json.as_bytes())
+        .map_err(|e| format!("保存分组权限失败: {}", e))?;
+    *profiles = updated;
+    Ok(())
+}
+
+/// 按设备配置和分组配置算出权限，未考虑用户类别和连接策略
+pub fn resolve_profile(
+    user: Option<&UserInfo>,
+    groups: &HashMap<String, PermissionProfile>,
+) -> PermissionProfile {
+    let Some(user) = user else {
+        return PermissionProfile::default();
+    };
+    if let Some(profile) = &user.profile {
+        return profile.clone();
+    }
+    user.groups
+        .iter()
+        .filter_map(|group| groups.get(group))
+        .fold(None, |acc: Option<PermissionProfile>, profile| {
+            Some(match acc {
+                Some(acc) => acc.union(profile),
+                None => profile.clone(),
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// 设备当前的权限配置
+pub fn profile_for(user: Option<&UserInfo>) -> PermissionProfile {
+    resolve_profile(user, &GROUP_PROFILES.lock().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_utils::user_manager::UserType;
+
+    fn user(groups: &[&str], profile: Option<PermissionProfile>) -> UserInfo {
+        UserInfo {
+            device_name: "phone".to_string(),
+            device_id: "serial-p".to_string(),
+            user_type: UserType::Normal,
+            public_key: None,
+            fingerprint: None,
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            profile,
+        }
+    }
+
+    #[test]
+    fn test_resolve_profile() {
+        let mut groups = HashMap::new();
+        groups.insert(
+            "support".to_string(),
+            PermissionProfile {
+                perms: vec![Permission::View, Permission::Clipboard],
+                max_quality: Quality::Balanced,
+            },
+        );
+        groups.insert(
+            "audio".to_string(),
+            PermissionProfile {
+                perms: vec![Permission::View, Permission::Audio],
+                max_quality: Quality::Low,
+            },
+        );
+
+        // 没有任何配置时给全部权限
+        assert_eq!(
+            resolve_profile(Some(&user(&[], None)), &groups),
+            PermissionProfile::default()
+        );
+        // 多个分组取并集
+        let merged = resolve_profile(Some(&user(&["audio", "support"], None)), &groups);
+        assert!(merged.has(Permission::Clipboard) && merged.has(Permission::Audio));
+        assert!(!merged.has(Permission::Control));
+        assert_eq!(merged.max_quality, Quality::Balanced);
+        // 设备自己的配置优先
+        let own = PermissionProfile {
+            perms: vec![Permission::View],
+            max_quality: Quality::Low,
+        };
+        assert_eq!(
+            resolve_profile(Some(&user(&["support"], Some(own.clone()))), &groups),
+            own
+        );
+        assert_eq!(
+            PermissionProfile::default().view_only().perms,
+            vec![Permission::View, Permission::Audio]
+        );
+    }
+
+    #[test]
+    fn test_datachannel_permissions_and_quality() {
+        assert_eq!(
+            Permission::required_for("mouse_move"),
+            Some(Permission::Control)
+        );
+        assert_eq!(
+            Permission::required_for("clipboard_set"),
+            Some(Permission::Clipboard)
+        );
+        assert_eq!(
+            Permission::required_for("file_chunk"),
+            Some(Permission::FileTransfer)
+        );
+        assert_eq!(Permission::required_for("ping"), None);
+        // 没有列出的命令至少需要 View
+        assert_eq!(
+            Permission::required_for("unknown_cmd"),
+            Some(Permission::View)
+        );
+        assert_eq!(
+            Quality::from_mode("high_quality").min(Quality::Balanced),
+            Quality::Balanced
+        );
+    }
+}
@@ -0,0 +1,440 @@
+//! 连接策略
+//!
+//! 在审批之前按顺序匹配规则，第一条命中的规则决定结果，都不命中时沿用按用户类别的默认流程。
+//! 规则可以按设备序列号、设备分组、用户类别匹配，限定生效时间段和同时在线的会话数，
+//! 并指定拒绝、免确认、强制确认或只读。规则保存在 policy_rules.json，与 user_data.json 放在一起。
+//!
+//! 例如“外包人员只能在工作日 9–18 点只读访问”：
+//! 先放一条 `groups: ["contractors"]`、工作日 09:00–18:00、`view_only` 的允许规则，
+//! 再放一条 `groups: ["contractors"]` 的拒绝规则。
+
+use chrono::{Datelike, Local, Timelike};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::sync::Mutex;
+
+use super::user_manager::{UserInfo, UserType, USER_LIST};
+use crate::config::{get_policy_rules_path, CURRENT_USERS_INFO};
+use crate::storage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+/// 生效时间段，使用本机时间
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeWindow {
+    /// 星期几，1 为周一、7 为周日，为空表示每天
+    #[serde(default)]
+    pub days: Vec<u32>,
+    /// "HH:MM"，end 早于 start 时表示跨过午夜
+    pub start: String,
+    pub end: String,
+}
+
+impl TimeWindow {
+    fn parse_time(value: &str) -> Result<u32, String> {
+        let (h, m) = value
+            .split_once(':')
+            .ok_or_else(|| format!("时间格式应为 HH:MM: {}", value))?;
+        let (h, m): (u32, u32) = match (h.parse(), m.parse()) {
+            (Ok(h), Ok(m)) if h < 24 && m < 60 => (h, m),
+            _ => return Err(format!("时间格式应为 HH:MM: {}", value)),
+        };
+        Ok(h * 60 + m)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(day) = self.days.iter().find(|d| !(1..=7).contains(*d)) {
+            return Err(format!("星期应在 1 到 7 之间: {}", day));
+        }
+        Self::parse_time(&self.start)?;
+        Self::parse_time(&self.end)?;
+        Ok(())
+    }
+
+    /// weekday 为 1–7，minute 为当天第几分钟
+    fn contains(&self, weekday: u32, minute: u32) -> bool {
+        let (Ok(start), Ok(end)) = (Self::parse_time(&self.start), Self::parse_time(&self.end))
+        else {
+            return false;
+        };
+        if start <= end {
+            let day_ok = self.days.is_empty() || self.days.contains(&weekday);
+            day_ok && (start..end).contains(&minute)
+        } else if minute >= start {
+            self.days.is_empty() || self.days.contains(&weekday)
+        } else if minute < end {
+            // 跨午夜的后半段属于前一天的时间段
+            let yesterday = if weekday == 1 { 7 } else { weekday - 1 };
+            self.days.is_empty() || self.days.contains(&yesterday)
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PolicyRule {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// 匹配的设备序列号，为空表示不限
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// 匹配的设备分组，设备属于其中任一分组即可，为空表示不限
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// 匹配的用户类别，新设备没有类别，只能被不限类别的规则匹配
+    #[serde(default)]
+    pub user_types: Vec<UserType>,
+    /// 只在这些时间段内命中，为空表示任何时间
+    #[serde(default)]
+    pub schedule: Vec<TimeWindow>,
+    pub action: RuleAction,
+    /// 命中此规则的设备最多同时在线几个
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+    /// 为 true 时即使是信任设备也要本机确认；为 false 时口令正确即放行；
+    /// 不填时沿用按用户类别的默认流程
+    #[serde(default)]
+    pub require_approval: Option<bool>,
+    /// 只能观看，不能申请控制
+    #[serde(default)]
+    pub view_only: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// 参与匹配的设备信息
+#[derive(Debug, Clone, Default)]
+pub struct PolicySubject {
+    pub device_serial: String,
+    pub user_type: Option<UserType>,
+    pub groups: Vec<String>,
+}
+
+impl PolicyRule {
+    fn validate(&self) -> Result<(), String> {
+        if self.id.trim().is_empty() {
+            return Err("规则编号不能为空".to_string());
+        }
+        if self.max_sessions == Some(0) {
+            return Err(format!("规则{}的会话上限不能为 0", self.id));
+        }
+        for window in &self.schedule {
+            window.validate()?;
+        }
+        Ok(())
+    }
+
+    fn matches(&self, subject: &PolicySubject) -> bool {
+        (self.devices.is_empty() || self.devices.contains(&subject.device_serial))
+            && (self.groups.is_empty() || self.groups.iter().any(|g| subject.groups.contains(g)))
+            && (self.user_types.is_empty()
+                || subject
+                    .user_type
+                    .as_ref()
+                    .is_some_and(|t| self.user_types.contains(t)))
+    }
+
+    fn in_schedule(&self, weekday: u32, minute: u32) -> bool {
+        self.schedule.is_empty() || self.schedule.iter().any(|w| w.contains(weekday, minute))
+    }
+}
+
+/// 策略判定结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// 没有规则命中，按用户类别的默认流程处理
+    NoMatch,
+    Deny {
+        rule: String,
+        reason: String,
+    },
+    Allow {
+        rule: String,
+        /// None 表示是否确认沿用默认流程
+        require_approval: Option<bool>,
+        view_only: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PolicyEngine {
+    rules: Vec<PolicyRule>,
+}
+
+impl PolicyEngine {
+    pub fn new(rules: Vec<PolicyRule>) -> Result<Self, String> {
+        let mut ids = HashSet::new();
+        for rule in &rules {
+            rule.validate()?;
+            if !ids.insert(rule.id.as_str()) {
+                return Err(format!("规则编号重复: {}", rule.id));
+            }
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn rules(&self) -> &[PolicyRule] {
+        &self.rules
+    }
+
+    /// 按本机当前时间判定
+    pub fn evaluate(&self, subject: &PolicySubject, sessions: &[PolicySubject]) -> Verdict {
+        let now = Local::now();
+        self.evaluate_at(
+            subject,
+            sessions,
+            now.weekday().number_from_monday(),
+            now.hour() * 60 + now.minute(),
+        )
+    }
+
+    /// sessions 为当前在线的其他设备，用于检查会话上限
+    pub fn evaluate_at(
+        &self,
+        subject: &PolicySubject,
+        sessions: &[PolicySubject],
+        weekday: u32,
+        minute: u32,
+    ) -> Verdict {
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|r| r.enabled && r.matches(subject) && r.in_schedule(weekday, minute))
+        else {
+            return Verdict::NoMatch;
+        };
+        match rule.action {
+            RuleAction::Deny => Verdict::Deny {
+                rule: rule.id.clone(),
+                reason: "连接被策略拒绝".to_string(),
+            },
+            RuleAction::Allow => {
+                if let Some(max) = rule.max_sessions {
+                    let active = sessions
+                        .iter()
+                        .filter(|s| s.device_serial != subject.device_serial && rule.matches(s))
+                        .count();
+                    if active >= max {
+                        return Verdict::Deny {
+                            rule: rule.id.clone(),
+                            reason: format!("已达到策略允许的{}个会话上限", max),
+                        };
+                    }
+                }
+                Verdict::Allow {
+                    rule: rule.id.clone(),
+                    require_approval: rule.require_approval,
+                    view_only: rule.view_only,
+                }
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref POLICY: Mutex<PolicyEngine> = Mutex::new(load_rules());
+}
+
+fn load_rules() -> PolicyEngine {
+    fs::read_to_string(get_policy_rules_path())
+        .ok()
+        .and_then(|data| serde_json::from_str::<Vec<PolicyRule>>(&data).ok())
+        .and_then(|rules| PolicyEngine::new(rules).ok())
+        .unwrap_or_default()
+}
+
+pub fn get_rules() -> Vec<PolicyRule> {
+    POLICY.lock().unwrap().rules().to_vec()
+}
+
+/// 整体替换规则列表，顺序即优先级
+pub fn set_rules(rules: Vec<PolicyRule>) -> Result<(), String> {
+    let engine = PolicyEngine::new(rules)?;
+    let json = serde_json::to_string_pretty(engine.rules()).map_err(|e| e.to_string())?;
+    storage::write_atomic(&get_policy_rules_path(), json.as_bytes())
+        .map_err(|e| format!("保存策略失败: {}", e))?;
+    println!("[POLICY]策略更新，共{}条规则", engine.rules().len());
+    *POLICY.lock().unwrap() = engine;
+    Ok(())
+}
+
+impl PolicySubject {
+    pub fn new(device_serial: &str, user: Option<&UserInfo>) -> Self {
+        Self {
+            device_serial: device_serial.to_string(),
+            user_type: user.map(|u| u.user_type.clone()),
+            groups: user.map(|u| u.groups.clone()).unwrap_or_default(),
+        }
+    }
+}
+
+/// 判定一台设备的连接请求，当前在线的设备从 CURRENT_USERS_INFO 取
+pub fn evaluate_device(device_serial: &str, user: Option<&UserInfo>) -> Verdict {
+    let online: Vec<String> = CURRENT_USERS_INFO
+        .lock()
+        .unwrap()
+        .usersinfo
+        .iter()
+        .map(|cur| cur.device_id.clone())
+        .collect();
+    evaluate_device_among(device_serial, user, &online)
+}
+
+/// 按给定的在线设备序列号判定，调用方已经持有 CURRENT_USERS_INFO 时用这个
+pub fn evaluate_device_among(
+    device_serial: &str,
+    user: Option<&UserInfo>,
+    online: &[String],
+) -> Verdict {
+    let subject = PolicySubject::new(device_serial, user);
+    let sessions: Vec<PolicySubject> = {
+        let users = USER_LIST.lock().unwrap();
+        online
+            .iter()
+            .map(|serial| PolicySubject::new(serial, users.get(serial)))
+            .collect()
+    };
+    let verdict = POLICY.lock().unwrap().evaluate(&subject, &sessions);
+    if verdict != Verdict::NoMatch {
+        println!("[POLICY]设备{:?}的判定结果：{:?}", device_serial, verdict);
+    }
+    verdict
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contractor(serial: &str) -> PolicySubject {
+        PolicySubject {
+            device_serial: serial.to_string(),
+            user_type: Some(UserType::Normal),
+            groups: vec!["contractors".to_string()],
+        }
+    }
+
+    fn contractor_rules() -> PolicyEngine {
+        let rules: Vec<PolicyRule> = serde_json::from_value(serde_json::json!([
+            {
+                "id": "contractors-office-hours",
+                "groups": ["contractors"],
+                "schedule": [{ "days": [1, 2, 3, 4, 5], "start": "09:00", "end": "18:00" }],
+                "action": "allow",
+                "max_sessions": 1,
+                "view_only": true
+            },
+            { "id": "contractors-otherwise", "groups": ["contractors"], "action": "deny" }
+        ]))
+        .unwrap();
+        PolicyEngine::new(rules).unwrap()
+    }
+
+    #[test]
+    fn test_contractor_office_hours() {
+        let engine = contractor_rules();
+        let subject = contractor("c1");
+        // 周三 10:00
+        assert_eq!(
+            engine.evaluate_at(&subject, &[], 3, 600),
+            Verdict::Allow {
+                rule: "contractors-office-hours".to_string(),
+                require_approval: None,
+                view_only: true,
+            }
+        );
+        // 周三 19:00、周六 10:00
+        assert!(matches!(
+            engine.evaluate_at(&subject, &[], 3, 19 * 60),
+            Verdict::Deny { rule, .. } if rule == "contractors-otherwise"
+        ));
+        assert!(matches!(
+            engine.evaluate_at(&subject, &[], 6, 600),
+            Verdict::Deny { .. }
+        ));
+        // 已有一个外包设备在线
+        assert!(matches!(
+            engine.evaluate_at(&subject, &[contractor("c2")], 3, 600),
+            Verdict::Deny { rule, .. } if rule == "contractors-office-hours"
+        ));
+        // 其他设备不受影响
+        let other = PolicySubject {
+            device_serial: "d1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(engine.evaluate_at(&other, &[], 3, 600), Verdict::NoMatch);
+    }
+
+    #[test]
+    fn test_require_approval_defaults_to_normal_flow() {
+        let rules: Vec<PolicyRule> = serde_json::from_value(serde_json::json!([
+            { "id": "lab", "devices": ["d1"], "action": "allow" },
+            { "id": "kiosk", "devices": ["d2"], "action": "allow", "require_approval": false }
+        ]))
+        .unwrap();
+        let engine = PolicyEngine::new(rules).unwrap();
+        let subject = |serial: &str| PolicySubject {
+            device_serial: serial.to_string(),
+            ..Default::default()
+        };
+        // 没写 require_approval 的允许规则不会让设备免确认
+        assert!(matches!(
+            engine.evaluate_at(&subject("d1"), &[], 1, 0),
+            Verdict::Allow {
+                require_approval: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            engine.evaluate_at(&subject("d2"), &[], 1, 0),
+            Verdict::Allow {
+                require_approval: Some(false),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_overnight_window_and_validation() {
+        let window = TimeWindow {
+            days: vec![5],
+            start: "22:00".to_string(),
+            end: "06:00".to_string(),
+        };
+        assert!(window.contains(5, 23 * 60));
+        assert!(window.contains(6, 60));
+        assert!(!window.contains(1, 60));
+
+        let rule = |id: &str, start: &str| PolicyRule {
+            id: id.to_string(),
+            name: String::new(),
+            enabled: true,
+            devices: Vec::new(),
+            groups: Vec::new(),
+            user_types: Vec::new(),
+            schedule: vec![TimeWindow {
+                days: Vec::new(),
+                start: start.to_string(),
+                end: "18:00".to_string(),
+            }],
+            action: RuleAction::Deny,
+            max_sessions: None,
+            require_approval: None,
+            view_only: false,
+        };
+        assert!(PolicyEngine::new(vec![rule("a", "25:00")]).is_err());
+        assert!(PolicyEngine::new(vec![rule("a", "09:00"), rule("a", "10:00")]).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::events::{emit, ServerEvent};
+
+/// 指数退避，带随机抖动，避免大量主机同时重连
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// 已经尝试的次数
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// 下一次重连前的等待时间：base * 2^attempt，封顶 max，再取 [1/2, 1] 之间的随机比例
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = self.attempt.min(16);
+        self.attempt += 1;
+        let ceiling = self.base.saturating_mul(1 << exp).min(self.max);
+        let ms = ceiling.as_millis() as u64;
+        let jittered = rand::rng().random_range(ms / 2..=ms);
+        Duration::from_millis(jittered)
+    }
+
+    /// 连接成功后清零
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+/// 提供给前端的重连状态
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconnectStatus {
+    pub connected: bool,
+    pub attempt: u32,
+    pub next_retry_ms: u64,
+    pub last_error: String,
+}
+
+lazy_static! {
+    pub static ref RECONNECT_STATUS: Mutex<ReconnectStatus> =
+        Mutex::new(ReconnectStatus::default());
+}
+
+pub fn mark_connected() {
+    let mut status = RECONNECT_STATUS.lock().unwrap();
+    *status = ReconnectStatus {
+        connected: true,
+        ..Default::default()
+    };
+}
+
+pub fn mark_retrying(attempt: u32, delay: Duration, error: &str) {
+    let mut status = RECONNECT_STATUS.lock().unwrap();
+    status.connected = false;
+    status.attempt = attempt;
+    status.next_retry_ms = delay.as_millis() as u64;
+    status.last_error = error.to_string();
+    println!(
+        "[RECONNECT]第{}次重连将在{:?}后进行，原因：{}",
+        attempt, delay, error
+    );
+    emit(ServerEvent::Reconnecting {
+        attempt,
+        next_retry_ms: status.next_retry_ms,
+        last_error: status.last_error.clone(),
+    });
+}
+
+pub fn reset_status() {
+    *RECONNECT_STATUS.lock().unwrap() = ReconnectStatus::default();
+}
+
+pub fn get_status() -> ReconnectStatus {
+    RECONNECT_STATUS.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+        let d0 = backoff.next_delay();
+        assert!(d0 >= Duration::from_millis(50) && d0 <= Duration::from_millis(100));
+        let d1 = backoff.next_delay();
+        assert!(d1 >= Duration::from_millis(100) && d1 <= Duration::from_millis(200));
+        for _ in 0..20 {
+            assert!(backoff.next_delay() <= Duration::from_millis(1000));
+        }
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,137 @@
+use serde::Serialize;
+
+/// 一个中转服务器地址及其健康状况
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayEndpoint {
+    pub url: String,
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// 上次注册成功的时间戳（秒）
+    pub last_connected: Option<i64>,
+}
+
+impl RelayEndpoint {
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            healthy: true,
+            consecutive_failures: 0,
+            last_error: None,
+            last_connected: None,
+        }
+    }
+}
+
+/// 按优先级排列的中转服务器列表，当前使用的失败后切换到下一个健康的
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayPool {
+    pub endpoints: Vec<RelayEndpoint>,
+    pub active: usize,
+}
+
+impl RelayPool {
+    pub fn new(urls: Vec<String>) -> Self {
+        let mut pool = Self {
+            endpoints: Vec::new(),
+            active: 0,
+        };
+        pool.set_endpoints(urls);
+        pool
+    }
+
+    /// 从逗号分隔的地址列表创建，如环境变量 SERVER_ADDRESS
+    pub fn from_list(list: &str) -> Self {
+        Self::new(list.split(',').map(str::to_string).collect())
+    }
+
+    /// 替换地址列表，已有地址保留健康记录，重新从第一个开始使用
+    pub fn set_endpoints(&mut self, urls: Vec<String>) {
+        let mut endpoints = Vec::new();
+        for url in urls {
+            let url = url.trim().to_string();
+            if url.is_empty() || endpoints.iter().any(|e: &RelayEndpoint| e.url == url) {
+                continue;
+            }
+            let existing = self.endpoints.iter().find(|e| e.url == url).cloned();
+            endpoints.push(existing.unwrap_or_else(|| RelayEndpoint::new(url)));
+        }
+        self.endpoints = endpoints;
+        self.active = 0;
+    }
+
+    /// 当前使用的地址
+    pub fn active_url(&self) -> Option<String> {
+        self.endpoints.get(self.active).map(|e| e.url.clone())
+    }
+
+    /// 当前地址注册成功
+    pub fn mark_success(&mut self) {
+        if let Some(endpoint) = self.endpoints.get_mut(self.active) {
+            endpoint.healthy = true;
+            endpoint.consecutive_failures = 0;
+            endpoint.last_error = None;
+            endpoint.last_connected = Some(chrono::Utc::now().timestamp());
+        }
+    }
+
+    /// 当前地址连接或心跳失败，切换到下一个地址。
+    /// 返回 true 表示切到了一个仍然健康的地址，可以立即重试；否则说明都失败过，应退避后再试
+    pub fn mark_failure(&mut self, reason: &str) -> bool {
+        let len = self.endpoints.len();
+        if len == 0 {
+            return false;
+        }
+        if let Some(endpoint) = self.endpoints.get_mut(self.active) {
+            endpoint.healthy = false;
+            endpoint.consecutive_failures += 1;
+            endpoint.last_error = Some(reason.to_string());
+        }
+        for step in 1..=len {
+            let idx = (self.active + step) % len;
+            if self.endpoints[idx].healthy {
+                self.active = idx;
+                return true;
+            }
+        }
+        // 全都失败过，按顺序轮换
+        self.active = (self.active + 1) % len;
+        false
+    }
+
+    /// 重新开始时从最优先的地址连起
+    pub fn reset(&mut self) {
+        self.active = 0;
+        for endpoint in self.endpoints.iter_mut() {
+            endpoint.healthy = true;
+            endpoint.consecutive_failures = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failover_order() {
+        let mut pool = RelayPool::from_list("wss://a, wss://b,wss://c,wss://a");
+        assert_eq!(pool.endpoints.len(), 3);
+        assert_eq!(pool.active_url().unwrap(), "wss://a");
+
+        assert!(pool.mark_failure("timeout"));
+        assert_eq!(pool.active_url().unwrap(), "wss://b");
+        pool.mark_success();
+
+        assert!(pool.mark_failure("pong"));
+        assert_eq!(pool.active_url().unwrap(), "wss://c");
+        assert!(!pool.mark_failure("refused"));
+        // 三个都失败过，按顺序轮换回 a
+        assert_eq!(pool.active_url().unwrap(), "wss://a");
+        assert_eq!(pool.endpoints[1].consecutive_failures, 1);
+
+        pool.reset();
+        assert!(pool.endpoints.iter().all(|e| e.healthy));
+        assert_eq!(pool.active_url().unwrap(), "wss://a");
+    }
+}
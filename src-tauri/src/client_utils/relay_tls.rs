@@ -0,0 +1,275 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use lazy_static::lazy_static;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// 连接中转服务器的 TLS 设置
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayTlsConfig {
+    /// 额外信任的根证书（PEM 文件），用于私有 CA 签发的中转服务器
+    #[serde(default)]
+    pub extra_ca_files: Vec<PathBuf>,
+    /// 证书公钥固定，格式为 `sha256/<base64>`，证书链中任一证书匹配即可
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+    /// 双向 TLS 的客户端证书与私钥（PEM 文件）
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+}
+
+impl RelayTlsConfig {
+    /// 从环境变量读取：RELAY_CA_FILES、RELAY_SPKI_PINS（分号分隔）、RELAY_CLIENT_CERT、RELAY_CLIENT_KEY
+    fn from_env() -> Self {
+        let list = |name: &str| -> Vec<String> {
+            std::env::var(name)
+                .map(|v| {
+                    v.split(';')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        Self {
+            extra_ca_files: list("RELAY_CA_FILES")
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            spki_pins: list("RELAY_SPKI_PINS"),
+            client_cert: std::env::var("RELAY_CLIENT_CERT").ok().map(PathBuf::from),
+            client_key: std::env::var("RELAY_CLIENT_KEY").ok().map(PathBuf::from),
+        }
+    }
+
+    /// 是否都是默认值，此时直接使用 awc 的默认配置
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+lazy_static! {
+    pub static ref RELAY_TLS: Mutex<RelayTlsConfig> = Mutex::new(RelayTlsConfig::from_env());
+}
+
+pub fn get_relay_tls() -> RelayTlsConfig {
+    RELAY_TLS.lock().unwrap().clone()
+}
+
+/// 校验后保存，文件不存在或格式错误时返回错误且不生效
+pub fn set_relay_tls(config: RelayTlsConfig) -> Result<(), String> {
+    build_client_config(&config)?;
+    println!("[RELAY_TLS]TLS 设置更新为{:?}", config);
+    *RELAY_TLS.lock().unwrap() = config;
+    Ok(())
+}
+
+/// 当前设置对应的 rustls 配置，默认设置返回 None
+pub fn client_config() -> Result<Option<Arc<ClientConfig>>, String> {
+    let config = get_relay_tls();
+    if config.is_default() {
+        return Ok(None);
+    }
+    build_client_config(&config).map(|c| Some(Arc::new(c)))
+}
+
+pub fn build_client_config(config: &RelayTlsConfig) -> Result<ClientConfig, String> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    for path in &config.extra_ca_files {
+        for cert in read_certs(path)? {
+            roots
+                .add(&cert)
+                .map_err(|e| format!("无法信任{:?}中的证书: {}", path, e))?;
+        }
+    }
+    let pins = config
+        .spki_pins
+        .iter()
+        .map(|pin| parse_pin(pin))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pins,
+        }));
+    let mut tls = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+            .map_err(|e| format!("客户端证书无效: {}", e))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("客户端证书与私钥需要同时设置".to_string()),
+    };
+    // websocket 只走 HTTP/1.1
+    tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(tls)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let pem = fs::read(path).map_err(|e| format!("读取{:?}失败: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .map_err(|e| format!("解析{:?}失败: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{:?}中没有证书", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, String> {
+    let pem = fs::read(path).map_err(|e| format!("读取{:?}失败: {}", path, e))?;
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut pem.as_slice())
+        .map_err(|e| format!("解析{:?}失败: {}", path, e))?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut pem.as_slice())
+            .map_err(|e| format!("解析{:?}失败: {}", path, e))?;
+    }
+    keys.into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| format!("{:?}中没有私钥", path))
+}
+
+/// 解析 `sha256/<base64>` 形式的固定值
+fn parse_pin(pin: &str) -> Result<[u8; 32], String> {
+    let b64 = pin
+        .strip_prefix("sha256/")
+        .ok_or_else(|| format!("公钥固定值需要以 sha256/ 开头: {}", pin))?;
+    let raw = BASE64
+        .decode(b64)
+        .map_err(|e| format!("公钥固定值不是有效的 base64: {}", e))?;
+    raw.try_into()
+        .map_err(|_| format!("公钥固定值长度不是 32 字节: {}", pin))
+}
+
+/// 证书 SubjectPublicKeyInfo 的 SHA-256
+pub fn spki_sha256(cert: &Certificate) -> Option<[u8; 32]> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    Some(Sha256::digest(parsed.tbs_certificate.subject_pki.raw).into())
+}
+
+/// 在常规证书链校验之后再检查公钥固定
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        if self.pins.is_empty() {
+            return Ok(verified);
+        }
+        let matched = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(spki_sha256)
+            .any(|hash| self.pins.contains(&hash));
+        if matched {
+            Ok(verified)
+        } else {
+            println!("[RELAY_TLS]中转服务器证书公钥与固定值不符");
+            Err(rustls::Error::General("SPKI pin mismatch".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert_path() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("cert.pem")
+    }
+
+    #[test]
+    fn test_extra_ca_and_pin() {
+        let cert = read_certs(&cert_path()).unwrap().remove(0);
+        let pin = format!("sha256/{}", BASE64.encode(spki_sha256(&cert).unwrap()));
+        assert_eq!(parse_pin(&pin).unwrap(), spki_sha256(&cert).unwrap());
+
+        let config = RelayTlsConfig {
+            extra_ca_files: vec![cert_path()],
+            spki_pins: vec![pin],
+            ..Default::default()
+        };
+        let tls = build_client_config(&config).unwrap();
+        assert_eq!(tls.alpn_protocols, vec![b"http/1.1".to_vec()]);
+    }
+
+    fn verify_with_pins(cert: &Certificate, pins: Vec<[u8; 32]>) -> Result<(), rustls::Error> {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let verifier = PinnedVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pins,
+        };
+        let name = ServerName::try_from("localhost").unwrap();
+        verifier
+            .verify_server_cert(
+                cert,
+                &[],
+                &name,
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    #[test]
+    fn test_pin_mismatch_rejected() {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = Certificate(generated.serialize_der().unwrap());
+        // 证书链本身可信，只有固定值决定结果
+        assert!(verify_with_pins(&cert, vec![spki_sha256(&cert).unwrap()]).is_ok());
+        assert_eq!(
+            verify_with_pins(&cert, vec![[0u8; 32]]),
+            Err(rustls::Error::General("SPKI pin mismatch".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let config = RelayTlsConfig {
+            spki_pins: vec!["md5/abc".to_string()],
+            ..Default::default()
+        };
+        assert!(build_client_config(&config).is_err());
+        let config = RelayTlsConfig {
+            client_cert: Some(cert_path()),
+            ..Default::default()
+        };
+        assert!(build_client_config(&config).is_err());
+    }
+}
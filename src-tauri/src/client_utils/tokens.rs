@@ -0,0 +1,171 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::permissions::Permission;
+use super::user_manager::UserType;
+
+/// 按用户类别设置的 token 有效期（秒）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenLifetimes {
+    pub trusted_secs: u64,
+    pub normal_secs: u64,
+}
+
+impl Default for TokenLifetimes {
+    fn default() -> Self {
+        Self {
+            trusted_secs: 8 * 3600,
+            normal_secs: 3600,
+        }
+    }
+}
+
+impl TokenLifetimes {
+    pub fn for_role(&self, role: &UserType) -> u64 {
+        match role {
+            UserType::Trusted => self.trusted_secs,
+            UserType::Normal => self.normal_secs,
+            UserType::Blacklist => 0,
+        }
+    }
+}
+
+/// 本次启动签发过、尚未撤销的 token
+#[derive(Debug)]
+struct IssuedToken {
+    device_serial: String,
+    /// 手机端 uuid，DataChannel 按它找到会话当前的权限
+    uuid: String,
+    perms: Vec<Permission>,
+    exp: usize,
+    /// 签发顺序，同一会话有多个有效 token 时以最新的为准
+    seq: u64,
+}
+
+/// 已签发 token 的登记表，只有登记在册的 jti 才算有效
+#[derive(Debug, Default)]
+pub struct TokenRegistry {
+    issued: HashMap<String, IssuedToken>,
+    next_seq: u64,
+}
+
+impl TokenRegistry {
+    pub fn record(
+        &mut self,
+        jti: &str,
+        device_serial: &str,
+        uuid: &str,
+        perms: &[Permission],
+        exp: usize,
+    ) {
+        self.next_seq += 1;
+        self.issued.insert(
+            jti.to_string(),
+            IssuedToken {
+                device_serial: device_serial.to_string(),
+                uuid: uuid.to_string(),
+                perms: perms.to_vec(),
+                exp,
+                seq: self.next_seq,
+            },
+        );
+    }
+
+    /// uuid 对应会话最新一个未过期、未撤销的 token 的权限，没有时返回 None
+    pub fn permissions_for(&self, uuid: &str, now: usize) -> Option<Vec<Permission>> {
+        self.issued
+            .values()
+            .filter(|t| t.uuid == uuid && t.exp > now)
+            .max_by_key(|t| t.seq)
+            .map(|t| t.perms.clone())
+    }
+
+    pub fn is_active(&self, jti: &str) -> bool {
+        self.issued.contains_key(jti)
+    }
+
+    /// 撤销单个 token，例如刷新后作废旧 token
+    pub fn revoke(&mut self, jti: &str) -> bool {
+        self.issued.remove(jti).is_some()
+    }
+
+    /// 撤销某台设备的全部 token，返回撤销的数量
+    pub fn revoke_device(&mut self, device_serial: &str) -> usize {
+        let before = self.issued.len();
+        self.issued.retain(|_, t| t.device_serial != device_serial);
+        before - self.issued.len()
+    }
+
+    /// 清理已经过期的记录
+    pub fn prune(&mut self, now: usize) {
+        self.issued.retain(|_, t| t.exp > now);
+    }
+}
+
+lazy_static! {
+    pub static ref TOKEN_LIFETIMES: Mutex<TokenLifetimes> = Mutex::new(TokenLifetimes::default());
+    pub static ref TOKEN_REGISTRY: Mutex<TokenRegistry> = Mutex::new(TokenRegistry::default());
+}
+
+pub fn get_lifetimes() -> TokenLifetimes {
+    *TOKEN_LIFETIMES.lock().unwrap()
+}
+
+pub fn set_lifetimes(lifetimes: TokenLifetimes) -> Result<(), String> {
+    if lifetimes.trusted_secs == 0 || lifetimes.normal_secs == 0 {
+        return Err("有效期必须大于 0".to_string());
+    }
+    println!("[TOKEN]有效期更新为{:?}", lifetimes);
+    *TOKEN_LIFETIMES.lock().unwrap() = lifetimes;
+    Ok(())
+}
+
+/// 踢出或拉黑设备时调用，已发出的 token 立即失效
+pub fn revoke_device(device_serial: &str) {
+    let count = TOKEN_REGISTRY.lock().unwrap().revoke_device(device_serial);
+    if count > 0 {
+        println!("[TOKEN]撤销设备{:?}的{}个token", device_serial, count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_revoke_and_prune() {
+        let mut registry = TokenRegistry::default();
+        registry.record("a", "serial-1", "phone-1", &[], 100);
+        registry.record("b", "serial-1", "phone-1", &[], 200);
+        registry.record("c", "serial-2", "phone-2", &[], 300);
+
+        assert!(registry.revoke("a"));
+        assert!(!registry.is_active("a"));
+        assert_eq!(registry.revoke_device("serial-1"), 1);
+        assert!(!registry.is_active("b"));
+
+        registry.prune(300);
+        assert!(!registry.is_active("c"));
+    }
+
+    #[test]
+    fn test_permissions_follow_latest_token() {
+        let mut registry = TokenRegistry::default();
+        let all = [Permission::View, Permission::Control];
+        registry.record("old", "serial-1", "phone-1", &all, 500);
+        assert_eq!(registry.permissions_for("phone-1", 0), Some(all.to_vec()));
+        // 刷新后权限被收窄，以新 token 为准
+        registry.record("new", "serial-1", "phone-1", &[Permission::View], 300);
+        assert_eq!(
+            registry.permissions_for("phone-1", 0),
+            Some(vec![Permission::View])
+        );
+        assert_eq!(registry.permissions_for("phone-1", 300), Some(all.to_vec()));
+        // 撤销后不再有权限
+        registry.revoke_device("serial-1");
+        assert_eq!(registry.permissions_for("phone-1", 0), None);
+        assert_eq!(registry.permissions_for("phone-2", 0), None);
+    }
+}
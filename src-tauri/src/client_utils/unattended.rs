@@ -0,0 +1,333 @@
+//! 无人值守模式
+//!
+//! 管理员设置一个固定口令，只保存 argon2 加盐哈希（unattended.json），不保存明文。
+//! 另外保存一份固定盐的派生值，用作端到端加密握手的秘密（见 [`crate::client_utils::e2e`]），
+//! 拿到它只能建立加密通道，登录仍要校验加盐哈希。
+//! 开启后，用固定口令连接的设备不需要本机确认，随后的会话强制写入审计日志；
+//! 可选在屏幕上显示正在被远程访问的提示。临时连接口令的流程不变，仍需确认。
+//!
+//! 固定口令不会自动更换，按来源锁定挡不住换着序列号和 uuid 的枚举，
+//! 所以另有一个全局的失败计数：窗口内失败过多时暂停固定口令一段时间，并写入审计。
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audit;
+use crate::client_utils::e2e::unattended_secret;
+use crate::client_utils::lockout::{Clock, SystemClock};
+use crate::config::get_unattended_path;
+use crate::storage;
+
+/// 固定口令的最短长度
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// 统计失败次数的时间窗口
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(600);
+
+/// 窗口内所有来源累计失败多少次后暂停固定口令
+pub const MAX_FAILURES: usize = 20;
+
+/// 暂停时长
+pub const COOLDOWN: Duration = Duration::from_secs(900);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UnattendedSettings {
+    pub enabled: bool,
+    /// argon2 PHC 格式的口令哈希
+    #[serde(default)]
+    password_hash: Option<String>,
+    /// 端到端加密握手用的派生值，旧版本保存的设置没有，需要重新设置口令
+    #[serde(default)]
+    pake_secret: Option<String>,
+    /// 会话进行中时在屏幕上显示提示
+    #[serde(default)]
+    pub show_indicator: bool,
+}
+
+/// 给前端看的设置，不包含哈希
+#[derive(Debug, Clone, Serialize)]
+pub struct UnattendedView {
+    pub enabled: bool,
+    pub has_password: bool,
+    pub show_indicator: bool,
+}
+
+impl UnattendedSettings {
+    pub fn view(&self) -> UnattendedView {
+        UnattendedView {
+            enabled: self.enabled,
+            has_password: self.password_hash.is_some(),
+            show_indicator: self.show_indicator,
+        }
+    }
+
+    pub fn set_password(&mut self, password: &str) -> Result<(), String> {
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(format!("固定口令至少需要{}位", MIN_PASSWORD_LEN));
+        }
+        let mut salt = [0u8; 16];
+        rand::rng().fill(&mut salt);
+        let salt = SaltString::encode_b64(&salt).map_err(|e| e.to_string())?;
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| format!("口令哈希失败: {}", e))?;
+        self.password_hash = Some(hash.to_string());
+        self.pake_secret = Some(unattended_secret(password)?);
+        Ok(())
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool, show_indicator: bool) -> Result<(), String> {
+        if enabled && self.password_hash.is_none() {
+            return Err("请先设置固定口令".to_string());
+        }
+        self.enabled = enabled;
+        self.show_indicator = show_indicator;
+        Ok(())
+    }
+
+    /// 模式关闭或没有设置口令时总是返回 false
+    pub fn verify(&self, password: &str) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let Some(hash) = &self.password_hash else {
+            return false;
+        };
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return false;
+        };
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    }
+}
+
+/// 所有来源共用的失败计数，固定口令和 TOTP 口令各用一个
+pub struct AttemptLimiter {
+    clock: Arc<dyn Clock>,
+    failures: VecDeque<Instant>,
+    blocked_until: Option<Instant>,
+}
+
+impl AttemptLimiter {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            failures: VecDeque::new(),
+            blocked_until: None,
+        }
+    }
+
+    /// 暂停中时返回剩余时间
+    pub fn blocked(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        self.blocked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// 记一次失败，本次失败触发暂停时返回 true
+    pub fn record_failure(&mut self) -> bool {
+        let now = self.clock.now();
+        while self
+            .failures
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= FAILURE_WINDOW)
+        {
+            self.failures.pop_front();
+        }
+        self.failures.push_back(now);
+        if self.failures.len() < MAX_FAILURES || self.blocked().is_some() {
+            return false;
+        }
+        self.failures.clear();
+        self.blocked_until = Some(now + COOLDOWN);
+        true
+    }
+}
+
+lazy_static! {
+    static ref UNATTENDED: Mutex<UnattendedSettings> = Mutex::new(load_settings());
+    static ref ATTEMPTS: Mutex<AttemptLimiter> =
+        Mutex::new(AttemptLimiter::new(Arc::new(SystemClock)));
+}
+
+fn load_settings() -> UnattendedSettings {
+    fs::read_to_string(get_unattended_path())
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+/// 文件里有口令哈希和握手秘密，只允许当前用户读写
+fn save_settings(settings: &UnattendedSettings) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+    storage::write_private(&get_unattended_path(), json.as_bytes())
+        .map_err(|e| format!("保存无人值守设置失败：{}", e))
+}
+
+pub fn is_enabled() -> bool {
+    UNATTENDED.lock().unwrap().enabled
+}
+
+pub fn get_settings() -> UnattendedView {
+    UNATTENDED.lock().unwrap().view()
+}
+
+pub fn set_password(password: &str) -> Result<(), String> {
+    {
+        let mut settings = UNATTENDED.lock().unwrap();
+        let mut updated = settings.clone();
+        updated.set_password(password)?;
+        save_settings(&updated)?;
+        *settings = updated;
+    }
+    println!("[UNATTENDED]固定口令已更新");
+    audit::record(
+        "unattended_password_changed",
+        audit::Outcome::Success,
+        None,
+        None,
+        "",
+    );
+    Ok(())
+}
+
+pub fn set_enabled(enabled: bool, show_indicator: bool) -> Result<(), String> {
+    let mut updated = UNATTENDED.lock().unwrap().clone();
+    let was_enabled = updated.enabled;
+    updated.set_enabled(enabled, show_indicator)?;
+    save_settings(&updated)?;
+    if was_enabled && !enabled {
+        // 关闭后审计不再强制开启，保存成功后、生效前先记下
+        audit::record(
+            "unattended_disabled",
+            audit::Outcome::Success,
+            None,
+            None,
+            "",
+        );
+    }
+    *UNATTENDED.lock().unwrap() = updated;
+    println!("[UNATTENDED]无人值守模式：{}", enabled);
+    if enabled {
+        audit::record(
+            "unattended_enabled",
+            audit::Outcome::Success,
+            None,
+            None,
+            "",
+        );
+    }
+    Ok(())
+}
+
+/// 端到端加密握手用的秘密，模式关闭或暂停中时没有
+pub fn pake_secret() -> Option<String> {
+    if ATTEMPTS.lock().unwrap().blocked().is_some() {
+        return None;
+    }
+    let settings = UNATTENDED.lock().unwrap();
+    settings.pake_secret.clone().filter(|_| settings.enabled)
+}
+
+/// 记录一次可能针对固定口令的失败：口令校验失败或用固定口令握手失败。
+/// 模式关闭时不计数；触发暂停时写入审计
+pub fn record_failure(uuid: &str) {
+    if !is_enabled() || !ATTEMPTS.lock().unwrap().record_failure() {
+        return;
+    }
+    println!(
+        "[UNATTENDED]固定口令失败次数过多，暂停{}秒",
+        COOLDOWN.as_secs()
+    );
+    audit::record(
+        "unattended_burst",
+        audit::Outcome::Denied,
+        None,
+        Some(uuid),
+        &format!(
+            "{}秒内失败{}次，暂停{}秒",
+            FAILURE_WINDOW.as_secs(),
+            MAX_FAILURES,
+            COOLDOWN.as_secs()
+        ),
+    );
+}
+
+/// 校验固定口令。argon2 比较慢，放到阻塞线程里算；暂停中一律不通过
+pub async fn verify_password(password: &str) -> bool {
+    let settings = UNATTENDED.lock().unwrap().clone();
+    if !settings.enabled {
+        return false;
+    }
+    if let Some(remaining) = ATTEMPTS.lock().unwrap().blocked() {
+        println!("[UNATTENDED]固定口令暂停中，剩余{:?}", remaining);
+        return false;
+    }
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || settings.verify(&password))
+        .await
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hash_and_enable() {
+        let mut settings = UnattendedSettings::default();
+        assert!(settings.set_enabled(true, false).is_err());
+        assert!(settings.set_password("short").is_err());
+
+        settings.set_password("lab-machine-01").unwrap();
+        let hash = settings.password_hash.clone().unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(!hash.contains("lab-machine-01"));
+        assert_eq!(
+            settings.pake_secret,
+            Some(unattended_secret("lab-machine-01").unwrap())
+        );
+        // 未开启时固定口令不可用
+        assert!(!settings.verify("lab-machine-01"));
+
+        settings.set_enabled(true, true).unwrap();
+        assert!(settings.verify("lab-machine-01"));
+        assert!(!settings.verify("lab-machine-02"));
+        assert!(settings.view().has_password);
+    }
+
+    #[test]
+    fn test_burst_pauses_unattended() {
+        use crate::client_utils::lockout::FakeClock;
+
+        let clock = Arc::new(FakeClock::new());
+        let mut limiter = AttemptLimiter::new(clock.clone());
+        // 窗口外的失败不累计
+        for _ in 0..MAX_FAILURES - 1 {
+            assert!(!limiter.record_failure());
+        }
+        clock.advance(FAILURE_WINDOW);
+        assert!(!limiter.record_failure());
+        assert!(limiter.blocked().is_none());
+
+        for _ in 0..MAX_FAILURES - 2 {
+            assert!(!limiter.record_failure());
+        }
+        assert!(limiter.record_failure());
+        assert!(limiter.blocked().is_some());
+        // 暂停中继续失败不会重复触发
+        assert!(!limiter.record_failure());
+
+        clock.advance(COOLDOWN);
+        assert!(limiter.blocked().is_none());
+    }
+}
@@ -1,12 +1,21 @@
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
+use crate::audit;
 use crate::config::get_userinfo_path;
+use crate::storage::{self, StorageError};
 
-use super::dialog::show_confirmation_dialog;
+use super::credentials::revoke_device_credentials;
+use super::device_key::fingerprint;
+use super::permissions::PermissionProfile;
+use super::tokens::revoke_device;
 use std::collections::HashMap;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
+use thiserror::Error;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -22,31 +31,177 @@ pub struct UserInfo {
     pub device_id: String,
     //hashed_password: String, //unnecessary
     pub user_type: UserType,
+    /// 首次配对时绑定的 Ed25519 公钥（base64）
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// 公钥指纹，供界面展示和核对
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// 设备分组，供连接策略和分组权限匹配
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// 设备自己的权限配置，为 None 时按分组或默认
+    #[serde(default)]
+    pub profile: Option<PermissionProfile>,
 }
 
 // 全局存储所有用户信息，启动服务时会从json文件读取，运行时实时更新改变量和本地消息
 
 lazy_static! {
     pub static ref USER_LIST: Mutex<HashMap<String, UserInfo>> = Mutex::new(load_devices());
+    // 保存时从取快照到写完一直持有，先取的旧快照不会覆盖后取的新快照
+    static ref SAVE_LOCK: Mutex<()> = Mutex::new(());
 }
 
-/// 读取本地存储的设备信息
+/// 启动时 user_data.json 存在但读不了、备份失败或者是更新版本的程序写的，
+/// 为了不用空列表覆盖它，本次运行不再保存
+static SAVE_BLOCKED: AtomicBool = AtomicBool::new(false);
+
+/// user_data.json 的格式版本，格式变化时递增并在 migrate_devices 中补上迁移
+pub const DEVICES_SCHEMA_VERSION: u32 = 2;
+
+/// user_data.json 的内容
+#[derive(Debug, Serialize, Deserialize)]
+struct DevicesFile {
+    version: u32,
+    devices: HashMap<String, UserInfo>,
+}
+
+/// 设备列表解析失败的原因
+#[derive(Debug, Error, PartialEq, Eq)]
+enum ParseError {
+    /// 更新版本的程序写的文件，本程序读不懂，但也不能覆盖
+    #[error("文件版本{0}比当前程序支持的版本新")]
+    NewerVersion(u32),
+
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// 解析设备列表，旧版本的格式逐级迁移到当前版本
+fn parse_devices(data: &str) -> Result<HashMap<String, UserInfo>, ParseError> {
+    let value: serde_json::Value =
+        serde_json::from_str(data).map_err(|e| ParseError::Invalid(e.to_string()))?;
+    // 版本 1 没有外层结构，整个文件就是序列号到设备信息的映射
+    let mut version = value
+        .get("version")
+        .and_then(serde_json::Value::as_u64)
+        .map_or(1, |v| v as u32);
+    if version > DEVICES_SCHEMA_VERSION {
+        return Err(ParseError::NewerVersion(version));
+    }
+    let mut value = value;
+    while version < DEVICES_SCHEMA_VERSION {
+        value = migrate_devices(version, value);
+        version += 1;
+    }
+    let file: DevicesFile =
+        serde_json::from_value(value).map_err(|e| ParseError::Invalid(e.to_string()))?;
+    Ok(file.devices)
+}
+
+/// 从 version 迁移到 version + 1
+fn migrate_devices(version: u32, value: serde_json::Value) -> serde_json::Value {
+    match version {
+        1 => serde_json::json!({ "version": 2, "devices": value }),
+        _ => value,
+    }
+}
+
+/// 读取本地存储的设备信息，不能保存时置上 SAVE_BLOCKED
 fn load_devices() -> HashMap<String, UserInfo> {
-    if let Ok(data) = fs::read_to_string(get_userinfo_path()) {
-        println!("[USER_LIST:成功从{:?}读取用户信息]", get_userinfo_path());
-        serde_json::from_str(&data).unwrap_or_else(|_| HashMap::new())
-    } else {
-        println!("[USER_LIST:路径下没有json文件,用户信息表初始化为空]");
-        HashMap::new()
+    let (devices, save_blocked) =
+        load_devices_from(&get_userinfo_path(), storage::backup_unreadable);
+    SAVE_BLOCKED.store(save_blocked, Ordering::Relaxed);
+    devices
+}
+
+/// 从 path 读取设备信息，返回设备列表和是否要暂停保存。
+/// 文件损坏时用 backup 备份后从空列表开始；读不了、备份失败或版本更新时保持原样并暂停保存。
+/// 这几种情况都会通知界面
+fn load_devices_from(
+    path: &Path,
+    backup: impl Fn(&Path) -> io::Result<PathBuf>,
+) -> (HashMap<String, UserInfo>, bool) {
+    let report = |reason: String, backup: Option<String>| {
+        storage::report(StorageError {
+            file: path.display().to_string(),
+            reason,
+            backup,
+        })
+    };
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("[USER_LIST:路径下没有json文件,用户信息表初始化为空]");
+            return (HashMap::new(), false);
+        }
+        Err(e) => {
+            // 读不了的文件保持原样，暂停保存以免把它覆盖
+            report(
+                format!("读取失败：{}，修复后重启前不会保存设备信息", e),
+                None,
+            );
+            return (HashMap::new(), true);
+        }
+    };
+    match parse_devices(&data) {
+        Ok(devices) => {
+            println!("[USER_LIST:成功从{:?}读取用户信息]", path);
+            (devices, false)
+        }
+        Err(e @ ParseError::NewerVersion(_)) => {
+            report(format!("解析失败：{}，升级程序前不会保存设备信息", e), None);
+            (HashMap::new(), true)
+        }
+        Err(e) => match backup(path) {
+            Ok(backup) => {
+                report(
+                    format!("解析失败：{}", e),
+                    Some(backup.display().to_string()),
+                );
+                (HashMap::new(), false)
+            }
+            Err(backup_error) => {
+                println!("[USER_LIST]备份{:?}失败：{:?}", path, backup_error);
+                report(
+                    format!(
+                        "解析失败：{}，备份失败：{}，修复后重启前不会保存设备信息",
+                        e, backup_error
+                    ),
+                    None,
+                );
+                (HashMap::new(), true)
+            }
+        },
     }
 }
 
-/// 保存设备信息到本地
-fn save_devices() {
-    let devices = USER_LIST.lock().unwrap();
-    if let Ok(json) = serde_json::to_string(&*devices) {
-        let _ = fs::write(get_userinfo_path(), json);
+/// 保存设备信息到本地，失败时通知界面
+fn save_devices() -> Result<(), String> {
+    let _saving = SAVE_LOCK.lock().unwrap();
+    let json = {
+        let devices = USER_LIST.lock().unwrap();
+        serde_json::to_string(&DevicesFile {
+            version: DEVICES_SCHEMA_VERSION,
+            devices: devices.clone(),
+        })
+        .map_err(|e| e.to_string())?
+    };
+    if SAVE_BLOCKED.load(Ordering::Relaxed) {
+        println!("[USER_LIST]用户信息文件读取失败，暂不保存");
+        return Err("用户信息文件读取失败，为避免覆盖暂不保存".to_string());
     }
+    let path = get_userinfo_path();
+    storage::write_atomic(&path, json.as_bytes()).map_err(|e| {
+        let reason = format!("保存失败：{}", e);
+        storage::report(StorageError {
+            file: path.display().to_string(),
+            reason: reason.clone(),
+            backup: None,
+        });
+        reason
+    })
 }
 
 /// 根据序列号搜索
@@ -69,21 +224,84 @@ pub async fn add_device(device_name: &str, device_id: &str) {
                 device_name: device_name.to_string(),
                 device_id: device_id.to_string(),
                 user_type: UserType::Normal, // 默认普通用户
+                public_key: None,
+                fingerprint: None,
+                groups: Vec::new(),
+                profile: None,
             },
         );
     }
-    save_devices();
+    // 保存失败时已经通知界面，设备仍然保留在内存中
+    let _ = save_devices();
     println!("[USER_LIST:已添加设备{:?}到普通用户]", device_name);
 }
 
+/// 绑定设备公钥，返回指纹。公钥无效或设备不存在时返回 None
+pub fn bind_device_key(serial: &str, public_key: &str) -> Option<String> {
+    let fingerprint = match fingerprint(public_key) {
+        Ok(fp) => fp,
+        Err(e) => {
+            println!("[USER LIST]设备{:?}的公钥无效: {}", serial, e);
+            return None;
+        }
+    };
+    {
+        let mut users = USER_LIST.lock().unwrap();
+        let user = users.get_mut(serial)?;
+        user.public_key = Some(public_key.to_string());
+        user.fingerprint = Some(fingerprint.clone());
+    }
+    let _ = save_devices();
+    println!("[USER LIST]设备{:?}绑定公钥{}", serial, fingerprint);
+    Some(fingerprint)
+}
+
+/// 设置设备分组，返回是否已修改，保存失败时返回错误
+pub fn set_user_groups(serial: &str, groups: Vec<String>) -> Result<bool, String> {
+    let mut groups: Vec<String> = groups
+        .into_iter()
+        .map(|g| g.trim().to_string())
+        .filter(|g| !g.is_empty())
+        .collect();
+    groups.sort();
+    groups.dedup();
+    {
+        let mut users = USER_LIST.lock().unwrap();
+        let Some(user) = users.get_mut(serial) else {
+            println!("[USER LIST]设备{:?}不存在，无法设置分组", serial);
+            return Ok(false);
+        };
+        user.groups = groups;
+    }
+    save_devices()?;
+    Ok(true)
+}
+
+/// 设置设备的权限配置，profile 为 None 时恢复按分组或默认。返回是否已修改，保存失败时返回错误
+pub fn set_user_profile(serial: &str, profile: Option<PermissionProfile>) -> Result<bool, String> {
+    {
+        let mut users = USER_LIST.lock().unwrap();
+        let Some(user) = users.get_mut(serial) else {
+            println!("[USER LIST]设备{:?}不存在，无法设置权限", serial);
+            return Ok(false);
+        };
+        println!("[USER LIST]设备{:?}的权限设置为{:?}", serial, profile);
+        user.profile = profile;
+    }
+    save_devices()?;
+    Ok(true)
+}
+
 #[derive(Debug, Serialize)]
 pub struct UserInfoString {
     pub device_name: String,
     pub device_id: String,
     pub user_type: String,
+    pub fingerprint: Option<String>,
+    pub groups: Vec<String>,
+    pub profile: Option<PermissionProfile>,
 }
 pub async fn transfer_userinfo_to_vue() -> Vec<UserInfoString> {
-    load_devices();
     let userlist = USER_LIST.lock().unwrap();
     userlist
         .values()
@@ -95,62 +313,127 @@ pub async fn transfer_userinfo_to_vue() -> Vec<UserInfoString> {
                 UserType::Normal => "regular".to_string(),
                 UserType::Blacklist => "blacklist".to_string(),
             },
+            fingerprint: info.fingerprint.clone(),
+            groups: info.groups.clone(),
+            profile: info.profile.clone(),
         })
         .collect()
 }
 
-pub async fn update_user_category(serial: String, usertype: String) {
-    let mut users = USER_LIST.lock().unwrap();
-    {
-        let user = users.get_mut(&serial).unwrap();
-
-        let user_type = match usertype.as_str() {
-            "trusted" => "可信",
-            "regular" => "普通",
-            "blacklist" => "黑名单",
-            _ => "未知",
-        };
-        let msg = format!(
-            "是否将用户 {:?} 类别修改为 {:?}",
-            user.device_name, user_type
-        );
-        if !show_confirmation_dialog("更改用户类别", &msg) {
-            return;
+/// 修改用户类别，返回是否已修改。确认由界面负责，这里不再弹出阻塞的对话框
+pub async fn update_user_category(serial: String, usertype: String) -> Result<bool, String> {
+    let user_type = match usertype.as_str() {
+        "trusted" => UserType::Trusted,
+        "regular" => UserType::Normal,
+        "blacklist" => UserType::Blacklist,
+        _ => {
+            println!("[USER INFO]未定义的用户类型{:?}", &usertype);
+            return Ok(false);
         }
-    }
+    };
+    set_user_type(&serial, user_type)
+}
 
-    let _res = if let Some(user) = users.get_mut(&serial) {
-        user.user_type = match usertype.as_str() {
-            "trusted" => UserType::Trusted,
-            "regular" => UserType::Normal,
-            "blacklist" => UserType::Blacklist,
-            _ => {
-                println!("[USER INFO]未定义的用户类型{:?}", &usertype);
-                return;
-            }
+/// 设置用户类别并保存，拉黑时撤销该设备的 token。保存失败时内存中已经修改，返回错误
+pub fn set_user_type(serial: &str, user_type: UserType) -> Result<bool, String> {
+    let previous = {
+        let mut users = USER_LIST.lock().unwrap();
+        let Some(user) = users.get_mut(serial) else {
+            println!("[USER LIST]更新用户类型失败");
+            return Ok(false);
         };
-
+        let previous = std::mem::replace(&mut user.user_type, user_type.clone());
         println!(
             "[USER LIST]成功更新用户{:?}类型为'{:?}'",
             user.device_id, user.user_type
         );
-        Ok(())
-    } else {
-        println!("[USER LIST]更新用户类型失败");
-        Err(())
+        previous
     };
-    drop(users);
-    save_devices();
+    audit::record(
+        "user_type_changed",
+        audit::Outcome::Success,
+        Some(serial),
+        None,
+        &format!("{:?} -> {:?}", previous, user_type),
+    );
+    if user_type == UserType::Blacklist {
+        // 拉黑后已发出的 token 和长期凭据立即失效
+        revoke_device(serial);
+        revoke_device_credentials(serial);
+    }
+    save_devices()?;
+    Ok(true)
 }
 
-pub async fn delete_user(serial: String) {
+pub async fn delete_user(serial: String) -> Result<(), String> {
     let mut users = USER_LIST.lock().unwrap();
     let removed = users.remove_entry(&serial);
     drop(users);
     if let Some(rem) = removed {
-        save_devices();
-        println!("[USER_INFO]用户信息{:?}删除", rem)
+        revoke_device_credentials(&serial);
+        println!("[USER_INFO]用户信息{:?}删除", rem);
+        save_devices()
     } else {
-        println!("[USER_INFO]设备{:?}不存在，删除失败", &serial)
+        println!("[USER_INFO]设备{:?}不存在，删除失败", &serial);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_migrate_devices() {
+        // 版本 1：整个文件就是设备映射
+        let v1 = r#"{"s1":{"device_name":"phone","device_id":"s1","user_type":"Blacklist"}}"#;
+        let devices = parse_devices(v1).unwrap();
+        assert_eq!(devices["s1"].user_type, UserType::Blacklist);
+
+        let current = serde_json::to_string(&DevicesFile {
+            version: DEVICES_SCHEMA_VERSION,
+            devices,
+        })
+        .unwrap();
+        assert_eq!(parse_devices(&current).unwrap()["s1"].device_name, "phone");
+
+        assert!(parse_devices(r#"{"s1":{"device_name":"#).is_err());
+        assert_eq!(
+            parse_devices(r#"{"version":99,"devices":{}}"#).unwrap_err(),
+            ParseError::NewerVersion(99)
+        );
+    }
+
+    #[test]
+    fn test_load_blocks_saving_when_file_cannot_be_replaced() {
+        let dir = std::env::temp_dir().join(format!("lqmy-devices-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("user_data.json");
+
+        // 损坏的文件备份成功后可以照常保存
+        fs::write(&path, "{broken").unwrap();
+        let (devices, blocked) = load_devices_from(&path, storage::backup_unreadable);
+        assert!(devices.is_empty());
+        assert!(!blocked);
+        assert!(!path.exists());
+
+        // 备份失败时文件还在原处，不能保存
+        fs::write(&path, "{broken").unwrap();
+        let failing = |_: &Path| Err(io::Error::other("read-only"));
+        let (_, blocked) = load_devices_from(&path, failing);
+        assert!(blocked);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{broken");
+
+        // 更新版本的文件不备份也不覆盖
+        let newer = format!(
+            r#"{{"version":{},"devices":{{}}}}"#,
+            DEVICES_SCHEMA_VERSION + 1
+        );
+        fs::write(&path, &newer).unwrap();
+        let (_, blocked) = load_devices_from(&path, storage::backup_unreadable);
+        assert!(blocked);
+        assert_eq!(fs::read_to_string(&path).unwrap(), newer);
+
+        let _ = fs::remove_dir_all(dir);
     }
 }
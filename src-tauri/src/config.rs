@@ -1,35 +1,56 @@
 use lazy_static::lazy_static;
 use rand::{distr::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::{env, path::PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 use tokio::sync::RwLock;
 
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 use webrtc::peer_connection::RTCPeerConnection;
 
 use crate::client_utils::current_user::CurUsersInfo;
+use crate::client_utils::relay_pool::RelayPool;
 use crate::client_utils::user_manager::{UserInfo, UserType};
+use crate::storage::{self, StorageError};
 use crate::video_capturer::assembly::MultiStreamManager;
 pub const NO_CONNECTION_INDENTIFIER: &str = "!@#$%^&*()";
+// 尚未生成连接口令时的占位
+pub const UNINIT_PASSWORD: &str = "Uninitia";
+// 尚未从服务器获得 uuid 时的占位
+pub const NOT_CONNECTED_UUID: &str = "尚未连接服务器";
 // 存储全局信息的结构体
 pub struct Config {
-    pub server_address: String,      // 电脑开放的端口
+    pub server_address: String, // 当前使用的中转服务器，由 RELAY_POOL 决定
     pub connection_password: String, // 生成的连接口令
 }
 
+// Tauri 应用句柄，setup 时设置，用于向前端发送事件
+pub static APP_HANDLE: OnceLock<tauri::AppHandle> = OnceLock::new();
+
 lazy_static! {
     // 服务器信息 websocket IP/ 连接口令
+    // 中转服务器列表，SERVER_ADDRESS 可以用逗号分隔多个地址
+    pub static ref RELAY_POOL: Mutex<RelayPool> = Mutex::new(RelayPool::from_list(
+        &env::var("SERVER_ADDRESS").unwrap_or_else(|_| "wss://localhost:9876".to_string()),
+    ));
     pub static ref CONFIG: Mutex<Config> = Mutex::new(Config {
-        server_address: env::var("SERVER_ADDRESS").unwrap_or_else(|_| "wss://localhost:9876".to_string()),
-        connection_password: "Uninitia".to_string(),
+        server_address: RELAY_POOL.lock().unwrap().active_url().unwrap_or_default(),
+        connection_password: UNINIT_PASSWORD.to_string(),
     });
     // 当前连接用户信息
     pub static ref CURRENT_USER:Mutex<UserInfo>=Mutex::new(UserInfo{
         device_name:"".to_string(),
         device_id:NO_CONNECTION_INDENTIFIER.to_string(),
-        user_type:UserType::Normal
+        user_type:UserType::Normal,
+        public_key:None,
+        fingerprint:None,
+        groups:Vec::new(),
+        profile:None
     });
     // 当前连接用户信息向量
     pub static ref CURRENT_USERS_INFO:Mutex<CurUsersInfo>=Mutex::new(CurUsersInfo::new(5));
@@ -42,7 +63,7 @@ lazy_static! {
     //已经移到user_manage.rs管理
     //pub static ref DEVICE_LIST: Mutex<HashMap<String, DeviceInfo>> = Mutex::new(HashMap::new());// 没有放到CONFIG，为了减少不必要的并发访问冲突
     // 中转站分配的uuid
-    pub static ref UUID:Mutex<String>=Mutex::new("尚未连接服务器".to_string());
+    pub static ref UUID:Mutex<String>=Mutex::new(NOT_CONNECTED_UUID.to_string());
     // 全局 PeerConnection 存储：session_id -> PeerConnection
     pub static ref PEER_CONNECTION: Mutex<HashMap<String, Arc<RTCPeerConnection>>> = Mutex::new(HashMap::new());
     // 全局候选列表存储：session_id -> Vec<RTCIceCandidateInit>
@@ -55,8 +76,173 @@ lazy_static! {
 
 
 }
+// 旧版本写死的数据目录，首次启动时从这里迁移
+const LEGACY_DATA_DIR: &str = "E:/WHU/SoftwareEngineering/GroupWork/LQMY-Desk";
+// 与 tauri.conf.json 的 identifier 一致，和 Tauri 路径 API 的 app_data_dir 是同一个目录
+const APP_IDENTIFIER: &str = "com.lqmy-desk.app";
+
+// 放在平台默认数据目录下，记录用户指定的数据目录
+const DATA_DIR_OVERRIDE_FILE: &str = "data_dir.json";
+
+/// 用户在设置里指定的数据目录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DataDirOverride {
+    data_dir: String,
+}
+
+/// 按平台决定数据目录，LQMY_DATA_DIR 优先，其次是默认目录下 data_dir.json 指定的目录：
+/// - Windows：%APPDATA%/com.lqmy-desk.app
+/// - macOS：~/Library/Application Support/com.lqmy-desk.app
+/// - 其他：$XDG_DATA_HOME/com.lqmy-desk.app，默认 ~/.local/share/com.lqmy-desk.app
+fn resolve_data_dir(var: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    if let Some(dir) = var("LQMY_DATA_DIR").filter(|value| !value.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    let default = default_data_dir(var)?;
+    let configured = fs::read_to_string(default.join(DATA_DIR_OVERRIDE_FILE))
+        .ok()
+        .and_then(|data| serde_json::from_str::<DataDirOverride>(&data).ok())
+        .filter(|o| !o.data_dir.trim().is_empty());
+    match configured {
+        Some(o) => Some(PathBuf::from(o.data_dir.trim())),
+        None => Some(default),
+    }
+}
+
+/// 平台默认的数据目录，不考虑覆盖
+fn default_data_dir(var: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    let var = |key: &str| var(key).filter(|value| !value.is_empty());
+    let base = if cfg!(windows) {
+        PathBuf::from(var("APPDATA")?)
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from(var("HOME")?).join("Library/Application Support")
+    } else {
+        match var("XDG_DATA_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(var("HOME")?).join(".local/share"),
+        }
+    };
+    Some(base.join(APP_IDENTIFIER))
+}
+
+/// 指定数据目录，重启后生效；为 None 时恢复平台默认目录。LQMY_DATA_DIR 仍然优先
+pub fn set_data_dir(dir: Option<String>) -> Result<(), String> {
+    let default = default_data_dir(|key| env::var(key).ok())
+        .ok_or_else(|| "无法确定默认数据目录".to_string())?;
+    let path = default.join(DATA_DIR_OVERRIDE_FILE);
+    let Some(dir) = dir.map(|d| d.trim().to_string()).filter(|d| !d.is_empty()) else {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(format!("恢复默认数据目录失败：{}", e))
+            }
+            _ => Ok(()),
+        };
+    };
+    if !Path::new(&dir).is_absolute() {
+        return Err("数据目录需要是绝对路径".to_string());
+    }
+    fs::create_dir_all(&dir).map_err(|e| format!("创建数据目录失败：{}", e))?;
+    fs::create_dir_all(&default).map_err(|e| format!("保存数据目录设置失败：{}", e))?;
+    let setting = DataDirOverride {
+        data_dir: dir.clone(),
+    };
+    let json = serde_json::to_string_pretty(&setting).map_err(|e| e.to_string())?;
+    storage::write_atomic(&path, json.as_bytes())
+        .map_err(|e| format!("保存数据目录设置失败：{}", e))?;
+    println!("[CONFIG]数据目录设置为{:?}，重启后生效", dir);
+    Ok(())
+}
+
+// 从旧目录迁移的文件，只包括本应用自己写的数据。
+// 旧目录是项目目录，里面的 cert.pem/key.pem 是随源码分发的公共证书，不能迁移
+const MIGRATED_FILES: &[&str] = &[
+    "user_data.json",
+    "password_policy.json",
+    "host_key.json",
+    "device_credentials.json",
+    "policy_rules.json",
+    "group_profiles.json",
+    "unattended.json",
+    "audit_settings.json",
+];
+
+/// 把旧目录下属于本应用的文件复制到新目录，新目录已有 user_data.json 时不迁移。返回复制的文件数
+fn migrate_legacy_data(legacy: &Path, target: &Path) -> usize {
+    if legacy == target || !legacy.is_dir() || target.join("user_data.json").exists() {
+        return 0;
+    }
+    let mut copied = 0;
+    for name in MIGRATED_FILES {
+        let from = legacy.join(name);
+        let to = target.join(name);
+        // 已经存在的不覆盖
+        if !from.is_file() || to.exists() {
+            continue;
+        }
+        match fs::copy(&from, &to) {
+            Ok(_) => copied += 1,
+            Err(e) => println!("[CONFIG]迁移{:?}失败：{:?}", from, e),
+        }
+    }
+    copied
+}
+
+/// 测试没有指定 LQMY_DATA_DIR 时指向进程独占的临时目录，不碰真实数据
+#[cfg(test)]
+fn isolate_test_data_dir() {
+    if env::var_os("LQMY_DATA_DIR").is_none() {
+        let dir = env::temp_dir().join(format!("lqmy-desk-test-{}", std::process::id()));
+        env::set_var("LQMY_DATA_DIR", dir);
+    }
+}
+
 fn load_storage_path() -> PathBuf {
-    "E:/WHU/SoftwareEngineering/GroupWork/LQMY-Desk".into()
+    #[cfg(test)]
+    isolate_test_data_dir();
+    let dir = resolve_data_dir(|key| env::var(key).ok()).unwrap_or_else(|| {
+        // 数据会写到启动时的工作目录，通知界面，以免用户找不到
+        storage::report(StorageError {
+            file: ".".to_string(),
+            reason: "无法确定数据目录，使用当前目录".to_string(),
+            backup: None,
+        });
+        PathBuf::from(".")
+    });
+    if let Err(e) = fs::create_dir_all(&dir) {
+        storage::report(StorageError {
+            file: dir.display().to_string(),
+            reason: format!("创建数据目录失败：{}", e),
+            backup: None,
+        });
+    }
+    let copied = migrate_legacy_data(Path::new(LEGACY_DATA_DIR), &dir);
+    if copied > 0 {
+        println!(
+            "[CONFIG]已从旧数据目录{:?}迁移{}个文件",
+            LEGACY_DATA_DIR, copied
+        );
+    }
+    println!("[CONFIG]数据目录：{:?}", dir);
+    dir
+}
+
+pub fn get_data_dir() -> PathBuf {
+    APPDATA_PATH.lock().unwrap().clone()
+}
+
+/// 数据目录下有 ffmpeg 时优先使用，否则从 PATH 查找
+pub fn get_ffmpeg_path() -> PathBuf {
+    let name = if cfg!(windows) {
+        "ffmpeg.exe"
+    } else {
+        "ffmpeg"
+    };
+    let bundled = get_data_dir().join(name);
+    if bundled.is_file() {
+        bundled
+    } else {
+        PathBuf::from(name)
+    }
 }
 
 pub fn get_userinfo_path() -> PathBuf {
@@ -64,6 +250,46 @@ pub fn get_userinfo_path() -> PathBuf {
     path.join("user_data.json")
 }
 
+pub fn get_password_policy_path() -> PathBuf {
+    let path = APPDATA_PATH.lock().unwrap();
+    path.join("password_policy.json")
+}
+
+pub fn get_host_key_path() -> PathBuf {
+    let path = APPDATA_PATH.lock().unwrap();
+    path.join("host_key.json")
+}
+
+pub fn get_credentials_path() -> PathBuf {
+    let path = APPDATA_PATH.lock().unwrap();
+    path.join("device_credentials.json")
+}
+
+pub fn get_policy_rules_path() -> PathBuf {
+    let path = APPDATA_PATH.lock().unwrap();
+    path.join("policy_rules.json")
+}
+
+pub fn get_group_profiles_path() -> PathBuf {
+    let path = APPDATA_PATH.lock().unwrap();
+    path.join("group_profiles.json")
+}
+
+pub fn get_unattended_path() -> PathBuf {
+    let path = APPDATA_PATH.lock().unwrap();
+    path.join("unattended.json")
+}
+
+pub fn get_audit_dir() -> PathBuf {
+    let path = APPDATA_PATH.lock().unwrap();
+    path.join("audit")
+}
+
+pub fn get_audit_settings_path() -> PathBuf {
+    let path = APPDATA_PATH.lock().unwrap();
+    path.join("audit_settings.json")
+}
+
 fn generate_jwt_key() -> String {
     let password: String = rand::rng()
         .sample_iter(&Alphanumeric)
@@ -103,17 +329,63 @@ pub fn update_uuid(uuid: &str) {
     println!("[CLIENT]服务器分配的uuid：{:?}", *cur_uuid)
 }
 
-pub fn update_server_addr(ipaddr: String) {
+/// 兼容旧接口：一个地址或逗号分隔的多个地址
+pub fn update_server_addr(ipaddr: String) -> Result<(), String> {
+    set_relay_endpoints(ipaddr.split(',').map(str::to_string).collect())
+}
+
+/// 替换中转服务器列表，按顺序优先使用。有任何一个地址不合法时不做修改
+pub fn set_relay_endpoints(urls: Vec<String>) -> Result<(), String> {
+    let urls: Vec<String> = urls
+        .iter()
+        .map(|url| url.trim())
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect();
+    if urls.is_empty() {
+        return Err("至少需要一个中转服务器地址".to_string());
+    }
+    for url in &urls {
+        validate_relay_url(url)?;
+    }
+    RELAY_POOL.lock().unwrap().set_endpoints(urls);
+    sync_active_relay();
+    Ok(())
+}
+
+/// 中转服务器地址必须是带主机名的 ws:// 或 wss:// 地址
+fn validate_relay_url(url: &str) -> Result<(), String> {
+    let uri: awc::http::Uri = url
+        .parse()
+        .map_err(|e| format!("中转服务器地址{:?}无法解析：{}", url, e))?;
+    if !matches!(uri.scheme_str(), Some("ws") | Some("wss")) {
+        return Err(format!(
+            "中转服务器地址{:?}必须以 ws:// 或 wss:// 开头",
+            url
+        ));
+    }
+    if uri.host().is_none_or(str::is_empty) {
+        return Err(format!("中转服务器地址{:?}缺少主机名", url));
+    }
+    Ok(())
+}
+
+/// 把当前使用的中转服务器同步到 CONFIG.server_address
+pub fn sync_active_relay() -> String {
+    let url = RELAY_POOL.lock().unwrap().active_url().unwrap_or_default();
     let mut config = CONFIG.lock().unwrap();
-    config.server_address = ipaddr;
-    println!("[CLIENT]所连服务器信息改变为:{:?}", config.server_address);
+    if config.server_address != url {
+        config.server_address = url.clone();
+        println!("[CLIENT]所连服务器信息改变为:{:?}", config.server_address);
+    }
+    url
 }
 
 pub fn reset_all_info() {
     let mut config = CONFIG.lock().unwrap();
-    config.connection_password = "Uninitia".to_string();
+    config.connection_password = UNINIT_PASSWORD.to_string();
     let mut uuid = UUID.lock().unwrap();
-    *uuid = "尚未连接服务器".to_string();
+    *uuid = NOT_CONNECTED_UUID.to_string();
     CURRENT_USERS_INFO.lock().unwrap().reset();
     println!("[CONFIG]口令、用户与UUID重置")
 }
@@ -123,3 +395,109 @@ pub fn reset_all_info() {
 //     cur_users_info.usersinfo.push(new_user.clone());
 //     println!("[CONFIG]添加新连接用户信息：{:?}", new_user)
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_relay_url() {
+        assert!(validate_relay_url("wss://relay.example.com:9876").is_ok());
+        assert!(validate_relay_url("ws://127.0.0.1:9876/").is_ok());
+        assert!(validate_relay_url("https://relay.example.com").is_err());
+        assert!(validate_relay_url("relay.example.com:9876").is_err());
+        assert!(validate_relay_url("wss://").is_err());
+        assert!(validate_relay_url("wss://bad host").is_err());
+    }
+
+    #[test]
+    fn test_resolve_data_dir() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        assert_eq!(
+            resolve_data_dir(env(&[("LQMY_DATA_DIR", "/data/lqmy"), ("HOME", "/home/a")])),
+            Some(PathBuf::from("/data/lqmy"))
+        );
+        assert_eq!(resolve_data_dir(env(&[("LQMY_DATA_DIR", "")])), None);
+        if cfg!(all(unix, not(target_os = "macos"))) {
+            assert_eq!(
+                resolve_data_dir(env(&[("HOME", "/home/a")])),
+                Some(PathBuf::from("/home/a/.local/share/com.lqmy-desk.app"))
+            );
+            assert_eq!(
+                resolve_data_dir(env(&[("HOME", "/home/a"), ("XDG_DATA_HOME", "/xdg")])),
+                Some(PathBuf::from("/xdg/com.lqmy-desk.app"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_data_dir_override_file() {
+        let root = env::temp_dir().join(format!("lqmy-datadir-{}", uuid::Uuid::new_v4()));
+        let xdg = root.to_str().unwrap().to_string();
+        let (home, appdata) = (xdg.clone(), xdg.clone());
+        let var = move |key: &str| match key {
+            "XDG_DATA_HOME" => Some(xdg.clone()),
+            "HOME" => Some(home.clone()),
+            "APPDATA" => Some(appdata.clone()),
+            _ => None,
+        };
+        let default = default_data_dir(&var).unwrap();
+        assert_eq!(resolve_data_dir(&var), Some(default.clone()));
+
+        fs::create_dir_all(&default).unwrap();
+        fs::write(
+            default.join(DATA_DIR_OVERRIDE_FILE),
+            r#"{"data_dir": "/srv/lqmy"}"#,
+        )
+        .unwrap();
+        assert_eq!(resolve_data_dir(&var), Some(PathBuf::from("/srv/lqmy")));
+        // 环境变量仍然优先
+        let with_env = |key: &str| match key {
+            "LQMY_DATA_DIR" => Some("/data/lqmy".to_string()),
+            _ => var(key),
+        };
+        assert_eq!(
+            resolve_data_dir(with_env),
+            Some(PathBuf::from("/data/lqmy"))
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_migrate_legacy_data() {
+        let root = env::temp_dir().join(format!("lqmy-migrate-{}", uuid::Uuid::new_v4()));
+        let legacy = root.join("legacy");
+        let target = root.join("target");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::create_dir_all(&target).unwrap();
+        fs::write(legacy.join("user_data.json"), "{}").unwrap();
+        fs::write(legacy.join("policy_rules.json"), "[]").unwrap();
+        fs::write(legacy.join("password_policy.json"), "old").unwrap();
+        fs::write(target.join("password_policy.json"), "new").unwrap();
+        // 项目目录里的其他文件不迁移
+        fs::write(legacy.join("key.pem"), "shared").unwrap();
+        fs::write(legacy.join("Cargo.toml"), "").unwrap();
+
+        assert_eq!(migrate_legacy_data(&legacy, &target), 2);
+        assert_eq!(
+            fs::read_to_string(target.join("user_data.json")).unwrap(),
+            "{}"
+        );
+        assert!(target.join("policy_rules.json").exists());
+        assert_eq!(
+            fs::read_to_string(target.join("password_policy.json")).unwrap(),
+            "new"
+        );
+        assert!(!target.join("key.pem").exists());
+        assert!(!target.join("Cargo.toml").exists());
+        // 已经迁移过的不再迁移
+        assert_eq!(migrate_legacy_data(&legacy, &target), 0);
+        let _ = fs::remove_dir_all(root);
+    }
+}
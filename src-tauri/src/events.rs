@@ -0,0 +1,86 @@
+//! 推送给前端的事件，前端用 `listen(事件名)` 订阅，不必再轮询 get_server_info
+use serde::Serialize;
+use tauri::Emitter;
+
+use crate::client_utils::approval::ApprovalRequest;
+use crate::client_utils::conn_state::ConnectionState;
+use crate::client_utils::user_manager::UserType;
+use crate::config::APP_HANDLE;
+use crate::storage::StorageError;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// 与中转服务器的连接状态变化
+    RelayState(ConnectionState),
+    /// 切换到另一个中转服务器
+    ActiveRelayChanged { url: String },
+    /// 断线后准备重连
+    Reconnecting {
+        attempt: u32,
+        next_retry_ms: u64,
+        last_error: String,
+    },
+    /// 连接口令重新生成
+    PasswordChanged { password: String },
+    /// 新用户连接成功
+    UserJoined {
+        device_name: String,
+        device_id: String,
+        uuid: String,
+        user_type: UserType,
+    },
+    /// 用户断开
+    UserLeft { uuid: String },
+    /// 授予控制权
+    ControlGranted { device_id: String, uuid: String },
+    /// 收回控制权
+    ControlRevoked { uuid: String },
+    /// 新的连接请求等待审批
+    ApprovalRequested(ApprovalRequest),
+    /// 审批结束：approved / denied / timed_out / cancelled
+    ApprovalResolved { id: String, outcome: String },
+    /// 连接口令验证失败，用于审计
+    AuthFailure {
+        device_serial: String,
+        uuid: String,
+        failures: u32,
+        lockout_secs: u64,
+        password_rotated: bool,
+    },
+    /// 会话中出现的错误，如命令解析失败、JWT 无效、RTC 连接失败
+    SessionError { uuid: String, reason: String },
+    /// 本地文件读写失败，如设备列表损坏已备份
+    StorageError(StorageError),
+}
+
+impl ServerEvent {
+    /// 事件名
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerEvent::RelayState(_) => "connection-state",
+            ServerEvent::ActiveRelayChanged { .. } => "active-relay-changed",
+            ServerEvent::Reconnecting { .. } => "relay-reconnecting",
+            ServerEvent::PasswordChanged { .. } => "password-changed",
+            ServerEvent::UserJoined { .. } => "user-joined",
+            ServerEvent::UserLeft { .. } => "user-left",
+            ServerEvent::ControlGranted { .. } => "control-granted",
+            ServerEvent::ControlRevoked { .. } => "control-revoked",
+            ServerEvent::ApprovalRequested(_) => "approval-requested",
+            ServerEvent::ApprovalResolved { .. } => "approval-resolved",
+            ServerEvent::AuthFailure { .. } => "auth-failure",
+            ServerEvent::SessionError { .. } => "session-error",
+            ServerEvent::StorageError(_) => "storage-error",
+        }
+    }
+}
+
+/// 发送事件，应用尚未启动完成时直接忽略
+pub fn emit(event: ServerEvent) {
+    let Some(app) = APP_HANDLE.get() else {
+        return;
+    };
+    if let Err(e) = app.emit(event.name(), &event) {
+        println!("[EVENT]发送{:?}失败：{:?}", event.name(), e);
+    }
+}
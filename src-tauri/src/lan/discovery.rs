@@ -0,0 +1,207 @@
+//! 局域网主机发现
+//!
+//! 手机端向 UDP 广播地址的 [`DISCOVERY_PORT`] 发送 `{"type":"discover"}`，
+//! 每台开启发现的主机单播回复一条 [`HostAnnouncement`]，手机据此直接连接，无需手动输入 UUID。
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::client_utils::reconnect;
+use crate::config::{CURRENT_USERS_INFO, UUID};
+use crate::protocol::PROTOCOL_VERSION;
+
+use super::server::lan_status;
+
+/// 发现请求与应答使用的 UDP 端口
+pub const DISCOVERY_PORT: u16 = 9878;
+
+/// 主机对发现请求的应答
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostAnnouncement {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub name: String,
+    pub uuid: String,
+    /// 是否接受新的连接
+    pub accepting: bool,
+    /// 当前连接数 / 最大连接数
+    pub connected: usize,
+    pub capacity: usize,
+    /// 局域网直连端口，未开启直连时为空
+    pub lan_port: Option<u16>,
+    pub version: u32,
+}
+
+/// 回复发现请求的后台任务
+pub struct DiscoveryResponder {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl DiscoveryResponder {
+    /// 在 addr 上监听发现请求，每次收到请求时调用 announce 生成应答
+    pub async fn bind<F>(addr: SocketAddr, announce: F) -> io::Result<Self>
+    where
+        F: Fn() -> HostAnnouncement + Send + Sync + 'static,
+    {
+        let socket = UdpSocket::bind(addr).await?;
+        let addr = socket.local_addr()?;
+        let task = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (len, peer) = match socket.recv_from(&mut buf).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        println!("[DISCOVERY]接收失败：{:?}", e);
+                        continue;
+                    }
+                };
+                if !is_probe(&buf[..len]) {
+                    continue;
+                }
+                let reply = json!(announce()).to_string();
+                if let Err(e) = socket.send_to(reply.as_bytes(), peer).await {
+                    println!("[DISCOVERY]回复{:?}失败：{:?}", peer, e);
+                }
+            }
+        });
+        println!("[DISCOVERY]发现服务启动于{:?}", addr);
+        Ok(Self { addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stop(self) {
+        self.task.abort();
+        println!("[DISCOVERY]发现服务已停止");
+    }
+}
+
+fn is_probe(data: &[u8]) -> bool {
+    serde_json::from_slice::<Value>(data)
+        .ok()
+        .and_then(|v| {
+            v.get("type")
+                .and_then(Value::as_str)
+                .map(|t| t == "discover")
+        })
+        .unwrap_or(false)
+}
+
+/// 向 target（通常是广播地址）发送发现请求，收集 timeout 内的全部应答
+pub async fn browse(
+    target: SocketAddr,
+    timeout: Duration,
+) -> io::Result<Vec<(SocketAddr, HostAnnouncement)>> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let probe = json!({ "type": "discover", "version": PROTOCOL_VERSION }).to_string();
+    socket.send_to(probe.as_bytes(), target).await?;
+
+    let mut hosts = Vec::new();
+    let mut buf = [0u8; 1024];
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let res = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await;
+        let Ok(res) = res else { break };
+        let (len, peer) = res?;
+        match serde_json::from_slice::<HostAnnouncement>(&buf[..len]) {
+            Ok(host) => hosts.push((peer, host)),
+            Err(e) => println!("[DISCOVERY]忽略{:?}的无效应答：{:?}", peer, e),
+        }
+    }
+    Ok(hosts)
+}
+
+/// 本机的主机名，用于显示
+fn display_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "lqmy-desk".to_string())
+}
+
+/// 根据当前状态生成本机的应答
+pub fn current_announcement() -> HostAnnouncement {
+    let (connected, capacity, avail) = {
+        let cur = CURRENT_USERS_INFO.lock().unwrap();
+        (cur.usersinfo.len(), cur.capacity(), cur.is_avail())
+    };
+    let lan = lan_status();
+    let online = reconnect::get_status().connected || lan.running;
+    HostAnnouncement {
+        msg_type: "announce".to_string(),
+        name: display_name(),
+        uuid: UUID.lock().unwrap().clone(),
+        accepting: online && avail,
+        connected,
+        capacity,
+        lan_port: lan.running.then_some(lan.port),
+        version: PROTOCOL_VERSION,
+    }
+}
+
+lazy_static! {
+    static ref RESPONDER: Mutex<Option<DiscoveryResponder>> = Mutex::new(None);
+}
+
+/// 开启本机的发现应答
+pub async fn start_discovery() -> io::Result<()> {
+    if RESPONDER.lock().unwrap().is_some() {
+        return Ok(());
+    }
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT));
+    let responder = DiscoveryResponder::bind(addr, current_announcement).await?;
+    *RESPONDER.lock().unwrap() = Some(responder);
+    Ok(())
+}
+
+pub fn stop_discovery() {
+    if let Some(responder) = RESPONDER.lock().unwrap().take() {
+        responder.stop();
+    }
+}
+
+pub fn is_discovery_running() -> bool {
+    RESPONDER.lock().unwrap().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> HostAnnouncement {
+        HostAnnouncement {
+            msg_type: "announce".to_string(),
+            name: "test-host".to_string(),
+            uuid: "uuid-1".to_string(),
+            accepting: true,
+            connected: 1,
+            capacity: 5,
+            lan_port: Some(9877),
+            version: PROTOCOL_VERSION,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_browse_finds_responder() {
+        let responder = DiscoveryResponder::bind("127.0.0.1:0".parse().unwrap(), sample)
+            .await
+            .unwrap();
+        let hosts = browse(responder.local_addr(), Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].1, sample());
+        responder.stop();
+    }
+}
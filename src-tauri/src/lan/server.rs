@@ -0,0 +1,280 @@
+//! 局域网直连模式：主机内嵌 HTTPS/WSS 信令服务，同一网络内的手机无需中转服务器
+//!
+//! 手机端连接 `wss://<主机IP>:<端口>/ws`，信令格式与中转服务器相同（见 [`crate::protocol`]）：
+//! 先 `register` 拿到本机分配的 uuid，再通过 `message` 发送 auth / offer / candidate 等命令，
+//! 桌面端的回复以 `message` 的形式直接写回这条 websocket。
+//! 认证同样走 `message` 里的 auth 命令，与中转模式共用端到端加密和失败锁定。
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use actix_web::{dev::ServerHandle, web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_ws::Message;
+use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::client::handle_message;
+use crate::client_utils::disconnect::disconnect_cur_user_by_uuid;
+use crate::client_utils::e2e;
+use crate::client_utils::password::ensure_connection_password;
+use crate::config::{get_data_dir, UUID};
+use crate::protocol::SignalMessage;
+use crate::storage;
+use crate::webrtc::webrtc_connect::close_peerconnection;
+
+use super::discovery::is_discovery_running;
+
+/// 局域网信令服务默认端口
+pub const DEFAULT_LAN_PORT: u16 = 9877;
+
+struct LanServer {
+    handle: ServerHandle,
+    addr: SocketAddr,
+}
+
+lazy_static! {
+    // 运行中的局域网服务
+    static ref LAN_SERVER: Mutex<Option<LanServer>> = Mutex::new(None);
+    // 直连的手机：uuid -> 写回 websocket 的通道
+    static ref LAN_PEERS: Mutex<HashMap<String, mpsc::UnboundedSender<String>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// 提供给前端的局域网服务状态
+#[derive(Debug, Clone, Serialize)]
+pub struct LanStatus {
+    pub running: bool,
+    pub port: u16,
+    pub peers: usize,
+    /// 是否开启了局域网发现
+    pub discovery: bool,
+}
+
+pub fn lan_status() -> LanStatus {
+    let server = LAN_SERVER.lock().unwrap();
+    LanStatus {
+        running: server.is_some(),
+        port: server.as_ref().map(|s| s.addr.port()).unwrap_or(0),
+        peers: LAN_PEERS.lock().unwrap().len(),
+        discovery: is_discovery_running(),
+    }
+}
+
+/// 目标是直连的手机时直接写回其 websocket，返回是否已投递
+pub fn try_deliver(target_uuid: &str, payload: &Value) -> bool {
+    let peers = LAN_PEERS.lock().unwrap();
+    let Some(tx) = peers.get(target_uuid) else {
+        return false;
+    };
+    let from = UUID.lock().unwrap().clone();
+    let msg = SignalMessage::message(&from, target_uuid, payload.clone()).to_json();
+    tx.send(msg.to_string()).is_ok()
+}
+
+/// 启动局域网信令服务，已经在运行则直接返回当前端口
+pub async fn start_lan_server(port: u16) -> io::Result<u16> {
+    if let Some(server) = LAN_SERVER.lock().unwrap().as_ref() {
+        return Ok(server.addr.port());
+    }
+    ensure_connection_password().await;
+    let tls = load_tls_config(&get_data_dir())?;
+
+    // actix 服务需要自己的 System，放到单独线程里跑
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let sys = actix_rt::System::new();
+        sys.block_on(async move {
+            let server = HttpServer::new(|| App::new().route("/ws", web::get().to(ws_handler)))
+                .bind_rustls_021(("0.0.0.0", port), tls);
+            let server = match server {
+                Ok(server) => server,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            let addr = server.addrs()[0];
+            let server = server.run();
+            let _ = tx.send(Ok(LanServer {
+                handle: server.handle(),
+                addr,
+            }));
+            if let Err(e) = server.await {
+                println!("[LAN]局域网服务异常退出：{:?}", e);
+            }
+        });
+        LAN_PEERS.lock().unwrap().clear();
+        println!("[LAN]局域网服务线程结束");
+    });
+
+    let server = rx
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "局域网服务启动线程退出"))??;
+    let port = server.addr.port();
+    println!("[LAN]局域网信令服务启动于{:?}", server.addr);
+    *LAN_SERVER.lock().unwrap() = Some(server);
+    Ok(port)
+}
+
+pub async fn stop_lan_server() {
+    let server = LAN_SERVER.lock().unwrap().take();
+    if let Some(server) = server {
+        server.handle.stop(true).await;
+        println!("[LAN]局域网信令服务已停止");
+    }
+}
+
+/// 读取 dir 下的 TLS 证书，首次启动时为本机生成
+fn load_tls_config(dir: &Path) -> io::Result<rustls::ServerConfig> {
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    if !cert_path.exists() || !key_path.exists() {
+        generate_certificate(&cert_path, &key_path)?;
+    }
+    let cert_pem = std::fs::read(&cert_path)?;
+    let key_pem = std::fs::read(&key_path)?;
+
+    let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_pem.as_slice())?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut key_pem.as_slice())?;
+    }
+    if keys.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "key.pem 中没有私钥",
+        ));
+    }
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, rustls::PrivateKey(keys.remove(0)))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// 生成本机独有的自签名证书，私钥只允许当前用户读取
+fn generate_certificate(cert_path: &Path, key_path: &Path) -> io::Result<()> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .map_err(io::Error::other)?;
+    let cert_pem = cert.serialize_pem().map_err(io::Error::other)?;
+    storage::write_private(key_path, cert.serialize_private_key_pem().as_bytes())?;
+    storage::write_atomic(cert_path, cert_pem.as_bytes())?;
+    println!("[LAN]已生成本机自签名证书{:?}", cert_path);
+    Ok(())
+}
+
+async fn ws_handler(req: HttpRequest, body: web::Payload) -> actix_web::Result<HttpResponse> {
+    let (response, mut session, mut stream) = actix_ws::handle(&req, body)?;
+    println!("[LAN]收到来自{:?}的直连", req.peer_addr());
+
+    // 写回方向：其他模块通过 try_deliver 投递到这里
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let mut writer = session.clone();
+    actix_rt::spawn(async move {
+        while let Some(text) = rx.recv().await {
+            if writer.text(text).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    actix_rt::spawn(async move {
+        let mut my_uuid: Option<String> = None;
+        while let Some(Ok(frame)) = stream.next().await {
+            match frame {
+                Message::Text(text) => {
+                    let msg = match serde_json::from_str::<SignalMessage>(&text) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            println!("[LAN]无法识别的信令{:?}：{}", text, e);
+                            continue;
+                        }
+                    };
+                    match msg {
+                        SignalMessage::Register { .. } => {
+                            let uuid = match &my_uuid {
+                                Some(uuid) => uuid.clone(),
+                                None => {
+                                    let uuid = uuid::Uuid::new_v4().to_string();
+                                    LAN_PEERS.lock().unwrap().insert(uuid.clone(), tx.clone());
+                                    my_uuid = Some(uuid.clone());
+                                    uuid
+                                }
+                            };
+                            let ack = SignalMessage::RegisterAck { uuid }.to_json();
+                            let _ = tx.send(ack.to_string());
+                        }
+                        SignalMessage::Message {
+                            payload, version, ..
+                        } => {
+                            let Some(from) = my_uuid.clone() else {
+                                println!("[LAN]未注册的直连客户端发送消息，忽略");
+                                continue;
+                            };
+                            handle_message(from, &payload, version).await;
+                        }
+                        SignalMessage::Ping { .. } => {
+                            let _ = tx.send(json!(SignalMessage::Pong).to_string());
+                        }
+                        SignalMessage::Close => break,
+                        other => println!("[LAN]忽略直连客户端发来的{:?}", other),
+                    }
+                }
+                Message::Ping(bytes) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        if let Some(uuid) = my_uuid {
+            // 连接断了就结束这台手机的会话，不留下占着名额的用户和 PeerConnection
+            close_peerconnection(&uuid).await;
+            disconnect_cur_user_by_uuid(&uuid);
+            LAN_PEERS.lock().unwrap().remove(&uuid);
+            e2e::forget(&uuid);
+            println!("[LAN]直连客户端{:?}断开", uuid);
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generates_certificate_once() {
+        let dir = std::env::temp_dir().join(format!("lqmy-lan-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        load_tls_config(&dir).unwrap();
+        let cert = std::fs::read(dir.join("cert.pem")).unwrap();
+        // 已有证书时沿用，不重新生成
+        load_tls_config(&dir).unwrap();
+        assert_eq!(std::fs::read(dir.join("cert.pem")).unwrap(), cert);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join("key.pem"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -1,3 +1,6 @@
+pub mod protocol;
+pub mod relay;
+
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
 fn greet(name: &str) -> String {
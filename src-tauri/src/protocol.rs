@@ -0,0 +1,383 @@
+//! 桌面端与中转服务器 / 手机端之间的信令协议
+//!
+//! 外层帧（websocket 文本帧）是 [`SignalMessage`]，按 `type` 字段区分：
+//! `register` / `register_ack` / `register_reject` / `message` / `ping` / `pong` / `close` /
+//! `peer_offline`。
+//!
+//! `message` 的 `payload` 是手机端发来的命令 [`Command`]，按 `cmd` 字段区分，
+//! 具体参数放在 `data` 里。为兼容已有的手机端，`payload` 与 `data` 既可以是 JSON 对象，
+//! 也可以是序列化后的 JSON 字符串。
+//!
+//! 桌面端的回复同样放在 `message` 的 `payload` 里：
+//! - `auth` / `control` / `closertc`：`{"status": "...", "body": "..."}`
+//! - `offer`：`{"cmd": "answear", "value": {"client_uuid": "...", "sdp": "..."}}`
+//! - 本地 ICE 候选：`{"cmd": "candidate", "value": {"candidates": {...}}}`
+//! - `refresh` / `resume`：`{"cmd": "refresh" | "resume", "value": {"status": "...", "body": "..."}}`
+//! - `challenge`：`{"cmd": "challenge", "value": {"nonce": "<base64>"}}`
+//! - 主动断开：`{"cmd": "disconnect"}`
+//! - 命令无法处理：`{"cmd": "error", "value": {"code": 400, "reason": "...", "cmd": "..."}}`
+//!
+//! 协议有不兼容改动时递增 [`PROTOCOL_VERSION`]。
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+/// 当前信令协议版本
+pub const PROTOCOL_VERSION: u32 = 1;
+/// 仍能处理的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+fn default_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+/// websocket 上的一帧信令
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SignalMessage {
+    /// 客户端注册，desktop 或 mobile
+    Register {
+        client_type: String,
+        #[serde(default = "default_version")]
+        version: u32,
+        /// 重连时希望沿用的 uuid
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uuid: Option<String>,
+    },
+    /// 注册成功，服务器分配 uuid
+    RegisterAck {
+        uuid: String,
+    },
+    /// 注册被拒绝
+    RegisterReject {
+        reason: String,
+    },
+    /// 转发给 target_uuid 的消息
+    Message {
+        #[serde(default)]
+        from: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        target_uuid: String,
+        payload: Value,
+        #[serde(default = "default_version")]
+        version: u32,
+    },
+    /// 心跳
+    Ping {
+        #[serde(default)]
+        from: String,
+    },
+    Pong,
+    /// 主动关闭
+    Close,
+    /// 中转服务器通知：uuid 对应的客户端已经下线
+    PeerOffline {
+        uuid: String,
+    },
+}
+
+impl SignalMessage {
+    /// 构造一条发给 target_uuid 的消息
+    pub fn message(from: &str, target_uuid: &str, payload: Value) -> Self {
+        SignalMessage::Message {
+            from: from.to_string(),
+            target_uuid: target_uuid.to_string(),
+            payload,
+            version: PROTOCOL_VERSION,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+/// 手机端的认证请求
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthRequest {
+    pub device_name: String,
+    pub device_serial: String,
+    pub password: String,
+    pub uuid: String,
+    /// base64 编码的 Ed25519 公钥，首次配对时绑定到设备
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// 对最近一次 challenge 的签名（base64）
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/// 携带 JWT 的 SDP Offer
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JWTOfferRequest {
+    pub client_uuid: String,
+    pub sdp: String,
+    pub mode: String, // "low_latency", "balanced", "high_quality"
+    pub jwt: String,
+}
+
+/// 携带 JWT 的远端 ICE 候选
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JWTCandidateRequest {
+    pub client_uuid: String,
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    pub sdp_mline_index: Option<u16>,
+    pub jwt: String,
+}
+
+/// 被动断连
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DisconnectReq {
+    pub jwt: String,
+    pub device_serial: String,
+}
+
+/// 控制权相关请求
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CrtlReq {
+    pub jwt: String,
+    pub uuid: String,
+    pub device_serial: String,
+}
+
+/// 用仍然有效的 token 换取新 token
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RefreshReq {
+    pub jwt: String,
+}
+
+/// 主机重启后用长期设备凭据恢复会话
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResumeReq {
+    pub credential: String,
+    pub device_name: String,
+    pub device_serial: String,
+    /// 绑定了设备密钥时必填：对最近一次 challenge 的签名（base64）
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/// 申请 auth 签名用的 challenge
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChallengeReq {
+    pub device_serial: String,
+}
+
+/// 桌面端能处理的全部 `cmd`
+pub const COMMANDS: &[&str] = &[
+    "auth",
+    "offer",
+    "candidate",
+    "disconnect",
+    "control",
+    "revokectrl",
+    "closertc",
+    "refresh",
+    "resume",
+    "challenge",
+];
+
+/// 手机端命令，对应 payload 中的 `cmd` / `data`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "cmd", content = "data", rename_all = "lowercase")]
+pub enum Command {
+    Auth(AuthRequest),
+    Offer(JWTOfferRequest),
+    Candidate(JWTCandidateRequest),
+    Disconnect(DisconnectReq),
+    Control(CrtlReq),
+    RevokeCtrl(CrtlReq),
+    CloseRtc(CrtlReq),
+    Refresh(RefreshReq),
+    Resume(ResumeReq),
+    Challenge(ChallengeReq),
+}
+
+impl Command {
+    /// 命令名，和 `cmd` 字段一致
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Auth(_) => "auth",
+            Command::Offer(_) => "offer",
+            Command::Candidate(_) => "candidate",
+            Command::Disconnect(_) => "disconnect",
+            Command::Control(_) => "control",
+            Command::RevokeCtrl(_) => "revokectrl",
+            Command::CloseRtc(_) => "closertc",
+            Command::Refresh(_) => "refresh",
+            Command::Resume(_) => "resume",
+            Command::Challenge(_) => "challenge",
+        }
+    }
+
+    /// 从 message 的 payload 解析命令，payload 和 data 都允许是 JSON 字符串
+    pub fn from_payload(payload: &Value) -> Result<Command, ProtocolError> {
+        let payload = unwrap_json_string(payload)?;
+        let cmd = payload
+            .get("cmd")
+            .and_then(Value::as_str)
+            .ok_or(ProtocolError::MissingField("cmd"))?
+            .to_string();
+        if !COMMANDS.contains(&cmd.as_str()) {
+            return Err(ProtocolError::UnknownCommand(cmd));
+        }
+        let data = match payload.get("data") {
+            Some(data) => unwrap_json_string(data)?,
+            None => Value::Null,
+        };
+        serde_json::from_value(json!({ "cmd": cmd, "data": data })).map_err(|e| {
+            ProtocolError::InvalidData {
+                cmd,
+                reason: e.to_string(),
+            }
+        })
+    }
+}
+
+/// 检查对端 message 的协议版本
+pub fn check_version(version: u32) -> Result<(), ProtocolError> {
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(ProtocolError::UnsupportedVersion(version))
+    }
+}
+
+/// 字符串形式的 JSON 展开成对象，其他值原样返回
+pub fn unwrap_json_string(value: &Value) -> Result<Value, ProtocolError> {
+    match value {
+        Value::String(s) => {
+            serde_json::from_str(s).map_err(|e| ProtocolError::InvalidJson(e.to_string()))
+        }
+        other => Ok(other.clone()),
+    }
+}
+
+/// 命令处理失败时回给对端的错误
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorReply {
+    pub code: u16,
+    pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<String>,
+}
+
+impl ErrorReply {
+    pub fn new(code: u16, reason: impl Into<String>, cmd: Option<&str>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+            cmd: cmd.map(str::to_string),
+        }
+    }
+
+    /// 包装成 message 的 payload
+    pub fn to_payload(&self) -> Value {
+        json!({ "cmd": "error", "value": self })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("invalid json: {0}")]
+    InvalidJson(String),
+
+    #[error("missing field `{0}`")]
+    MissingField(&'static str),
+
+    #[error("unknown command `{0}`")]
+    UnknownCommand(String),
+
+    #[error("invalid data for `{cmd}`: {reason}")]
+    InvalidData { cmd: String, reason: String },
+
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u32),
+}
+
+impl ProtocolError {
+    pub fn to_reply(&self) -> ErrorReply {
+        let cmd = match self {
+            ProtocolError::UnknownCommand(cmd) | ProtocolError::InvalidData { cmd, .. } => {
+                Some(cmd.as_str())
+            }
+            _ => None,
+        };
+        ErrorReply::new(400, self.to_string(), cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stringified_payload() {
+        let data = json!({
+            "device_name": "phone",
+            "device_serial": "123",
+            "password": "12345678",
+            "uuid": "u1"
+        })
+        .to_string();
+        let payload = Value::String(json!({ "cmd": "auth", "data": data }).to_string());
+        match Command::from_payload(&payload).unwrap() {
+            Command::Auth(req) => assert_eq!(req.device_serial, "123"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_and_invalid_command() {
+        let payload = json!({ "cmd": "reboot", "data": {} });
+        assert!(matches!(
+            Command::from_payload(&payload),
+            Err(ProtocolError::UnknownCommand(c)) if c == "reboot"
+        ));
+        let payload = json!({ "cmd": "control", "data": { "jwt": "x" } });
+        assert!(matches!(
+            Command::from_payload(&payload),
+            Err(ProtocolError::InvalidData { .. })
+        ));
+    }
+
+    #[test]
+    fn test_commands_match_enum() {
+        // serde 的报错会列出 Command 的全部变体，和 COMMANDS 比对
+        let err = serde_json::from_value::<Command>(json!({ "cmd": "nope", "data": null }))
+            .unwrap_err()
+            .to_string();
+        let expected: Vec<&str> = err
+            .split_once("expected one of ")
+            .unwrap()
+            .1
+            .split(", ")
+            .map(|name| name.trim_matches(|c: char| c == '`' || c.is_whitespace()))
+            .collect();
+        assert_eq!(expected, COMMANDS);
+    }
+
+    #[test]
+    fn test_check_version() {
+        assert!(check_version(PROTOCOL_VERSION).is_ok());
+        let err = check_version(PROTOCOL_VERSION + 1).unwrap_err();
+        assert!(matches!(err, ProtocolError::UnsupportedVersion(_)));
+        assert_eq!(err.to_reply().code, 400);
+        assert!(check_version(0).is_err());
+    }
+
+    #[test]
+    fn test_signal_message_roundtrip() {
+        let v: SignalMessage =
+            serde_json::from_str(r#"{"type":"register_ack","uuid":"abc"}"#).unwrap();
+        assert!(matches!(v, SignalMessage::RegisterAck { uuid } if uuid == "abc"));
+        let out = SignalMessage::message("me", "you", json!({"cmd":"disconnect"})).to_json();
+        assert_eq!(out["type"], "message");
+        assert_eq!(out["target_uuid"], "you");
+        assert_eq!(out["version"], PROTOCOL_VERSION);
+        let close = SignalMessage::Close.to_json();
+        assert_eq!(close, json!({ "type": "close" }));
+    }
+}
@@ -0,0 +1,394 @@
+//! 本地开发与测试用的中转服务器
+//!
+//! 与线上中转站使用同一套信令（见 [`crate::protocol`]）：
+//! 客户端先发 `register`，得到 `register_ack` 分配的 uuid，之后的 `message` 按 `target_uuid` 转发，
+//! `ping` 回复 `pong`，`close` 断开。转发时 `from` 一律由服务器改写为发送方的 uuid。
+//!
+//! 桌面端连本服务器时设置 `SERVER_ADDRESS=ws://127.0.0.1:9876`。
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use actix_web::{dev::ServerHandle, web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_ws::{Message, Session};
+use futures_util::StreamExt;
+use serde_json::json;
+
+use crate::protocol::{ErrorReply, SignalMessage};
+
+/// 服务器自身发出的消息使用的 from
+pub const RELAY_UUID: &str = "relay";
+
+/// 中转服务器的行为配置
+#[derive(Debug, Clone, Default)]
+pub struct RelayConfig {
+    /// 设置后拒绝所有桌面端注册，用于测试 register_reject
+    pub reject_desktops: Option<String>,
+}
+
+struct RelayClient {
+    client_type: String,
+    session: Session,
+}
+
+struct RelayState {
+    config: RelayConfig,
+    clients: Mutex<HashMap<String, RelayClient>>,
+}
+
+/// 运行中的中转服务器
+pub struct MockRelay {
+    addr: SocketAddr,
+    handle: ServerHandle,
+    state: Arc<RelayState>,
+}
+
+impl MockRelay {
+    /// 在 addr 上启动，端口写 0 则由系统分配
+    pub async fn start(addr: &str) -> io::Result<MockRelay> {
+        Self::start_with(addr, RelayConfig::default()).await
+    }
+
+    pub async fn start_with(addr: &str, config: RelayConfig) -> io::Result<MockRelay> {
+        let state = Arc::new(RelayState {
+            config,
+            clients: Mutex::new(HashMap::new()),
+        });
+        let data = web::Data::from(state.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/", web::get().to(ws_handler))
+        })
+        .workers(1)
+        .bind(addr)?;
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+        println!("[RELAY]中转服务器启动于{:?}", addr);
+        Ok(MockRelay {
+            addr,
+            handle,
+            state,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// 客户端连接用的 websocket 地址
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}/", self.addr)
+    }
+
+    /// 当前注册的客户端：(uuid, client_type)
+    pub fn clients(&self) -> Vec<(String, String)> {
+        self.state
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(uuid, c)| (uuid.clone(), c.client_type.clone()))
+            .collect()
+    }
+
+    /// 主动断开某个客户端，模拟与中转服务器的连接中断
+    pub async fn disconnect(&self, uuid: &str) -> bool {
+        let session = self
+            .state
+            .clients
+            .lock()
+            .unwrap()
+            .get(uuid)
+            .map(|c| c.session.clone());
+        match session {
+            Some(session) => {
+                let _ = session.close(None).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(true).await;
+        println!("[RELAY]中转服务器已停止");
+    }
+}
+
+async fn ws_handler(
+    req: HttpRequest,
+    body: web::Payload,
+    state: web::Data<RelayState>,
+) -> actix_web::Result<HttpResponse> {
+    let (response, session, mut stream) = actix_ws::handle(&req, body)?;
+    let state = state.into_inner();
+
+    actix_rt::spawn(async move {
+        let mut session = session;
+        // 注册成功后才有 uuid
+        let mut my_uuid: Option<String> = None;
+
+        while let Some(Ok(frame)) = stream.next().await {
+            match frame {
+                Message::Text(text) => {
+                    let msg = match serde_json::from_str::<SignalMessage>(&text) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            println!("[RELAY]无法识别的信令{:?}：{}", text, e);
+                            continue;
+                        }
+                    };
+                    match msg {
+                        SignalMessage::Register {
+                            client_type, uuid, ..
+                        } => {
+                            let reply = register(&state, &session, &client_type, uuid);
+                            if let SignalMessage::RegisterAck { uuid } = &reply {
+                                my_uuid = Some(uuid.clone());
+                            }
+                            if send(&mut session, &reply).await.is_err() {
+                                break;
+                            }
+                        }
+                        SignalMessage::Message {
+                            target_uuid,
+                            payload,
+                            version,
+                            ..
+                        } => {
+                            let Some(from) = my_uuid.clone() else {
+                                println!("[RELAY]未注册的客户端发送消息，忽略");
+                                continue;
+                            };
+                            let target = state
+                                .clients
+                                .lock()
+                                .unwrap()
+                                .get(&target_uuid)
+                                .map(|c| c.session.clone());
+                            let delivered = match target {
+                                Some(mut target) => {
+                                    let forward = SignalMessage::Message {
+                                        from,
+                                        target_uuid: target_uuid.clone(),
+                                        payload,
+                                        version,
+                                    };
+                                    send(&mut target, &forward).await.is_ok()
+                                }
+                                None => false,
+                            };
+                            if !delivered {
+                                let err = ErrorReply::new(404, "目标不在线", None);
+                                let reply = SignalMessage::message(
+                                    RELAY_UUID,
+                                    my_uuid.as_deref().unwrap_or_default(),
+                                    err.to_payload(),
+                                );
+                                if send(&mut session, &reply).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        SignalMessage::Ping { .. } => {
+                            if send(&mut session, &SignalMessage::Pong).await.is_err() {
+                                break;
+                            }
+                        }
+                        SignalMessage::Close => break,
+                        other => println!("[RELAY]忽略客户端发来的{:?}", other),
+                    }
+                }
+                Message::Ping(bytes) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        if let Some(uuid) = my_uuid {
+            let others: Vec<Session> = {
+                let mut clients = state.clients.lock().unwrap();
+                clients.remove(&uuid);
+                clients.values().map(|c| c.session.clone()).collect()
+            };
+            println!("[RELAY]{:?}断开", uuid);
+            // 通知其他客户端，让它们丢弃和这个 uuid 相关的状态
+            let offline = SignalMessage::PeerOffline { uuid };
+            for mut other in others {
+                let _ = send(&mut other, &offline).await;
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+/// 处理注册，返回 register_ack 或 register_reject
+fn register(
+    state: &RelayState,
+    session: &Session,
+    client_type: &str,
+    requested: Option<String>,
+) -> SignalMessage {
+    if client_type != "desktop" && client_type != "mobile" {
+        return SignalMessage::RegisterReject {
+            reason: format!("未知的客户端类型{:?}", client_type),
+        };
+    }
+    if client_type == "desktop" {
+        if let Some(reason) = &state.config.reject_desktops {
+            return SignalMessage::RegisterReject {
+                reason: reason.clone(),
+            };
+        }
+    }
+    let mut clients = state.clients.lock().unwrap();
+    // 重连时沿用原来的 uuid，前提是没被别人占用
+    let uuid = match requested {
+        Some(uuid) if !clients.contains_key(&uuid) => uuid,
+        _ => uuid::Uuid::new_v4().to_string(),
+    };
+    clients.insert(
+        uuid.clone(),
+        RelayClient {
+            client_type: client_type.to_string(),
+            session: session.clone(),
+        },
+    );
+    println!("[RELAY]{}注册成功：{:?}", client_type, uuid);
+    SignalMessage::RegisterAck { uuid }
+}
+
+async fn send(session: &mut Session, msg: &SignalMessage) -> Result<(), actix_ws::Closed> {
+    session.text(json!(msg).to_string()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use awc::ws::{Frame, Message as WsMessage};
+    use futures_util::SinkExt;
+    use serde_json::Value;
+
+    async fn recv(conn: &mut actix_codec::Framed<awc::BoxedSocket, awc::ws::Codec>) -> Value {
+        loop {
+            match conn.next().await.unwrap().unwrap() {
+                Frame::Text(bytes) => return serde_json::from_slice(&bytes).unwrap(),
+                _ => continue,
+            }
+        }
+    }
+
+    async fn connect(
+        relay: &MockRelay,
+        client_type: &str,
+    ) -> (
+        actix_codec::Framed<awc::BoxedSocket, awc::ws::Codec>,
+        String,
+    ) {
+        let (_, mut conn) = awc::Client::new()
+            .ws(relay.ws_url())
+            .connect()
+            .await
+            .unwrap();
+        let register = json!({ "type": "register", "client_type": client_type });
+        conn.send(WsMessage::Text(register.to_string().into()))
+            .await
+            .unwrap();
+        let ack = recv(&mut conn).await;
+        assert_eq!(ack["type"], "register_ack");
+        let uuid = ack["uuid"].as_str().unwrap().to_string();
+        (conn, uuid)
+    }
+
+    #[actix_rt::test]
+    async fn test_register_and_route() {
+        let relay = MockRelay::start("127.0.0.1:0").await.unwrap();
+        let (mut desktop, desktop_uuid) = connect(&relay, "desktop").await;
+        let (mut mobile, mobile_uuid) = connect(&relay, "mobile").await;
+        assert_eq!(relay.clients().len(), 2);
+
+        let msg = json!({
+            "type": "message",
+            "from": "spoofed",
+            "target_uuid": desktop_uuid,
+            "payload": { "cmd": "auth", "data": {} }
+        });
+        mobile
+            .send(WsMessage::Text(msg.to_string().into()))
+            .await
+            .unwrap();
+        let got = recv(&mut desktop).await;
+        assert_eq!(got["type"], "message");
+        assert_eq!(got["from"], Value::String(mobile_uuid.clone()));
+        assert_eq!(got["payload"]["cmd"], "auth");
+
+        desktop
+            .send(WsMessage::Text(
+                json!({ "type": "ping" }).to_string().into(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut desktop).await["type"], "pong");
+
+        // 手机端下线时通知桌面端
+        mobile
+            .send(WsMessage::Text(
+                json!({ "type": "close" }).to_string().into(),
+            ))
+            .await
+            .unwrap();
+        let offline = recv(&mut desktop).await;
+        assert_eq!(offline["type"], "peer_offline");
+        assert_eq!(offline["uuid"], Value::String(mobile_uuid));
+
+        relay.stop().await;
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_target_and_reject() {
+        let relay = MockRelay::start_with(
+            "127.0.0.1:0",
+            RelayConfig {
+                reject_desktops: Some("维护中".to_string()),
+            },
+        )
+        .await
+        .unwrap();
+        let (mut mobile, _) = connect(&relay, "mobile").await;
+        let msg = json!({ "type": "message", "target_uuid": "nobody", "payload": {} });
+        mobile
+            .send(WsMessage::Text(msg.to_string().into()))
+            .await
+            .unwrap();
+        let got = recv(&mut mobile).await;
+        assert_eq!(got["payload"]["cmd"], "error");
+        assert_eq!(got["payload"]["value"]["code"], 404);
+
+        let (_, mut desktop) = awc::Client::new()
+            .ws(relay.ws_url())
+            .connect()
+            .await
+            .unwrap();
+        let register = json!({ "type": "register", "client_type": "desktop" });
+        desktop
+            .send(WsMessage::Text(register.to_string().into()))
+            .await
+            .unwrap();
+        let reject = recv(&mut desktop).await;
+        assert_eq!(reject["type"], "register_reject");
+        assert_eq!(reject["reason"], "维护中");
+
+        relay.stop().await;
+    }
+}
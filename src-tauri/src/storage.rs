@@ -0,0 +1,144 @@
+//! 本地文件的可靠读写
+//!
+//! 写入先写到同目录的临时文件，落盘后再 rename 覆盖，中途崩溃不会留下写了一半的文件。
+//! 读不出来的文件改名备份，不直接丢弃；读写错误记录下来并通过 `storage-error` 事件通知界面。
+
+use chrono::Local;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::events::{emit, ServerEvent};
+
+/// 最多保留的错误条数
+const MAX_ERRORS: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StorageError {
+    /// 出错的文件
+    pub file: String,
+    pub reason: String,
+    /// 读不出来的文件备份到了哪里
+    pub backup: Option<String>,
+}
+
+lazy_static! {
+    static ref STORAGE_ERRORS: Mutex<Vec<StorageError>> = Mutex::new(Vec::new());
+}
+
+/// 原子写入：写临时文件、同步到磁盘、rename 覆盖目标
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_replacing(path, data, false)
+}
+
+/// 原子写入私钥等敏感文件，Unix 上只有本人可读写；Windows 上数据目录本身在用户目录下
+pub fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_replacing(path, data, true)
+}
+
+fn write_replacing(path: &Path, data: &[u8], private: bool) -> io::Result<()> {
+    // 每次写入用不同的临时文件，并发保存同一个文件时不会互相截断
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp-{}", uuid::Uuid::new_v4()));
+    let tmp = PathBuf::from(tmp);
+    let result = write_synced(&tmp, data, private).and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn write_synced(path: &Path, data: &[u8], private: bool) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// 把读不出来的文件改名为 `<文件名>.corrupt-<时间>`，返回备份路径
+pub fn backup_unreadable(path: &Path) -> io::Result<PathBuf> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".corrupt-{}", Local::now().format("%Y%m%d%H%M%S")));
+    let backup = PathBuf::from(backup);
+    fs::rename(path, &backup)?;
+    Ok(backup)
+}
+
+/// 记录一次读写错误并通知界面
+pub fn report(error: StorageError) {
+    println!(
+        "[STORAGE]{:?}读写失败：{}，备份：{:?}",
+        error.file, error.reason, error.backup
+    );
+    {
+        let mut errors = STORAGE_ERRORS.lock().unwrap();
+        if errors.len() >= MAX_ERRORS {
+            errors.remove(0);
+        }
+        errors.push(error.clone());
+    }
+    emit(ServerEvent::StorageError(error));
+}
+
+/// 启动以来的读写错误，界面打开时拉取，启动早期发生的错误事件可能还没有人监听
+pub fn errors() -> Vec<StorageError> {
+    STORAGE_ERRORS.lock().unwrap().clone()
+}
+
+pub fn clear_errors() {
+    STORAGE_ERRORS.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic_and_backup() {
+        let dir = std::env::temp_dir().join(format!("lqmy-storage-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.json");
+
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        // 临时文件都已经改名或清理
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let backup = backup_unreadable(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "second");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let dir = std::env::temp_dir().join(format!("lqmy-storage-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.json");
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || write_atomic(&path, format!("writer-{}", i).as_bytes()))
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        // 每次写入都是完整的，不会被另一次写入截断
+        assert!(fs::read_to_string(&path).unwrap().starts_with("writer-"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
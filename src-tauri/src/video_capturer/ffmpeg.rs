@@ -3,7 +3,7 @@ use std::{
     sync::Mutex,
 };
 
-use crate::config::{APPDATA_PATH, PEER_CONNECTION};
+use crate::config::{get_ffmpeg_path, PEER_CONNECTION};
 use lazy_static::lazy_static;
 use sha2::digest::consts::False;
 use webrtc::peer_connection;
@@ -19,7 +19,7 @@ pub fn start_screen_capture(udp_port: u16) {
         return;
     };
     println!("[FFMPEG]启动");
-    let path = APPDATA_PATH.lock().unwrap().clone().join("ffmpeg.exe");
+    let path = get_ffmpeg_path();
     let addr = format!("rtp://127.0.0.1:{}", udp_port);
     let ffmpeg_child_process = Command::new(path.clone())
         .args(&[
@@ -1,13 +1,19 @@
-use crate::client::{PENDING, SEND_NOTIFY};
-use crate::config::{GLOBAL_STREAM_MANAGER, PEER_CONNECTION, UUID};
+use crate::client::send_to_peer;
+use crate::client_utils::permissions::Permission;
+use crate::client_utils::tokens::TOKEN_REGISTRY;
+use crate::config::{CANDIDATES, CURRENT_USERS_INFO, GLOBAL_STREAM_MANAGER, PEER_CONNECTION};
+use crate::events::{emit, ServerEvent};
+pub use crate::protocol::{JWTCandidateRequest, JWTOfferRequest};
 use crate::video_capturer::assembly::QualityConfig;
 
 use actix_web::web;
 
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use serde_json::{json, Value};
 
-use std::sync::Arc;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
 use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 
 use webrtc::data_channel::RTCDataChannel;
@@ -26,35 +32,18 @@ use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
 
-#[derive(Debug, Deserialize)]
-pub struct JWTOfferRequest {
-    pub client_uuid: String,
-    pub sdp: String,
-    pub mode: String, // "low_latency", "balanced", "high_quality"
-    pub jwt: String,
-}
-
 #[derive(Serialize)]
 pub struct AnswerResponse {
     pub client_uuid: String,
     pub sdp: String,
 }
 
-#[derive(Deserialize)]
-pub struct JWTCandidateRequest {
-    pub client_uuid: String,
-    pub candidate: String,
-    pub sdp_mid: Option<String>,
-    pub sdp_mline_index: Option<u16>,
-    pub jwt: String,
-}
-
 #[derive(Serialize)]
 pub struct CandidateResponse {
     pub candidates: RTCIceCandidateInit,
 }
 
-// 初始 Offer/Answer，返回 AnswerResponse
+// 初始 Offer/Answer，返回 AnswerResponse。DataChannel 命令按该会话当前有效 token 的权限检查
 pub async fn handle_webrtc_offer(offer: &web::Json<JWTOfferRequest>) -> AnswerResponse {
     println!("[WEBRTC]准备启动");
     let client_uuid = &offer.client_uuid;
@@ -177,8 +166,12 @@ pub async fn handle_webrtc_offer(offer: &web::Json<JWTOfferRequest>) -> AnswerRe
 
     // // 6. DataChannel 信令与重协商
     // 设置监听：对方创建的 DataChannel 到来时触发
+    let dc_uuid = client_uuid.clone();
     pc.on_data_channel(Box::new(move |dc: Arc<RTCDataChannel>| {
         println!("[WEBRTC] 收到远端 DataChannel：label = {}", dc.label());
+        let client_uuid = dc_uuid.clone();
+        // 用弱引用，避免回调持有 DataChannel 自身造成循环引用
+        let reply_dc = Arc::downgrade(&dc);
 
         // 设置消息接收处理逻辑
         dc.on_message(Box::new(move |msg| {
@@ -195,6 +188,14 @@ pub async fn handle_webrtc_offer(offer: &web::Json<JWTOfferRequest>) -> AnswerRe
 
                         // 你可以根据字段内容进行进一步处理
                         if let Some(cmd) = json.get("cmd").and_then(|v| v.as_str()) {
+                            if let Err(reason) = check_datachannel_cmd(cmd, &client_uuid) {
+                                println!("[WEBRTC]拒绝{:?}的{}命令：{}", client_uuid, cmd, reason);
+                                let reply = json!({
+                                    "cmd": "error",
+                                    "value": {"code": 403, "reason": reason, "cmd": cmd}
+                                });
+                                return reply_on(reply_dc.clone(), reply.to_string());
+                            }
                             match cmd {
                                 "mouse_move" => {
                                     println!("🖱️ 收到鼠标移动命令: {:?}", json);
@@ -225,6 +226,10 @@ pub async fn handle_webrtc_offer(offer: &web::Json<JWTOfferRequest>) -> AnswerRe
     }));
 
     // 7. 收集本地 ICE 候选
+    CANDIDATES
+        .lock()
+        .unwrap()
+        .insert(client_uuid.clone(), Vec::new());
     {
         let uuid = client_uuid.clone();
         pc.on_ice_candidate(Box::new(move |opt| {
@@ -236,22 +241,20 @@ pub async fn handle_webrtc_offer(offer: &web::Json<JWTOfferRequest>) -> AnswerRe
                         sdp_mline_index: json.sdp_mline_index,
                         username_fragment: None,
                     };
-                    let my_uuid = UUID.lock().unwrap().clone();
-                    let res = send_ice_candidate(init);
-                    let payload = json!({"cmd":"candidate","value":res});
-                    let reply = json!({
-                        "type": "message",
-                        "target_uuid": uuid,
-                        "from":my_uuid,
-                        "payload": json!(payload),
-                    });
-                    drop(my_uuid);
-
-                    let mut pending = PENDING.lock().unwrap();
-                    pending.push(reply.clone());
-                    drop(pending);
-                    SEND_NOTIFY.notify_one();
-                    println!("[CLIENT]RTC返回ICE：{:?}", reply);
+                    // answer 还没发出前先缓存，由 flush_local_candidates 放出
+                    let init = match CANDIDATES.lock().unwrap().get_mut(&uuid) {
+                        Some(buf) => {
+                            buf.push(init);
+                            None
+                        }
+                        None => Some(init),
+                    };
+                    if let Some(init) = init {
+                        let res = send_ice_candidate(init);
+                        let payload = json!({"cmd":"candidate","value":res});
+                        println!("[CLIENT]RTC返回ICE：{:?}", payload);
+                        send_to_peer(&uuid, payload);
+                    }
                 }
             }
             Box::pin(async {})
@@ -271,7 +274,7 @@ pub async fn handle_webrtc_offer(offer: &web::Json<JWTOfferRequest>) -> AnswerRe
     {
         let pc2 = pc.clone();
         let client_uuid2 = client_uuid.clone();
-        let mode2 = format!("{:?}", mode.clone());
+        let mode2 = mode.clone();
         pc.on_peer_connection_state_change(Box::new(move |state| {
             println!("[WEBRTC]连接状态改变，ConnectionState： {:?}", state);
 
@@ -301,6 +304,11 @@ pub async fn handle_webrtc_offer(offer: &web::Json<JWTOfferRequest>) -> AnswerRe
                         println!("[STREAM MANAGER]启动写track失败：{:?}", e)
                     };
                 });
+            } else if state == RTCPeerConnectionState::Failed {
+                emit(ServerEvent::SessionError {
+                    uuid: client_uuid2.clone(),
+                    reason: "WebRTC 连接失败".to_string(),
+                });
             } else if state == RTCPeerConnectionState::Closed {
                 let pc3 = pc2.clone();
                 let client_uuid3 = client_uuid2.clone();
@@ -388,6 +396,18 @@ pub async fn handle_ice_candidate(req: &web::Json<JWTCandidateRequest>) -> Strin
 //     let cands = lock.remove(uuid).unwrap_or_default();
 //     CandidateResponse { candidates: cands }
 // }
+/// answer 已入队，把缓存的本地 ICE 候选按顺序发出，之后的候选直接发送
+pub fn flush_local_candidates(client_uuid: &str) {
+    let mut buffered = CANDIDATES.lock().unwrap();
+    if let Some(cands) = buffered.remove(client_uuid) {
+        // 持有锁入队，保证新产生的候选排在缓存之后
+        for init in cands {
+            let payload = json!({"cmd":"candidate","value":send_ice_candidate(init)});
+            send_to_peer(client_uuid, payload);
+        }
+    }
+}
+
 #[inline]
 pub fn send_ice_candidate(candi: RTCIceCandidateInit) -> CandidateResponse {
     CandidateResponse { candidates: candi }
@@ -457,6 +477,7 @@ pub async fn close_peerconnection(client_uuid: &str) {
             pcs.remove(client_uuid);
         } // MutexGuard is dropped here
 
+        CANDIDATES.lock().unwrap().remove(client_uuid);
         println!("[CLOSE PC]指定用户的RTC关闭成功，{:?}", client_uuid);
         //end_screen_capture(false);
         GLOBAL_STREAM_MANAGER
@@ -469,6 +490,45 @@ pub async fn close_peerconnection(client_uuid: &str) {
     }
 }
 
+/// DataChannel 命令的权限检查：需要对应权限，输入类命令还要求当前持有控制权
+/// 每条命令都重新查该会话当前的权限，token 刷新收窄或被撤销后立即生效
+fn check_datachannel_cmd(cmd: &str, client_uuid: &str) -> Result<(), String> {
+    let Some(perm) = Permission::required_for(cmd) else {
+        return Ok(());
+    };
+    let now = chrono::Utc::now().timestamp() as usize;
+    let Some(perms) = TOKEN_REGISTRY
+        .lock()
+        .unwrap()
+        .permissions_for(client_uuid, now)
+    else {
+        return Err("会话没有有效的token".to_string());
+    };
+    if !perms.contains(&perm) {
+        return Err(format!("缺少{:?}权限", perm));
+    }
+    if perm == Permission::Control
+        && !CURRENT_USERS_INFO
+            .lock()
+            .unwrap()
+            .is_controller_by_uuid(client_uuid.to_string())
+    {
+        return Err("当前没有控制权".to_string());
+    }
+    Ok(())
+}
+
+/// 在 DataChannel 上回复一条消息，通道已关闭时什么也不做
+fn reply_on(dc: Weak<RTCDataChannel>, reply: String) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        if let Some(dc) = dc.upgrade() {
+            if let Err(e) = dc.send_text(reply).await {
+                println!("[WEBRTC]DataChannel 回复失败：{:?}", e);
+            }
+        }
+    })
+}
+
 fn select_mode(mode: &str, client_uuid: &str) -> QualityConfig {
     match mode {
         "low" => QualityConfig::new(client_uuid, 320, 240, 10000, 30),
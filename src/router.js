@@ -1,10 +1,12 @@
 import { createRouter, createWebHistory } from "vue-router";
 import HomeView from "./components/HomeView.vue";
 import UserManagement from "./components/UserManagement.vue";
+import AuditLog from "./components/AuditLog.vue";
 
 const routes = [
   { path: "/", component: HomeView },
-  { path: "/users", component: UserManagement }
+  { path: "/users", component: UserManagement },
+  { path: "/audit", component: AuditLog }
 ];
 
 const router = createRouter({
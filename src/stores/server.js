@@ -17,6 +17,8 @@ export const useServerStore = defineStore('server', () => {
     pointer: 0,
     usersinfo: [],
   });
+  // 口令策略，定时更换、TOTP 附带剩余有效时间
+  const passwordPolicy = ref(null);
 
   return {
     isRunning,
@@ -25,5 +27,6 @@ export const useServerStore = defineStore('server', () => {
     //currentUser,
     currentUuid,
     curUsersInfo,
+    passwordPolicy,
   };
 });